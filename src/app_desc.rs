@@ -0,0 +1,134 @@
+use crate::checksum::Sha256Digest;
+use crate::error::{Result, UpgradeError};
+use crate::image::{
+    ImageError, ImageHeader, SegmentHeader, IMAGE_HEADER_SIZE, SEGMENT_HEADER_SIZE,
+};
+use crate::partition::{find_inactive_partition, find_running_partition};
+use crate::upgrade_data::UpgradeInfo;
+use embedded_storage::nor_flash::NorFlash;
+use esp_partition_table::PartitionEntry;
+use log::debug;
+use semver::Version;
+
+/// Magic word at the start of `esp_app_desc_t`.
+pub const APP_DESC_MAGIC: u32 = 0xABCD_5432;
+/// `esp_app_desc_t` is the first thing in the first segment of an app image.
+pub const APP_DESC_OFFSET: usize = IMAGE_HEADER_SIZE + SEGMENT_HEADER_SIZE;
+pub const APP_DESC_SIZE: usize = 256;
+
+/// `esp_app_desc_t`: what the build put into the image about itself.
+/// [documented here](https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/system/misc_system_api.html#app-version)
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct AppDescriptor {
+    /// Anti-rollback version, only enforced with secure boot.
+    pub secure_version: u32,
+    version: [u8; 32],
+    project_name: [u8; 32],
+    time: [u8; 16],
+    date: [u8; 16],
+    idf_version: [u8; 32],
+    /// SHA-256 of the ELF the image was built from.
+    pub app_elf_sha256: Sha256Digest,
+}
+
+impl AppDescriptor {
+    /// Reads the descriptor of the image in app partition `partition`.
+    pub fn from_partition<S: NorFlash>(
+        storage: &mut S,
+        partition: &PartitionEntry,
+    ) -> Result<Self> {
+        if partition.size < APP_DESC_OFFSET + APP_DESC_SIZE {
+            return Err(UpgradeError::InvalidImage(ImageError::Truncated));
+        }
+
+        let mut buffer = [0; APP_DESC_OFFSET + APP_DESC_SIZE];
+        storage
+            .read(partition.offset, &mut buffer)
+            .map_err(|_| UpgradeError::StorageError)?;
+
+        let mut header = [0; IMAGE_HEADER_SIZE];
+        header.copy_from_slice(&buffer[..IMAGE_HEADER_SIZE]);
+        let header = ImageHeader::try_from(header)?;
+        let mut segment_header = [0; SEGMENT_HEADER_SIZE];
+        segment_header.copy_from_slice(&buffer[IMAGE_HEADER_SIZE..APP_DESC_OFFSET]);
+        let segment = SegmentHeader::try_from(segment_header)?;
+        if header.segment_count == 0 || (segment.data_len as usize) < APP_DESC_SIZE {
+            return Err(UpgradeError::InvalidImage(ImageError::NoAppDescriptor));
+        }
+
+        let mut app_desc = [0; APP_DESC_SIZE];
+        app_desc.copy_from_slice(&buffer[APP_DESC_OFFSET..]);
+        let app_desc = Self::try_from(app_desc)?;
+        debug!("app descriptor in {}: {:?}", partition.name(), app_desc);
+        Ok(app_desc)
+    }
+
+    /// Descriptor of the firmware that's currently running.
+    pub fn running<S: NorFlash>(storage: &mut S) -> Result<Self> {
+        let upgrade_info = UpgradeInfo::from_flash(storage)?;
+        let partition = find_running_partition(storage, upgrade_info.seq)?;
+        Self::from_partition(storage, &partition)
+    }
+
+    /// Descriptor of the firmware in the partition the next update goes to,
+    /// e.g. the image staged by the last download.
+    pub fn inactive<S: NorFlash>(storage: &mut S) -> Result<Self> {
+        let upgrade_info = UpgradeInfo::from_flash(storage)?;
+        let partition = find_inactive_partition(storage, upgrade_info.seq)?;
+        Self::from_partition(storage, &partition)
+    }
+
+    /// Version string as set at build time, e.g. `1.2.3`.
+    pub fn version(&self) -> &str {
+        c_str(&self.version)
+    }
+
+    /// [`AppDescriptor::version`] as semver, for comparing with releases.
+    pub fn semver(&self) -> Result<Version> {
+        Ok(Version::parse(self.version())?)
+    }
+
+    pub fn project_name(&self) -> &str {
+        c_str(&self.project_name)
+    }
+
+    /// Build time, `__TIME__` format.
+    pub fn time(&self) -> &str {
+        c_str(&self.time)
+    }
+
+    /// Build date, `__DATE__` format.
+    pub fn date(&self) -> &str {
+        c_str(&self.date)
+    }
+
+    /// ESP-IDF version the image was built against.
+    pub fn idf_version(&self) -> &str {
+        c_str(&self.idf_version)
+    }
+}
+
+impl TryFrom<[u8; APP_DESC_SIZE]> for AppDescriptor {
+    type Error = UpgradeError;
+    fn try_from(value: [u8; APP_DESC_SIZE]) -> Result<Self> {
+        let magic = u32::from_le_bytes(value[0..4].try_into().unwrap());
+        if magic != APP_DESC_MAGIC {
+            return Err(UpgradeError::InvalidImage(ImageError::NoAppDescriptor));
+        }
+        Ok(Self {
+            secure_version: u32::from_le_bytes(value[4..8].try_into().unwrap()),
+            version: value[16..48].try_into().unwrap(),
+            project_name: value[48..80].try_into().unwrap(),
+            time: value[80..96].try_into().unwrap(),
+            date: value[96..112].try_into().unwrap(),
+            idf_version: value[112..144].try_into().unwrap(),
+            app_elf_sha256: value[144..176].try_into().unwrap(),
+        })
+    }
+}
+
+/// Text up to the first NUL, empty if it isn't UTF-8.
+fn c_str(bytes: &[u8]) -> &str {
+    let len = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    core::str::from_utf8(&bytes[..len]).unwrap_or("")
+}
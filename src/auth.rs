@@ -0,0 +1,139 @@
+//! Credentials sent with every request to the botifactory server.
+//!
+//! HMAC signed requests prove the device knows the secret without sending
+//! it. The signature covers
+//!
+//! ```text
+//! {method}\n{url}\n{timestamp}\n{hex sha256 of the body}
+//! ```
+//!
+//! and is sent as `authorization: HMAC-SHA256 keyId={key_id},signature={hex}`
+//! with the unix timestamp in `x-timestamp`, so the server can reject
+//! replayed requests.
+
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+use hmac::{Hmac, Mac};
+use reqwless::request::Method;
+use sha2::{Digest, Sha256};
+
+/// Current unix time in seconds, e.g. from SNTP or an RTC. Implemented for
+/// closures, so one can capture whatever keeps the time.
+pub trait Clock {
+    fn now(&self) -> u64;
+}
+
+impl<F: Fn() -> u64> Clock for F {
+    fn now(&self) -> u64 {
+        self()
+    }
+}
+
+/// How the client identifies itself to the server.
+#[derive(Default)]
+pub enum Credentials {
+    /// Public endpoints.
+    #[default]
+    None,
+    /// `authorization: Bearer {token}`, one token for the whole fleet.
+    Bearer(String),
+    /// `x-device-id` and `x-api-key` headers, a key per device.
+    ApiKey { device_id: String, key: String },
+    /// Requests signed with a shared secret, see the [module docs](self).
+    Hmac {
+        key_id: String,
+        secret: Vec<u8>,
+        /// Timestamps the requests.
+        clock: Box<dyn Clock + Send>,
+    },
+}
+
+/// Doesn't print the secrets, only who they belong to.
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Credentials::None => f.write_str("None"),
+            Credentials::Bearer(_) => f.debug_tuple("Bearer").field(&REDACTED).finish(),
+            Credentials::ApiKey { device_id, .. } => f
+                .debug_struct("ApiKey")
+                .field("device_id", device_id)
+                .field("key", &REDACTED)
+                .finish(),
+            Credentials::Hmac { key_id, .. } => f
+                .debug_struct("Hmac")
+                .field("key_id", key_id)
+                .field("secret", &REDACTED)
+                .finish_non_exhaustive(),
+        }
+    }
+}
+
+const REDACTED: &str = "<redacted>";
+
+impl Credentials {
+    /// Headers to add to a request.
+    pub fn headers(&self, method: Method, url: &str, body: &[u8]) -> Vec<(&'static str, String)> {
+        match self {
+            Credentials::None => Vec::new(),
+            Credentials::Bearer(token) => {
+                let mut value = String::from("Bearer ");
+                value.push_str(token);
+                alloc::vec![("authorization", value)]
+            }
+            Credentials::ApiKey { device_id, key } => alloc::vec![
+                ("x-device-id", device_id.clone()),
+                ("x-api-key", key.clone()),
+            ],
+            Credentials::Hmac {
+                key_id,
+                secret,
+                clock,
+            } => {
+                let timestamp = clock.now().to_string();
+                let signature = sign_request(secret, method, url, &timestamp, body);
+                alloc::vec![
+                    (
+                        "authorization",
+                        format!(
+                            "HMAC-SHA256 keyId={},signature={}",
+                            key_id,
+                            encode_hex(&signature)
+                        ),
+                    ),
+                    ("x-timestamp", timestamp),
+                ]
+            }
+        }
+    }
+}
+
+/// HMAC-SHA256 of a request as described in the [module docs](self).
+pub fn sign_request(
+    secret: &[u8],
+    method: Method,
+    url: &str,
+    timestamp: &str,
+    body: &[u8],
+) -> [u8; 32] {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC takes keys of any size");
+    let body_hash = encode_hex(&Sha256::digest(body));
+    mac.update(method.as_str().as_bytes());
+    mac.update(b"\n");
+    mac.update(url.as_bytes());
+    mac.update(b"\n");
+    mac.update(timestamp.as_bytes());
+    mac.update(b"\n");
+    mac.update(body_hash.as_bytes());
+    mac.finalize().into_bytes().into()
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    let mut hex = String::with_capacity(2 * bytes.len());
+    for byte in bytes {
+        let _ = write!(hex, "{:02x}", byte);
+    }
+    hex
+}
@@ -1,16 +1,54 @@
+use crate::auth::Credentials;
+use crate::checksum::parse_sha256;
+use crate::decompress::Encoding;
 use crate::error::{Result, UpgradeError};
-use crate::storage::save_new_fw;
+use crate::flash::BlockingAsync;
+use crate::redirect::{is_redirect, resolve_location, same_origin, DEFAULT_MAX_REDIRECTS};
+use crate::release::Release;
+use crate::report::{queue_event_async, ReportLog, UpdateEvent};
+use crate::resume::{resume_offset_in, ResumeLog};
+use crate::retry::{next_random, with_timeout, Delay, IdleTimeout, NeverFires, RetryPolicy};
+use crate::signature::parse_signature;
+use crate::storage::{save_new_fw_in_session, SaveOptions, SaveSession};
+use crate::tls::{require_https, TlsOptions};
+use crate::update::{UpdateDecision, UpdatePolicy};
 use alloc::format;
-use botifactory_types::ReleaseBody;
+use alloc::vec::Vec;
+use embedded_io_async::{Read, Write};
 use embedded_nal_async::{Dns, TcpConnect};
 use embedded_storage::nor_flash::NorFlash;
-use log::debug;
+use embedded_storage_async::nor_flash::NorFlash as AsyncNorFlash;
+use log::{debug, error, info};
 use reqwless::client::HttpClient;
-use reqwless::request::RequestBuilder;
+use reqwless::request::{Method, RequestBuilder};
+use reqwless::response::{Response, StatusCode};
 use semver::Version;
 
 use alloc::string::{String, ToString};
 
+/// Which release of a channel a URL points at.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ReleaseSelector<'a> {
+    Latest,
+    /// The release before the latest one, e.g. to roll back to.
+    Previous,
+    /// Release with the server's id, see [`crate::Release::id`].
+    Id(u64),
+    Version(&'a Version),
+}
+
+/// Builds the URLs of the botifactory REST API for one project and channel.
+///
+/// ```text
+/// {server}/{project}/channels                     channels of the project
+/// {server}/{project}/{channel}/releases           releases of the channel
+/// {server}/{project}/{channel}/latest             release metadata
+/// {server}/{project}/{channel}/previous
+/// {server}/{project}/{channel}/{id}
+/// {server}/{project}/{channel}/version/{version}
+/// {release URL}/binary                            the firmware itself
+/// {server}/{project}/{channel}/reports            update outcomes, POST
+/// ```
 pub struct BotifactoryUrlBuilder {
     pub server_url: String,
     pub project_name: String,
@@ -20,39 +58,145 @@ pub struct BotifactoryUrlBuilder {
 impl BotifactoryUrlBuilder {
     pub fn new(server_url: &str, project_name: &str, channel_name: &str) -> Self {
         Self {
-            server_url: server_url.to_string(),
+            server_url: server_url.trim_end_matches('/').to_string(),
             project_name: project_name.to_string(),
             channel_name: channel_name.to_string(),
         }
     }
-    pub fn latest(self) -> String {
-        format!(
-            "{}/{}/{}/latest",
-            self.server_url, self.project_name, self.channel_name
-        )
+
+    pub fn latest(&self) -> String {
+        self.release(ReleaseSelector::Latest)
+    }
+
+    pub fn previous(&self) -> String {
+        self.release(ReleaseSelector::Previous)
+    }
+
+    pub fn id(&self, id: u64) -> String {
+        self.release(ReleaseSelector::Id(id))
+    }
+
+    pub fn version(&self, version: &Version) -> String {
+        self.release(ReleaseSelector::Version(version))
+    }
+
+    /// The selected release. Serves the metadata to `Accept: application/json`
+    /// and the binary otherwise, so it works for every [`BotifactoryClient`] call.
+    pub fn release(&self, selector: ReleaseSelector) -> String {
+        let channel = self.channel();
+        match selector {
+            ReleaseSelector::Latest => format!("{}/latest", channel),
+            ReleaseSelector::Previous => format!("{}/previous", channel),
+            ReleaseSelector::Id(id) => format!("{}/{}", channel, id),
+            // `+` starts the build metadata but means a space in a URL
+            ReleaseSelector::Version(version) => format!(
+                "{}/version/{}",
+                channel,
+                version.to_string().replace('+', "%2B")
+            ),
+        }
     }
 
-    pub fn previous(self) -> String {
+    /// Download of the firmware binary of the selected release, whatever
+    /// the `Accept` header says.
+    pub fn binary(&self, selector: ReleaseSelector) -> String {
+        format!("{}/binary", self.release(selector))
+    }
+
+    /// All releases of the channel.
+    pub fn releases(&self) -> String {
+        format!("{}/releases", self.channel())
+    }
+
+    /// Where update outcomes are posted, see [`BotifactoryClient::report_outcome`].
+    pub fn reports(&self) -> String {
+        format!("{}/reports", self.channel())
+    }
+
+    /// All channels of the project.
+    pub fn channels(&self) -> String {
+        format!("{}/{}/channels", self.server_url, self.project_name)
+    }
+
+    fn channel(&self) -> String {
         format!(
-            "{}/{}/{}/latest",
+            "{}/{}/{}",
             self.server_url, self.project_name, self.channel_name
         )
     }
+}
 
-    pub fn id(self, id: String) -> String {
-        format!(
-            "{}/{}/{}/{}",
-            self.server_url, self.project_name, self.channel_name, id
-        )
+const PARTIAL_CONTENT: u16 = 206;
+
+/// Largest release JSON accepted.
+const MAX_RELEASE_LEN: usize = 4096;
+
+/// Encodings offered to the server when the caller asks for a compressed
+/// download, see [`crate::decompress`].
+#[cfg(feature = "deflate")]
+const ACCEPT_ENCODING: &str = "deflate, heatshrink, identity";
+#[cfg(not(feature = "deflate"))]
+const ACCEPT_ENCODING: &str = "heatshrink, identity";
+
+/// 401 and 403 get their own error so a bad token isn't taken for a network
+/// problem. Other 4xx won't go away by retrying, 5xx, 408 and 429 might.
+fn check_status(status: StatusCode) -> Result<()> {
+    match status.0 {
+        401 | 403 => {
+            error!("server rejected credentials: {:?}", status);
+            Err(UpgradeError::Unauthorized)
+        }
+        _ if status.is_successful() => Ok(()),
+        408 | 429 => Err(UpgradeError::RequestError),
+        _ if status.is_client_error() => Err(UpgradeError::HttpStatus(status.0)),
+        _ => Err(UpgradeError::RequestError),
     }
 }
 
-pub struct BotifactoryClient<'a, T, D>
+fn with_auth<'h>(
+    headers: &[(&'h str, &'h str)],
+    auth: &'h [(&'static str, String)],
+) -> Vec<(&'h str, &'h str)> {
+    let mut headers = headers.to_vec();
+    headers.extend(auth.iter().map(|(name, value)| (*name, value.as_str())));
+    headers
+}
+
+/// `Location` header of a redirect.
+fn location<'r, C: Read>(response: &'r Response<'_, '_, C>) -> Result<&'r str> {
+    let value = response
+        .headers()
+        .find(|(name, _)| name.eq_ignore_ascii_case("location"))
+        .map(|(_, value)| core::str::from_utf8(value))
+        .transpose()?
+        .ok_or_else(|| {
+            error!("redirect without location");
+            UpgradeError::RequestError
+        })?;
+    Ok(value)
+}
+
+/// Start offset of a `Content-Range: bytes <start>-<end>/<size>` header.
+fn content_range_start(value: &[u8]) -> Option<u32> {
+    let value = core::str::from_utf8(value).ok()?;
+    let (start, _) = value.trim().strip_prefix("bytes ")?.split_once('-')?;
+    start.parse().ok()
+}
+
+pub struct BotifactoryClient<'a, T, D, W = NeverFires>
 where
     T: TcpConnect + 'a,
     D: Dns + 'a,
 {
     url: String,
+    report_url: Option<String>,
+    credentials: Credentials,
+    allow_http: bool,
+    max_redirects: u8,
+    retry: RetryPolicy,
+    delay: W,
+    /// Jitter state, see [`RetryPolicy::jitter_seed`].
+    random: u32,
     client: HttpClient<'a, T, D>,
 }
 
@@ -60,70 +204,534 @@ impl<'a, T: embedded_nal_async::TcpConnect, D: embedded_nal_async::Dns>
     BotifactoryClient<'a, T, D>
 {
     pub fn new(url: String, client: HttpClient<'a, T, D>) -> BotifactoryClient<'a, T, D> {
-        Self { url, client }
+        Self {
+            url,
+            report_url: None,
+            credentials: Credentials::None,
+            allow_http: true,
+            max_redirects: DEFAULT_MAX_REDIRECTS,
+            retry: RetryPolicy::NONE,
+            delay: NeverFires::new(),
+            random: 0,
+            client,
+        }
+    }
+
+    /// Client that only talks TLS, see [`crate::tls`] for what's verified.
+    /// `url` and the report URL must be `https://` unless
+    /// [`TlsOptions::allow_http`] is set.
+    pub fn new_tls(url: String, tcp: &'a T, dns: &'a D, options: TlsOptions<'a>) -> Result<Self> {
+        require_https(&url, options.allow_http)?;
+        let allow_http = options.allow_http;
+        let client = HttpClient::new_with_tls(tcp, dns, options.into_config());
+        Ok(Self {
+            allow_http,
+            ..Self::new(url, client)
+        })
+    }
+
+    /// Retries and times out requests according to `policy`, waiting with `delay`.
+    pub fn with_retry<W: Delay>(
+        self,
+        policy: RetryPolicy,
+        delay: W,
+    ) -> BotifactoryClient<'a, T, D, W> {
+        BotifactoryClient {
+            url: self.url,
+            report_url: self.report_url,
+            credentials: self.credentials,
+            allow_http: self.allow_http,
+            max_redirects: self.max_redirects,
+            retry: policy,
+            delay,
+            random: policy.jitter_seed,
+            client: self.client,
+        }
+    }
+}
+
+impl<'a, T: embedded_nal_async::TcpConnect, D: embedded_nal_async::Dns, W: Delay>
+    BotifactoryClient<'a, T, D, W>
+{
+    /// Sent with every request from now on.
+    pub fn with_credentials(mut self, credentials: Credentials) -> Self {
+        self.credentials = credentials;
+        self
+    }
+
+    /// Redirects followed when downloading the binary, 0 to follow none.
+    /// Credentials are only sent along while the scheme, host and port stay
+    /// the same.
+    pub fn with_max_redirects(mut self, max_redirects: u8) -> Self {
+        self.max_redirects = max_redirects;
+        self
+    }
+
+    /// Where [`Self::report_outcome`] posts to, e.g. [`BotifactoryUrlBuilder::reports`].
+    pub fn with_report_url(mut self, report_url: String) -> Self {
+        self.report_url = Some(report_url);
+        self
     }
 
     pub async fn read_version(&mut self) -> Result<Version> {
+        Ok(self.read_release().await?.version)
+    }
+
+    /// Compares the server's version with the `current` one according to `policy`.
+    pub async fn check_for_update(
+        &mut self,
+        current: &Version,
+        policy: UpdatePolicy,
+    ) -> Result<UpdateDecision> {
+        let offered = self.read_version().await?;
+        let decision = policy.decide(current, &offered);
+        info!(
+            "running {}, server has {}: {:?}",
+            current, offered, decision
+        );
+        Ok(decision)
+    }
+
+    /// Everything the server says about the release, in one request. Take
+    /// the hash and encoding from [`Release::sha256`] and
+    /// [`Release::encoding`] rather than asking again.
+    pub async fn read_release(&mut self) -> Result<Release> {
+        let mut attempt = 1;
+        loop {
+            match self.read_release_once().await {
+                Err(error) if self.retry_after(attempt, &error).await => attempt += 1,
+                result => return result,
+            }
+        }
+    }
+
+    async fn read_release_once(&mut self) -> Result<Release> {
         let mut buffer = [0u8; 4096];
         debug!("building (json) request");
-        let headers = [("accept", "application/json")];
+        require_https(&self.url, self.allow_http)?;
+        let auth = self.credentials.headers(Method::GET, &self.url, &[]);
+        let headers = with_auth(&[("accept", "application/json")], &auth);
         let mut request = self
             .client
-            .request(reqwless::request::Method::GET, &self.url)
+            .request(Method::GET, &self.url)
             .await
             .map_err(UpgradeError::from)?
             .content_type(reqwless::headers::ContentType::ApplicationJson)
             .headers(&headers);
 
         debug!("sending request");
-        let response = request
-            .send(&mut buffer)
-            .await
-            .map_err(UpgradeError::from)?;
+        let response = with_timeout(
+            &self.delay,
+            self.retry.request_timeout_ms,
+            request.send(&mut buffer),
+        )
+        .await?
+        .map_err(UpgradeError::from)?;
         debug!("status code: {:?}", response.status);
-        if !response.status.is_successful() {
-            return Err(UpgradeError::RequestError);
-        }
+        check_status(response.status)?;
         debug!("reading response");
-        let response_body = response
-            .body()
-            .read_to_end()
-            .await
-            .map_err(UpgradeError::from)?;
+        let mut reader = IdleTimeout {
+            reader: response.body().reader(),
+            delay: &self.delay,
+            timeout_ms: self.retry.idle_timeout_ms,
+        };
+        let mut response_body = Vec::new();
+        let mut chunk = [0u8; 256];
+        loop {
+            let len = reader
+                .read(&mut chunk)
+                .await
+                .map_err(|_| UpgradeError::RequestError)?;
+            if len == 0 {
+                break;
+            }
+            if response_body.len() + len > MAX_RELEASE_LEN {
+                error!("release larger than {} bytes", MAX_RELEASE_LEN);
+                return Err(UpgradeError::ReleaseTooLarge);
+            }
+            response_body.extend_from_slice(&chunk[..len]);
+        }
 
-        let content = core::str::from_utf8(response_body)?;
+        let content = core::str::from_utf8(&response_body)?;
 
         debug!("content: {}", content);
-        let (release_response, _size): (ReleaseBody, usize) =
-            serde_json_core::from_str(content).map_err(UpgradeError::from)?;
-
-        debug!("version: {}", release_response.release.version);
-        Ok(release_response.release.version)
+        let release = Release::from_json(content)?;
+        debug!("version: {}", release.version);
+        Ok(release)
     }
 
     pub async fn read_binary<S: NorFlash>(&mut self, storage: &mut S) -> Result<()> {
+        self.read_binary_with_options(storage, SaveOptions::default())
+            .await
+    }
+
+    /// Like [`Self::read_binary`] but picks up where an interrupted download
+    /// of the same `release` left off, using an HTTP `Range` request.
+    ///
+    /// `release` identifies the release being downloaded (e.g. its version)
+    /// so progress from a different release is never reused. Falls back to a
+    /// full download if the server ignores the range.
+    pub async fn read_binary_resumable<S: NorFlash>(
+        &mut self,
+        storage: &mut S,
+        release: &str,
+    ) -> Result<()> {
+        let options = SaveOptions {
+            release: Some(release),
+            ..Default::default()
+        };
+        self.read_binary_with_options(storage, options).await
+    }
+
+    /// Downloads the binary with the given [`SaveOptions`].
+    ///
+    /// `resume_from` is worked out here from the persisted progress of
+    /// `release` and `image_len` from `Content-Length`. If no `expected_sha256` is given, the digest from the
+    /// `X-Checksum` response header is used when present, the same goes for
+    /// `signature` and the `X-Signature` header. Compressed payloads are only
+    /// accepted if `encoding` isn't [`Encoding::Identity`], the server's
+    /// `Content-Encoding` then picks the one that's decompressed before it's
+    /// written.
+    /// Redirects are followed, see [`Self::with_max_redirects`].
+    pub async fn read_binary_with_options<S: NorFlash>(
+        &mut self,
+        storage: &mut S,
+        options: SaveOptions<'_>,
+    ) -> Result<()> {
+        self.read_binary_with_options_async(&mut BlockingAsync::new(storage), options)
+            .await
+    }
+
+    /// [`Self::read_binary_with_options`] for async flash drivers, so the
+    /// network stack keeps running while sectors are erased.
+    pub async fn read_binary_with_options_async<S: AsyncNorFlash>(
+        &mut self,
+        storage: &mut S,
+        mut options: SaveOptions<'_>,
+    ) -> Result<()> {
+        let mut attempt = 1;
+        loop {
+            match self.read_binary_once(storage, options.reborrow()).await {
+                Err(error) if self.retry_after(attempt, &error).await => attempt += 1,
+                result => return result,
+            }
+        }
+    }
+
+    async fn read_binary_once<S: AsyncNorFlash>(
+        &mut self,
+        storage: &mut S,
+        options: SaveOptions<'_>,
+    ) -> Result<()> {
         let mut buffer = [0u8; 4096];
+        // The resume offset must not change before the download is written
+        let session = SaveSession::start(storage).await?;
         debug!("building (binary) request");
-        let headers = [("accept", "application/octet-stream")];
+        let offset = match options.release {
+            Some(release) if !options.is_transformed() => {
+                resume_offset_in(storage, &session.partitions, release, options.target_slot).await?
+            }
+            _ => 0,
+        };
+        let range = format!("bytes={}-", offset);
+        // Compression turns off resuming and the length checks, so it's only
+        // offered when asked for. A range of a compressed payload can't be
+        // decoded on its own either.
+        let accept_encoding = if offset > 0 || options.encoding == Encoding::Identity {
+            "identity"
+        } else {
+            ACCEPT_ENCODING
+        };
+        let all_headers = [
+            ("accept", "application/octet-stream"),
+            ("accept-encoding", accept_encoding),
+            ("range", range.as_str()),
+        ];
+        let mut next_url = self.url.clone();
+        let mut redirects = 0;
+        loop {
+            let url = core::mem::take(&mut next_url);
+            require_https(&url, self.allow_http)?;
+            // Credentials stay with the server they're meant for
+            let auth = if same_origin(&self.url, &url) {
+                self.credentials.headers(Method::GET, &url, &[])
+            } else {
+                Vec::new()
+            };
+            let headers = if offset > 0 {
+                with_auth(&all_headers, &auth)
+            } else {
+                with_auth(&all_headers[..2], &auth)
+            };
+
+            let mut request = self
+                .client
+                .request(Method::GET, &url)
+                .await
+                .map_err(UpgradeError::from)?
+                .content_type(reqwless::headers::ContentType::ApplicationOctetStream)
+                .headers(&headers);
+
+            debug!("sending request");
+            let response = with_timeout(
+                &self.delay,
+                self.retry.request_timeout_ms,
+                request.send(&mut buffer),
+            )
+            .await?
+            .map_err(UpgradeError::from)?;
+            debug!("status code: {:?}", response.status);
+            if is_redirect(response.status.0) {
+                if redirects == self.max_redirects {
+                    error!("more than {} redirects", self.max_redirects);
+                    return Err(UpgradeError::TooManyRedirects);
+                }
+                redirects += 1;
+                next_url = resolve_location(&url, location(&response)?);
+                info!("redirected to {}", next_url);
+                continue;
+            }
+            return Self::save_binary_response(
+                storage,
+                response,
+                offset,
+                options,
+                session,
+                &self.delay,
+                self.retry.idle_timeout_ms,
+            )
+            .await;
+        }
+    }
+
+    /// Saves the body of the final (not redirected) binary response.
+    async fn save_binary_response<S: AsyncNorFlash, C: Read + Write>(
+        storage: &mut S,
+        response: Response<'_, '_, C>,
+        offset: u32,
+        mut options: SaveOptions<'_>,
+        session: SaveSession,
+        delay: &W,
+        idle_timeout_ms: Option<u32>,
+    ) -> Result<()> {
+        check_status(response.status)?;
+
+        if let Some((_, value)) = response
+            .headers()
+            .find(|(name, _)| name.eq_ignore_ascii_case("content-encoding"))
+        {
+            let value = core::str::from_utf8(value)?;
+            options.encoding = Encoding::from_content_encoding(value).ok_or_else(|| {
+                error!("unsupported content encoding {}", value);
+                UpgradeError::UnsupportedEncoding
+            })?;
+        }
+
+        options.resume_from = if offset > 0 && response.status.0 == PARTIAL_CONTENT {
+            if options.encoding != Encoding::Identity {
+                error!("server sent part of a compressed payload, starting over next time");
+                if let Some(log) = ResumeLog::from_partitions::<S>(&session.partitions) {
+                    log.clear(storage).await?;
+                }
+                return Err(UpgradeError::RequestError);
+            }
+            let range_start = response
+                .headers()
+                .find(|(name, _)| name.eq_ignore_ascii_case("content-range"))
+                .and_then(|(_, value)| content_range_start(value));
+            if range_start != Some(offset) {
+                error!(
+                    "unexpected content range {:?}, wanted {}",
+                    range_start, offset
+                );
+                return Err(UpgradeError::RequestError);
+            }
+            offset
+        } else {
+            if offset > 0 {
+                info!("server ignored range request, restarting download");
+            }
+            0
+        };
+
+        // Content-Length counts payload bytes otherwise
+        if options.image_len.is_none() && !options.is_transformed() {
+            options.image_len = response
+                .content_length
+                .map(|len| options.resume_from as usize + len);
+        }
+
+        if options.expected_sha256.is_none() {
+            if let Some((_, value)) = response
+                .headers()
+                .find(|(name, _)| name.eq_ignore_ascii_case("x-checksum"))
+            {
+                let value = core::str::from_utf8(value)?;
+                options.expected_sha256 =
+                    Some(parse_sha256(value).ok_or(UpgradeError::ChecksumMismatch)?);
+            }
+        }
 
+        if options.signature.is_none() {
+            if let Some((_, value)) = response
+                .headers()
+                .find(|(name, _)| name.eq_ignore_ascii_case("x-signature"))
+            {
+                let value = core::str::from_utf8(value)?;
+                options.signature =
+                    Some(parse_signature(value).ok_or(UpgradeError::InvalidSignature)?);
+            }
+        }
+
+        let reader = IdleTimeout {
+            reader: response.body().reader(),
+            delay,
+            timeout_ms: idle_timeout_ms,
+        };
+        save_new_fw_in_session(storage, reader, options, session).await
+    }
+
+    /// Tells the server what happened to an update of `device_id`.
+    pub async fn report_outcome(&mut self, device_id: &str, event: &UpdateEvent) -> Result<()> {
+        let mut attempt = 1;
+        loop {
+            match self.report_outcome_once(device_id, event).await {
+                Err(error) if self.retry_after(attempt, &error).await => attempt += 1,
+                result => return result,
+            }
+        }
+    }
+
+    async fn report_outcome_once(&mut self, device_id: &str, event: &UpdateEvent) -> Result<()> {
+        let Some(report_url) = self.report_url.as_deref() else {
+            error!("no report url set");
+            return Err(UpgradeError::InvalidState);
+        };
+        require_https(report_url, self.allow_http)?;
+        let body = event.to_json(device_id)?;
+        let mut buffer = [0u8; 1024];
+        debug!("building (report) request");
+        let auth = self
+            .credentials
+            .headers(Method::POST, report_url, body.as_bytes());
+        let headers = with_auth(&[("accept", "application/json")], &auth);
         let mut request = self
             .client
-            .request(reqwless::request::Method::GET, &self.url)
+            .request(Method::POST, report_url)
             .await
             .map_err(UpgradeError::from)?
-            .content_type(reqwless::headers::ContentType::ApplicationOctetStream)
-            .headers(&headers);
+            .content_type(reqwless::headers::ContentType::ApplicationJson)
+            .headers(&headers)
+            .body(body.as_bytes());
 
-        debug!("sending request");
-        let response = request
-            .send(&mut buffer)
-            .await
-            .map_err(UpgradeError::from)?;
+        debug!("sending report {}", body);
+        let response = with_timeout(
+            &self.delay,
+            self.retry.request_timeout_ms,
+            request.send(&mut buffer),
+        )
+        .await?
+        .map_err(UpgradeError::from)?;
         debug!("status code: {:?}", response.status);
-        if !response.status.is_successful() {
-            return Err(UpgradeError::RequestError);
+        check_status(response.status)?;
+        Ok(())
+    }
+
+    /// Sends the events queued in flash with [`crate::queue_event`], oldest
+    /// first, and returns how many were delivered. Stops at the first one
+    /// that fails, the rest stay queued.
+    pub async fn flush_reports<S: NorFlash>(
+        &mut self,
+        storage: &mut S,
+        device_id: &str,
+    ) -> Result<usize> {
+        self.flush_reports_async(&mut BlockingAsync::new(storage), device_id)
+            .await
+    }
+
+    /// [`Self::flush_reports`] for async flash drivers.
+    pub async fn flush_reports_async<S: AsyncNorFlash>(
+        &mut self,
+        storage: &mut S,
+        device_id: &str,
+    ) -> Result<usize> {
+        match ReportLog::open(storage).await? {
+            Some(mut log) => self.flush_log(storage, &mut log, device_id).await,
+            None => Ok(0),
+        }
+    }
+
+    async fn flush_log<S: AsyncNorFlash>(
+        &mut self,
+        storage: &mut S,
+        log: &mut ReportLog,
+        device_id: &str,
+    ) -> Result<usize> {
+        let pending = log.pending().to_vec();
+        for (sent, queued) in pending.iter().enumerate() {
+            if let Err(e) = self.report_outcome(device_id, &queued.event).await {
+                info!("{} reports left in queue: {:?}", pending.len() - sent, e);
+                return Ok(sent);
+            }
+            log.mark_sent(storage, queued.seq).await?;
         }
+        Ok(pending.len())
+    }
 
-        save_new_fw(storage, response.body().reader()).await
+    /// Sends `event` after anything still queued, or queues it in flash if
+    /// that fails, so nothing is lost while the device is offline. Call
+    /// [`Self::flush_reports`] after reconnecting to send the rest.
+    pub async fn report_or_queue<S: NorFlash>(
+        &mut self,
+        storage: &mut S,
+        device_id: &str,
+        event: &UpdateEvent,
+    ) -> Result<()> {
+        self.report_or_queue_async(&mut BlockingAsync::new(storage), device_id, event)
+            .await
+    }
+
+    /// [`Self::report_or_queue`] for async flash drivers.
+    pub async fn report_or_queue_async<S: AsyncNorFlash>(
+        &mut self,
+        storage: &mut S,
+        device_id: &str,
+        event: &UpdateEvent,
+    ) -> Result<()> {
+        let Some(mut log) = ReportLog::open(storage).await? else {
+            // Nowhere to queue it, queue_event_async drops it with a warning
+            return match self.report_outcome(device_id, event).await {
+                Ok(()) => Ok(()),
+                Err(_) => queue_event_async(storage, event).await,
+            };
+        };
+        let pending = log.pending().len();
+        // Queued events go first to keep the order
+        if self.flush_log(storage, &mut log, device_id).await? == pending {
+            match self.report_outcome(device_id, event).await {
+                Ok(()) => return Ok(()),
+                Err(e) => info!("queueing report: {:?}", e),
+            }
+        }
+        log.queue(storage, event).await
+    }
+
+    /// Backs off and returns true if attempt number `attempt` failing with
+    /// `error` should be followed by another one.
+    async fn retry_after(&mut self, attempt: u32, error: &UpgradeError) -> bool {
+        if attempt >= self.retry.max_attempts || !error.is_transient() {
+            return false;
+        }
+        let backoff = self
+            .retry
+            .backoff_ms(attempt, next_random(&mut self.random));
+        info!(
+            "attempt {} failed: {:?}, retrying in {} ms",
+            attempt, error, backoff
+        );
+        if backoff > 0 {
+            self.delay.delay_ms(backoff).await;
+        }
+        true
     }
 }
@@ -0,0 +1,28 @@
+/// SHA-256 digest of a firmware image.
+pub type Sha256Digest = [u8; 32];
+
+/// Parses a hex encoded SHA-256 digest as published by botifactory,
+/// optionally prefixed with `sha256:` or `sha256=`.
+pub fn parse_sha256(value: &str) -> Option<Sha256Digest> {
+    let value = value.trim();
+    let hex = value
+        .strip_prefix("sha256:")
+        .or_else(|| value.strip_prefix("sha256="))
+        .unwrap_or(value);
+    decode_hex(hex)
+}
+
+/// Decodes exactly `N` bytes of hex.
+pub(crate) fn decode_hex<const N: usize>(hex: &str) -> Option<[u8; N]> {
+    // from_str_radix would also take a sign
+    if hex.len() != 2 * N || !hex.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    let mut bytes = [0; N];
+    for (byte, pair) in bytes.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        let pair = core::str::from_utf8(pair).ok()?;
+        *byte = u8::from_str_radix(pair, 16).ok()?;
+    }
+    Some(bytes)
+}
@@ -0,0 +1,416 @@
+//! Streaming decompression of firmware payloads.
+//!
+//! [`Decompressor`] wraps the reader the payload comes from and hands out
+//! plain image bytes, so compressed firmware goes through the same checks
+//! as an uncompressed one. Two formats are supported:
+//!
+//! - zlib (`deflate` feature), what HTTP calls `Content-Encoding: deflate`.
+//!   The window is sized from the stream header, compressing with a smaller
+//!   one (e.g. `zlib.compressobj(wbits=10)`) keeps RAM use down.
+//! - [heatshrink](https://github.com/atomicobject/heatshrink), an LZSS
+//!   variant made for microcontrollers. Its window and lookahead sizes aren't
+//!   part of the stream, see [`Encoding::from_content_encoding`].
+
+use crate::error::{Result, UpgradeError};
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+use embedded_io_async::{ErrorType, Read};
+use log::{debug, error};
+#[cfg(feature = "deflate")]
+use miniz_oxide::inflate::core::inflate_flags::{
+    TINFL_FLAG_HAS_MORE_INPUT, TINFL_FLAG_PARSE_ZLIB_HEADER,
+};
+#[cfg(feature = "deflate")]
+use miniz_oxide::inflate::core::{decompress, DecompressorOxide};
+#[cfg(feature = "deflate")]
+use miniz_oxide::inflate::TINFLStatus;
+
+/// heatshrink window size (as a power of 2) used when none is given.
+pub const HEATSHRINK_WINDOW_SZ2: u8 = 8;
+/// heatshrink lookahead size (as a power of 2) used when none is given.
+pub const HEATSHRINK_LOOKAHEAD_SZ2: u8 = 4;
+
+/// Compressed bytes are pulled from the reader in pieces of this size.
+const INPUT_SIZE: usize = 512;
+#[cfg(feature = "deflate")]
+const MAX_DEFLATE_WINDOW_BITS: u32 = 15;
+
+/// How a firmware payload is compressed.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub enum Encoding {
+    /// Not compressed at all.
+    #[default]
+    Identity,
+    /// zlib stream, as sent with `Content-Encoding: deflate`. Needs the
+    /// `deflate` feature.
+    Deflate,
+    /// heatshrink stream compressed with `-w window_sz2 -l lookahead_sz2`.
+    Heatshrink { window_sz2: u8, lookahead_sz2: u8 },
+}
+
+impl Encoding {
+    /// Parses a `Content-Encoding` value, or the encoding named in the
+    /// release metadata.
+    ///
+    /// heatshrink parameters can be given as `heatshrink-<window>-<lookahead>`,
+    /// plain `heatshrink` means [`HEATSHRINK_WINDOW_SZ2`] and
+    /// [`HEATSHRINK_LOOKAHEAD_SZ2`]. Returns `None` for anything this build
+    /// can't decode.
+    pub fn from_content_encoding(value: &str) -> Option<Self> {
+        let value = value.trim();
+        if value.is_empty() || value.eq_ignore_ascii_case("identity") {
+            return Some(Self::Identity);
+        }
+        #[cfg(feature = "deflate")]
+        if value.eq_ignore_ascii_case("deflate") {
+            return Some(Self::Deflate);
+        }
+        let params = value.strip_prefix("heatshrink")?;
+        let (window_sz2, lookahead_sz2) = if params.is_empty() {
+            (HEATSHRINK_WINDOW_SZ2, HEATSHRINK_LOOKAHEAD_SZ2)
+        } else {
+            let (window, lookahead) = params.strip_prefix('-')?.split_once('-')?;
+            (window.parse().ok()?, lookahead.parse().ok()?)
+        };
+        heatshrink_params_valid(window_sz2, lookahead_sz2).then_some(Self::Heatshrink {
+            window_sz2,
+            lookahead_sz2,
+        })
+    }
+}
+
+/// Same limits as the heatshrink encoder.
+fn heatshrink_params_valid(window_sz2: u8, lookahead_sz2: u8) -> bool {
+    (4..=15).contains(&window_sz2) && (3..window_sz2).contains(&lookahead_sz2)
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum DecompressError<E> {
+    /// The underlying reader failed.
+    Read(E),
+    /// The compressed stream is broken or truncated.
+    Corrupt,
+}
+
+impl<E: embedded_io::Error> embedded_io::Error for DecompressError<E> {
+    fn kind(&self) -> embedded_io::ErrorKind {
+        match self {
+            DecompressError::Read(error) => error.kind(),
+            DecompressError::Corrupt => embedded_io::ErrorKind::InvalidData,
+        }
+    }
+}
+
+impl<E> From<DecompressError<E>> for UpgradeError {
+    fn from(error: DecompressError<E>) -> Self {
+        match error {
+            // The download broke off
+            DecompressError::Read(_) => UpgradeError::RequestError,
+            DecompressError::Corrupt => UpgradeError::InvalidCompressedData,
+        }
+    }
+}
+
+/// [`Read`] adapter that decompresses what `R` produces.
+pub struct Decompressor<R> {
+    reader: R,
+    codec: Codec,
+}
+
+enum Codec {
+    Identity,
+    #[cfg(feature = "deflate")]
+    Deflate(Box<Inflate>),
+    Heatshrink(Box<Heatshrink>),
+}
+
+impl<R: Read> Decompressor<R> {
+    /// Fails with [`UpgradeError::UnsupportedEncoding`] for heatshrink
+    /// parameters the encoder wouldn't accept either, and for
+    /// [`Encoding::Deflate`] without the `deflate` feature.
+    pub fn new(reader: R, encoding: Encoding) -> Result<Self> {
+        debug!("payload encoding: {:?}", encoding);
+        let codec = match encoding {
+            Encoding::Identity => Codec::Identity,
+            #[cfg(feature = "deflate")]
+            Encoding::Deflate => Codec::Deflate(Box::new(Inflate {
+                input: Input::new(),
+                state: Box::default(),
+                window: Vec::new(),
+                out_pos: 0,
+                pending: 0..0,
+                done: false,
+            })),
+            #[cfg(not(feature = "deflate"))]
+            Encoding::Deflate => {
+                error!("built without the deflate feature");
+                return Err(UpgradeError::UnsupportedEncoding);
+            }
+            Encoding::Heatshrink {
+                window_sz2,
+                lookahead_sz2,
+            } => {
+                if !heatshrink_params_valid(window_sz2, lookahead_sz2) {
+                    error!(
+                        "unsupported heatshrink parameters -w {} -l {}",
+                        window_sz2, lookahead_sz2
+                    );
+                    return Err(UpgradeError::UnsupportedEncoding);
+                }
+                Codec::Heatshrink(Box::new(Heatshrink {
+                    input: Input::new(),
+                    window: vec![0; 1 << window_sz2],
+                    head: 0,
+                    window_sz2,
+                    lookahead_sz2,
+                    bit_buf: 0,
+                    bit_count: 0,
+                    distance: 0,
+                    remaining: 0,
+                    done: false,
+                }))
+            }
+        };
+        Ok(Self { reader, codec })
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+impl<R: Read> ErrorType for Decompressor<R> {
+    type Error = DecompressError<R::Error>;
+}
+
+impl<R: Read> Read for Decompressor<R> {
+    async fn read(&mut self, buf: &mut [u8]) -> core::result::Result<usize, Self::Error> {
+        match &mut self.codec {
+            Codec::Identity => self.reader.read(buf).await.map_err(DecompressError::Read),
+            #[cfg(feature = "deflate")]
+            Codec::Deflate(inflate) => inflate.read(&mut self.reader, buf).await,
+            Codec::Heatshrink(heatshrink) => heatshrink.read(&mut self.reader, buf).await,
+        }
+    }
+}
+
+/// Compressed bytes read ahead of the decoder.
+struct Input {
+    buf: [u8; INPUT_SIZE],
+    pos: usize,
+    len: usize,
+    eof: bool,
+}
+
+impl Input {
+    fn new() -> Self {
+        Self {
+            buf: [0; INPUT_SIZE],
+            pos: 0,
+            len: 0,
+            eof: false,
+        }
+    }
+
+    #[cfg(feature = "deflate")]
+    fn available(&self) -> &[u8] {
+        &self.buf[self.pos..self.len]
+    }
+
+    /// Reads more once everything buffered has been used up.
+    async fn fill<R: Read>(&mut self, reader: &mut R) -> core::result::Result<(), R::Error> {
+        if self.pos == self.len && !self.eof {
+            self.len = reader.read(&mut self.buf).await?;
+            self.pos = 0;
+            self.eof = self.len == 0;
+        }
+        Ok(())
+    }
+
+    async fn byte<R: Read>(
+        &mut self,
+        reader: &mut R,
+    ) -> core::result::Result<Option<u8>, R::Error> {
+        self.fill(reader).await?;
+        if self.pos == self.len {
+            return Ok(None);
+        }
+        self.pos += 1;
+        Ok(Some(self.buf[self.pos - 1]))
+    }
+}
+
+#[cfg(feature = "deflate")]
+struct Inflate {
+    input: Input,
+    state: Box<DecompressorOxide>,
+    /// Output ring buffer, allocated once the zlib header says how big the
+    /// window is.
+    window: Vec<u8>,
+    /// Where the next output goes in `window`.
+    out_pos: usize,
+    /// Output not handed out yet.
+    pending: core::ops::Range<usize>,
+    done: bool,
+}
+
+#[cfg(feature = "deflate")]
+impl Inflate {
+    async fn read<R: Read>(
+        &mut self,
+        reader: &mut R,
+        buf: &mut [u8],
+    ) -> core::result::Result<usize, DecompressError<R::Error>> {
+        loop {
+            if !self.pending.is_empty() {
+                let len = self.pending.len().min(buf.len());
+                let start = self.pending.start;
+                buf[..len].copy_from_slice(&self.window[start..start + len]);
+                self.pending.start += len;
+                return Ok(len);
+            }
+            if self.done || buf.is_empty() {
+                return Ok(0);
+            }
+
+            self.input
+                .fill(reader)
+                .await
+                .map_err(DecompressError::Read)?;
+            if self.window.is_empty() {
+                // The upper half of the first header byte is the window size
+                let Some(cmf) = self.input.available().first() else {
+                    error!("empty deflate stream");
+                    return Err(DecompressError::Corrupt);
+                };
+                let window_bits = u32::from(cmf >> 4) + 8;
+                if window_bits > MAX_DEFLATE_WINDOW_BITS {
+                    error!("bad zlib header {:02x}", cmf);
+                    return Err(DecompressError::Corrupt);
+                }
+                debug!("deflate window: {} bytes", 1 << window_bits);
+                self.window = vec![0; 1 << window_bits];
+            }
+
+            let mut flags = TINFL_FLAG_PARSE_ZLIB_HEADER;
+            if !self.input.eof {
+                flags |= TINFL_FLAG_HAS_MORE_INPUT;
+            }
+            let (status, consumed, written) = decompress(
+                &mut self.state,
+                self.input.available(),
+                &mut self.window,
+                self.out_pos,
+                flags,
+            );
+            self.input.pos += consumed;
+            self.pending = self.out_pos..self.out_pos + written;
+            self.out_pos = (self.out_pos + written) & (self.window.len() - 1);
+            match status {
+                TINFLStatus::Done => self.done = true,
+                TINFLStatus::NeedsMoreInput | TINFLStatus::HasMoreOutput => {}
+                status => {
+                    error!("deflate stream broken: {:?}", status);
+                    return Err(DecompressError::Corrupt);
+                }
+            }
+        }
+    }
+}
+
+/// Decoder for heatshrink streams: a tag bit, then either a literal byte or
+/// a back reference into the last `2^window_sz2` bytes of output.
+struct Heatshrink {
+    input: Input,
+    window: Vec<u8>,
+    /// Total bytes of output, the window is indexed modulo its size.
+    head: usize,
+    window_sz2: u8,
+    lookahead_sz2: u8,
+    bit_buf: u32,
+    bit_count: u8,
+    /// Back reference being copied: how far back, and how many bytes are left.
+    distance: usize,
+    remaining: usize,
+    done: bool,
+}
+
+impl Heatshrink {
+    async fn read<R: Read>(
+        &mut self,
+        reader: &mut R,
+        buf: &mut [u8],
+    ) -> core::result::Result<usize, DecompressError<R::Error>> {
+        let mask = self.window.len() - 1;
+        let mut filled = 0;
+        while filled < buf.len() && !self.done {
+            if self.remaining > 0 {
+                let byte = self.window[self.head.wrapping_sub(self.distance) & mask];
+                buf[filled] = self.push(byte);
+                filled += 1;
+                self.remaining -= 1;
+                continue;
+            }
+
+            // The stream just stops, a token cut short is the zero padding of the last byte
+            let Some(tag) = self.bits(reader, 1).await? else {
+                break;
+            };
+            if tag == 1 {
+                let Some(byte) = self.bits(reader, 8).await? else {
+                    break;
+                };
+                buf[filled] = self.push(byte as u8);
+                filled += 1;
+            } else {
+                let Some(index) = self.bits(reader, self.window_sz2).await? else {
+                    break;
+                };
+                let Some(count) = self.bits(reader, self.lookahead_sz2).await? else {
+                    break;
+                };
+                self.distance = index as usize + 1;
+                self.remaining = count as usize + 1;
+            }
+        }
+        Ok(filled)
+    }
+
+    fn push(&mut self, byte: u8) -> u8 {
+        let mask = self.window.len() - 1;
+        self.window[self.head & mask] = byte;
+        self.head = self.head.wrapping_add(1);
+        byte
+    }
+
+    /// Next `count` bits, most significant first. `None` (and done) once the
+    /// input runs out.
+    async fn bits<R: Read>(
+        &mut self,
+        reader: &mut R,
+        count: u8,
+    ) -> core::result::Result<Option<u16>, DecompressError<R::Error>> {
+        while self.bit_count < count {
+            match self
+                .input
+                .byte(reader)
+                .await
+                .map_err(DecompressError::Read)?
+            {
+                Some(byte) => {
+                    self.bit_buf = (self.bit_buf << 8) | u32::from(byte);
+                    self.bit_count += 8;
+                }
+                None => {
+                    self.done = true;
+                    return Ok(None);
+                }
+            }
+        }
+        self.bit_count -= count;
+        let value = (self.bit_buf >> self.bit_count) & ((1 << count) - 1);
+        self.bit_buf &= (1 << self.bit_count) - 1;
+        Ok(Some(value as u16))
+    }
+}
@@ -0,0 +1,227 @@
+//! Delta updates: rebuild the new image from the running one and a patch.
+//!
+//! The patch is a bsdiff style sequential format, so it can be applied
+//! while it streams in:
+//!
+//! ```text
+//! header:  magic "BOTD" | new image length (u32)
+//! records: diff length (u32) | extra length (u32) | seek (i32)
+//!          diff bytes, added to the old image byte by byte
+//!          extra bytes, copied as they are
+//! ```
+//!
+//! After a record the old image position moves on by the diff length plus
+//! `seek`. Records follow each other until the new image is complete. All
+//! integers are little endian.
+
+use crate::decompress::Decompressor;
+#[cfg(feature = "encryption")]
+use crate::encryption::Decryptor;
+use crate::error::{Result, UpgradeError};
+use crate::flash::{check_aligned, BlockingAsync};
+use crate::partition::running_slot;
+use crate::storage::{save_image, ImageSource, SaveOptions, SaveSession};
+use crate::upgrade_data::UpgradeInfo;
+use embedded_io_async::{Read, ReadExactError};
+use embedded_storage::nor_flash::NorFlash;
+use embedded_storage_async::nor_flash::NorFlash as AsyncNorFlash;
+use esp_partition_table::PartitionEntry;
+use log::{debug, error};
+
+pub const PATCH_MAGIC: [u8; 4] = *b"BOTD";
+pub const PATCH_HEADER_SIZE: usize = 8;
+const RECORD_HEADER_SIZE: usize = 12;
+/// Old image bytes are read in pieces of at most this size.
+const OLD_CHUNK_SIZE: usize = 256;
+const MAX_READ_SIZE: usize = 128;
+
+/// Applies the patch from `patch_reader` to the running firmware and
+/// writes the result to the inactive partition.
+pub async fn save_delta_fw<S: NorFlash, R: Read>(storage: &mut S, patch_reader: R) -> Result<()> {
+    save_delta_fw_with_options(storage, patch_reader, SaveOptions::default()).await
+}
+
+pub async fn save_delta_fw_with_options<S: NorFlash, R: Read>(
+    storage: &mut S,
+    patch_reader: R,
+    options: SaveOptions<'_>,
+) -> Result<()> {
+    save_delta_fw_with_options_async(&mut BlockingAsync::new(storage), patch_reader, options).await
+}
+
+/// [`save_delta_fw_with_options`] for async flash drivers.
+///
+/// The rebuilt image goes through the same checks as a full one, so
+/// `expected_sha256` and `signature` refer to the new image, not the patch,
+/// while `encoding` and `decryption_keys` apply to the patch. Delta updates
+/// can't be resumed, `resume_from` has to be 0.
+pub async fn save_delta_fw_with_options_async<S: AsyncNorFlash, R: Read>(
+    storage: &mut S,
+    patch_reader: R,
+    mut options: SaveOptions<'_>,
+) -> Result<()> {
+    options.check_features()?;
+    if options.resume_from != 0 {
+        error!("delta updates can't be resumed");
+        return Err(UpgradeError::InvalidState);
+    }
+    // Progress is counted in image bytes, which don't line up with the patch
+    options.release = None;
+    check_aligned(MAX_READ_SIZE, S::READ_SIZE)?;
+    // Before the running partition is looked up, so a concurrent download
+    // can't change it underneath us
+    let session = SaveSession::start(storage).await?;
+
+    #[cfg(feature = "encryption")]
+    let patch_reader = Decryptor::new(patch_reader, options.decryption_keys).await?;
+    let mut patch_reader = Decompressor::new(patch_reader, options.encoding)?;
+    let mut header = [0; PATCH_HEADER_SIZE];
+    read_patch(&mut patch_reader, &mut header).await?;
+    if header[0..4] != PATCH_MAGIC {
+        error!("not a delta patch: {:02x?}", &header[0..4]);
+        return Err(UpgradeError::InvalidPatch);
+    }
+    let new_len = u32::from_le_bytes(header[4..8].try_into().unwrap()) as usize;
+    options.image_len.get_or_insert(new_len);
+
+    let partitions = &session.partitions;
+    let upgrade_info = UpgradeInfo::from_partition_async(storage, partitions.otadata()?).await?;
+    let old = partitions.ota_slot(running_slot(upgrade_info.seq, partitions.slot_count()?))?;
+    debug!("patching {} into a {} byte image", old.name(), new_len);
+
+    let source = Patch {
+        reader: patch_reader,
+        old,
+        old_pos: 0,
+        new_len,
+        produced: 0,
+        state: State::Record,
+    };
+    save_image(storage, source, options, session).await
+}
+
+#[derive(Debug, Copy, Clone)]
+enum State {
+    Record,
+    Diff {
+        remaining: usize,
+        extra: usize,
+        seek: i32,
+    },
+    Extra {
+        remaining: usize,
+    },
+}
+
+/// Rebuilds the new image from the patch and the old image.
+struct Patch<R> {
+    reader: Decompressor<R>,
+    old: PartitionEntry,
+    old_pos: usize,
+    new_len: usize,
+    produced: usize,
+    state: State,
+}
+
+impl<S: AsyncNorFlash, R: Read> ImageSource<S> for Patch<R> {
+    async fn read(&mut self, storage: &mut S, buf: &mut [u8]) -> Result<usize> {
+        let mut filled = 0;
+        while filled < buf.len() && self.produced < self.new_len {
+            let free = buf.len() - filled;
+            match self.state {
+                State::Record => self.read_record().await?,
+                State::Diff {
+                    remaining,
+                    extra,
+                    seek,
+                } => {
+                    let len = remaining.min(free).min(OLD_CHUNK_SIZE);
+                    let out = &mut buf[filled..filled + len];
+                    read_patch(&mut self.reader, out).await?;
+                    self.add_old(storage, out).await?;
+                    self.old_pos += len;
+                    filled += len;
+                    self.produced += len;
+                    self.state = if remaining > len {
+                        State::Diff {
+                            remaining: remaining - len,
+                            extra,
+                            seek,
+                        }
+                    } else {
+                        self.old_pos = self
+                            .old_pos
+                            .checked_add_signed(seek as isize)
+                            .ok_or(UpgradeError::InvalidPatch)?;
+                        State::Extra { remaining: extra }
+                    };
+                }
+                State::Extra { remaining } => {
+                    let len = remaining.min(free);
+                    read_patch(&mut self.reader, &mut buf[filled..filled + len]).await?;
+                    filled += len;
+                    self.produced += len;
+                    self.state = if remaining > len {
+                        State::Extra {
+                            remaining: remaining - len,
+                        }
+                    } else {
+                        State::Record
+                    };
+                }
+            }
+        }
+        Ok(filled)
+    }
+}
+
+impl<R: Read> Patch<R> {
+    async fn read_record(&mut self) -> Result<()> {
+        let mut record = [0; RECORD_HEADER_SIZE];
+        read_patch(&mut self.reader, &mut record).await?;
+        let diff = u32::from_le_bytes(record[0..4].try_into().unwrap()) as usize;
+        let extra = u32::from_le_bytes(record[4..8].try_into().unwrap()) as usize;
+        let seek = i32::from_le_bytes(record[8..12].try_into().unwrap());
+        if self.produced + diff + extra > self.new_len {
+            error!("patch record runs past the end of the image");
+            return Err(UpgradeError::InvalidPatch);
+        }
+        self.state = State::Diff {
+            remaining: diff,
+            extra,
+            seek,
+        };
+        Ok(())
+    }
+
+    /// Adds the old image bytes at the current position to `out`.
+    async fn add_old<S: AsyncNorFlash>(&self, storage: &mut S, out: &mut [u8]) -> Result<()> {
+        if self.old_pos + out.len() > self.old.size {
+            error!("patch reads past the end of {}", self.old.name());
+            return Err(UpgradeError::InvalidPatch);
+        }
+        // Reads have to be aligned to READ_SIZE
+        let start = self.old_pos - self.old_pos % S::READ_SIZE;
+        let end = (self.old_pos + out.len()).next_multiple_of(S::READ_SIZE);
+        let mut old = [0; OLD_CHUNK_SIZE + 2 * MAX_READ_SIZE];
+        storage
+            .read(self.old.offset + start as u32, &mut old[..end - start])
+            .await
+            .map_err(|_| UpgradeError::StorageError)?;
+        let old = &old[self.old_pos - start..];
+        for (byte, old) in out.iter_mut().zip(old) {
+            *byte = byte.wrapping_add(*old);
+        }
+        Ok(())
+    }
+}
+
+async fn read_patch<R: Read>(reader: &mut Decompressor<R>, buf: &mut [u8]) -> Result<()> {
+    reader.read_exact(buf).await.map_err(|error| match error {
+        ReadExactError::UnexpectedEof => {
+            error!("patch ended early");
+            UpgradeError::InvalidPatch
+        }
+        ReadExactError::Other(error) => error.into(),
+    })
+}
@@ -0,0 +1,240 @@
+//! Encrypted firmware payloads, decrypted while they stream in.
+//!
+//! An encrypted payload is a header followed by the ciphertext:
+//!
+//! ```text
+//! magic "BOTE" | version (u8, 1) | cipher (u8) | key id (u8) | reserved (u8, 0)
+//! nonce (16 bytes)
+//! ciphertext
+//! ```
+//!
+//! For AES-CTR the nonce is the initial 128 bit big endian counter block,
+//! ChaCha20 uses the first 12 bytes of it with the block counter starting
+//! at 0. The key id picks one of the [`FirmwareKey`]s the device knows, so
+//! fleet keys can be rotated. [`encrypt_payload`] builds such a payload on
+//! the host side.
+//!
+//! Encryption only keeps the firmware confidential, it doesn't stop it from
+//! being tampered with. Check the decrypted image with `expected_sha256` or
+//! a signature as usual. Compressed payloads are compressed before they're
+//! encrypted.
+//!
+//! The keys can always be passed around, decrypting needs the `encryption`
+//! feature.
+
+use crate::error::{Result, UpgradeError};
+#[cfg(feature = "encryption")]
+use aes::{Aes128, Aes256};
+#[cfg(feature = "encryption")]
+use alloc::boxed::Box;
+#[cfg(feature = "encryption")]
+use alloc::vec::Vec;
+#[cfg(feature = "encryption")]
+use chacha20::ChaCha20;
+#[cfg(feature = "encryption")]
+use ctr::cipher::{KeyIvInit, StreamCipher};
+#[cfg(feature = "encryption")]
+use ctr::Ctr128BE;
+#[cfg(feature = "encryption")]
+use embedded_io_async::{ErrorType, Read, ReadExactError};
+#[cfg(feature = "encryption")]
+use log::debug;
+use log::error;
+
+pub const ENCRYPTED_MAGIC: [u8; 4] = *b"BOTE";
+pub const ENCRYPTED_HEADER_SIZE: usize = 24;
+pub const NONCE_SIZE: usize = 16;
+const ENCRYPTED_VERSION: u8 = 1;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Cipher {
+    Aes128Ctr = 1,
+    Aes256Ctr = 2,
+    ChaCha20 = 3,
+}
+
+impl TryFrom<u8> for Cipher {
+    type Error = UpgradeError;
+    fn try_from(value: u8) -> Result<Self> {
+        match value {
+            1 => Ok(Self::Aes128Ctr),
+            2 => Ok(Self::Aes256Ctr),
+            3 => Ok(Self::ChaCha20),
+            _ => Err(UpgradeError::InvalidEncryptedPayload),
+        }
+    }
+}
+
+/// Raw key material for one of the supported ciphers.
+#[derive(Clone)]
+pub enum CipherKey {
+    Aes128Ctr([u8; 16]),
+    Aes256Ctr([u8; 32]),
+    ChaCha20([u8; 32]),
+}
+
+impl CipherKey {
+    pub fn cipher(&self) -> Cipher {
+        match self {
+            CipherKey::Aes128Ctr(_) => Cipher::Aes128Ctr,
+            CipherKey::Aes256Ctr(_) => Cipher::Aes256Ctr,
+            CipherKey::ChaCha20(_) => Cipher::ChaCha20,
+        }
+    }
+}
+
+/// A device or fleet key and the id payloads refer to it by.
+#[derive(Clone)]
+pub struct FirmwareKey {
+    pub id: u8,
+    pub key: CipherKey,
+}
+
+/// The header in front of the ciphertext.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct EncryptionHeader {
+    pub cipher: Cipher,
+    pub key_id: u8,
+    pub nonce: [u8; NONCE_SIZE],
+}
+
+impl TryFrom<[u8; ENCRYPTED_HEADER_SIZE]> for EncryptionHeader {
+    type Error = UpgradeError;
+    fn try_from(value: [u8; ENCRYPTED_HEADER_SIZE]) -> Result<Self> {
+        if value[0..4] != ENCRYPTED_MAGIC || value[4] != ENCRYPTED_VERSION {
+            error!("not an encrypted payload: {:02x?}", &value[0..5]);
+            return Err(UpgradeError::InvalidEncryptedPayload);
+        }
+        Ok(Self {
+            cipher: Cipher::try_from(value[5])?,
+            key_id: value[6],
+            nonce: value[8..24].try_into().unwrap(),
+        })
+    }
+}
+
+impl From<EncryptionHeader> for [u8; ENCRYPTED_HEADER_SIZE] {
+    fn from(value: EncryptionHeader) -> Self {
+        let mut ret = [0; ENCRYPTED_HEADER_SIZE];
+        ret[0..4].copy_from_slice(&ENCRYPTED_MAGIC);
+        ret[4] = ENCRYPTED_VERSION;
+        ret[5] = value.cipher as u8;
+        ret[6] = value.key_id;
+        ret[8..24].copy_from_slice(&value.nonce);
+        ret
+    }
+}
+
+/// Encrypts `payload` with `key` into the format [`Decryptor`] reads.
+///
+/// `nonce` must never be used twice with the same key.
+#[cfg(feature = "encryption")]
+pub fn encrypt_payload(key: &FirmwareKey, nonce: [u8; NONCE_SIZE], payload: &[u8]) -> Vec<u8> {
+    let header = EncryptionHeader {
+        cipher: key.key.cipher(),
+        key_id: key.id,
+        nonce,
+    };
+    let mut encrypted = <[u8; ENCRYPTED_HEADER_SIZE]>::from(header).to_vec();
+    encrypted.extend_from_slice(payload);
+    Keystream::new(&key.key, &nonce).apply(&mut encrypted[ENCRYPTED_HEADER_SIZE..]);
+    encrypted
+}
+
+/// AES key schedules are large, they live on the heap.
+#[cfg(feature = "encryption")]
+enum Keystream {
+    Aes128Ctr(Box<Ctr128BE<Aes128>>),
+    Aes256Ctr(Box<Ctr128BE<Aes256>>),
+    ChaCha20(ChaCha20),
+}
+
+#[cfg(feature = "encryption")]
+impl Keystream {
+    fn new(key: &CipherKey, nonce: &[u8; NONCE_SIZE]) -> Self {
+        match key {
+            CipherKey::Aes128Ctr(key) => {
+                Self::Aes128Ctr(Box::new(Ctr128BE::new(key.into(), nonce.into())))
+            }
+            CipherKey::Aes256Ctr(key) => {
+                Self::Aes256Ctr(Box::new(Ctr128BE::new(key.into(), nonce.into())))
+            }
+            CipherKey::ChaCha20(key) => {
+                Self::ChaCha20(ChaCha20::new(key.into(), nonce[..12].into()))
+            }
+        }
+    }
+
+    fn apply(&mut self, buf: &mut [u8]) {
+        match self {
+            Keystream::Aes128Ctr(cipher) => cipher.apply_keystream(buf),
+            Keystream::Aes256Ctr(cipher) => cipher.apply_keystream(buf),
+            Keystream::ChaCha20(cipher) => cipher.apply_keystream(buf),
+        }
+    }
+}
+
+/// [`Read`] adapter that decrypts what `R` produces.
+///
+/// Without keys the payload is passed through as it is.
+#[cfg(feature = "encryption")]
+pub struct Decryptor<R> {
+    reader: R,
+    keystream: Option<Keystream>,
+}
+
+#[cfg(feature = "encryption")]
+impl<R: Read> Decryptor<R> {
+    /// Reads the header and sets up the cipher with the key it names.
+    /// With no `keys` nothing is read and the payload is taken as plaintext.
+    pub async fn new(mut reader: R, keys: &[FirmwareKey]) -> Result<Self> {
+        if keys.is_empty() {
+            return Ok(Self {
+                reader,
+                keystream: None,
+            });
+        }
+
+        let mut header = [0; ENCRYPTED_HEADER_SIZE];
+        reader
+            .read_exact(&mut header)
+            .await
+            .map_err(|error| match error {
+                ReadExactError::UnexpectedEof => UpgradeError::InvalidEncryptedPayload,
+                ReadExactError::Other(_) => UpgradeError::RequestError,
+            })?;
+        let header = EncryptionHeader::try_from(header)?;
+        let Some(key) = keys
+            .iter()
+            .find(|key| key.id == header.key_id && key.key.cipher() == header.cipher)
+        else {
+            error!("no {:?} key with id {}", header.cipher, header.key_id);
+            return Err(UpgradeError::MissingDecryptionKey(header.key_id));
+        };
+        debug!("decrypting with {:?} key {}", header.cipher, key.id);
+        Ok(Self {
+            reader,
+            keystream: Some(Keystream::new(&key.key, &header.nonce)),
+        })
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+#[cfg(feature = "encryption")]
+impl<R: Read> ErrorType for Decryptor<R> {
+    type Error = R::Error;
+}
+
+#[cfg(feature = "encryption")]
+impl<R: Read> Read for Decryptor<R> {
+    async fn read(&mut self, buf: &mut [u8]) -> core::result::Result<usize, Self::Error> {
+        let len = self.reader.read(buf).await?;
+        if let Some(keystream) = &mut self.keystream {
+            keystream.apply(&mut buf[..len]);
+        }
+        Ok(len)
+    }
+}
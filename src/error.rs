@@ -1,6 +1,8 @@
 use crate::alloc::string::ToString;
+use crate::image::ImageError;
 use alloc::str::Utf8Error;
 use alloc::string::String;
+use embedded_tls::TlsError;
 use esp_partition_table::NorFlashOpError;
 use log::error;
 use semver::Error as SemverError;
@@ -38,14 +40,124 @@ pub enum UpgradeError {
     SerdeError(#[from] serde_json_core::de::Error),
     #[error("Out of space")]
     OutOfSpace,
+    #[error("Checksum mismatch")]
+    ChecksumMismatch,
+    #[error("Firmware signature missing")]
+    MissingSignature,
+    #[error("Invalid firmware signature")]
+    InvalidSignature,
+    #[error("Flash write or erase size not supported")]
+    UnsupportedAlignment,
+    #[error("Can't write OTA slot {0}")]
+    InvalidSlot(u8),
+    #[error("Invalid delta patch")]
+    InvalidPatch,
+    #[error("Firmware encoding not supported")]
+    UnsupportedEncoding,
+    #[error("Invalid compressed firmware")]
+    InvalidCompressedData,
+    #[error("Invalid encrypted firmware")]
+    InvalidEncryptedPayload,
+    #[error("No key to decrypt firmware with key id {0}")]
+    MissingDecryptionKey(u8),
+    #[error("Invalid app image: {0:?}")]
+    InvalidImage(ImageError),
+    #[error("Server rejected the credentials")]
+    Unauthorized,
+    #[error("Refusing plain http URL")]
+    InsecureUrl,
+    #[error("TLS handshake failed, server not trusted")]
+    UntrustedServer,
+    #[error("Server answered with status {0}")]
+    HttpStatus(u16),
+    #[error("Request timed out")]
+    Timeout,
+    #[error("Too many redirects")]
+    TooManyRedirects,
+    #[error("Received {received} bytes, expected {expected}")]
+    LengthMismatch { expected: usize, received: usize },
+    #[error("Release metadata too large")]
+    ReleaseTooLarge,
+}
+
+impl UpgradeError {
+    /// Stable number for the error, sent to the server in update reports.
+    /// New variants get new numbers, existing ones never change.
+    pub fn code(&self) -> u16 {
+        match self {
+            Self::DLInProgress => 1,
+            Self::BootingIntoNewFW => 2,
+            Self::InvalidState => 3,
+            Self::InvalidCrc => 4,
+            Self::StorageError => 5,
+            Self::FlashError => 6,
+            Self::VersionError(_) => 7,
+            Self::PartitionNotFound => 8,
+            Self::PartitionFoundTwice => 9,
+            Self::OtaPartitionCorrupted => 10,
+            Self::RequestError => 11,
+            Self::UTF8Error(_) => 12,
+            Self::SerdeError(_) => 13,
+            Self::OutOfSpace => 14,
+            Self::ChecksumMismatch => 15,
+            Self::MissingSignature => 16,
+            Self::InvalidSignature => 17,
+            Self::UnsupportedAlignment => 18,
+            Self::InvalidSlot(_) => 19,
+            Self::InvalidPatch => 20,
+            Self::UnsupportedEncoding => 21,
+            Self::InvalidCompressedData => 22,
+            Self::InvalidEncryptedPayload => 23,
+            Self::MissingDecryptionKey(_) => 24,
+            Self::InvalidImage(_) => 25,
+            Self::Unauthorized => 26,
+            Self::InsecureUrl => 27,
+            Self::UntrustedServer => 28,
+            Self::HttpStatus(_) => 29,
+            Self::Timeout => 30,
+            Self::TooManyRedirects => 31,
+            Self::LengthMismatch { .. } => 32,
+            Self::ReleaseTooLarge => 33,
+        }
+    }
+
+    /// Whether trying again later might work, e.g. after a dropped
+    /// connection or a 503. Rejected requests and bad firmware won't.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Self::RequestError | Self::Timeout => true,
+            // Truncated transfer
+            Self::LengthMismatch { expected, received } => received < expected,
+            _ => false,
+        }
+    }
 }
 
 impl From<reqwless::Error> for UpgradeError {
     fn from(error: reqwless::Error) -> Self {
         error!("network error: {:?}", error);
-        Self::RequestError
+        match error {
+            reqwless::Error::Tls(tls) if is_rejected_handshake(&tls) => Self::UntrustedServer,
+            _ => Self::RequestError,
+        }
     }
 }
+
+/// The handshake rejected the server or the server rejected us: a bad
+/// certificate, a PSK it doesn't share, or an alert during the handshake. Dropped connections
+/// and malformed records are ordinary network failures.
+fn is_rejected_handshake(error: &TlsError) -> bool {
+    matches!(
+        error,
+        TlsError::HandshakeAborted(..)
+            | TlsError::AbortHandshake(..)
+            | TlsError::InvalidCertificate
+            | TlsError::InvalidCertificateEntry
+            | TlsError::InvalidCertificateRequest
+            | TlsError::InvalidSignature
+            | TlsError::InvalidSignatureScheme
+    )
+}
 impl From<()> for UpgradeError {
     fn from(_: ()) -> Self {
         error!("unit error");
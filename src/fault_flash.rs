@@ -0,0 +1,164 @@
+use alloc::vec;
+use embedded_storage::nor_flash::{
+    ErrorType, NorFlash, NorFlashError, NorFlashErrorKind, ReadNorFlash,
+};
+
+/// How an interrupted operation is left behind.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PowerCut {
+    /// Power is lost right before the operation starts.
+    Before,
+    /// Power is lost halfway through the operation.
+    /// Writes program the first half of the data and leave the next word
+    /// partially programmed, erases only clear the first half of the range.
+    Torn,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum FaultFlashError<E> {
+    /// The simulated power cut happened, the flash is unusable until "reboot".
+    PowerLost,
+    Flash(E),
+}
+
+impl<E: NorFlashError> NorFlashError for FaultFlashError<E> {
+    fn kind(&self) -> NorFlashErrorKind {
+        match self {
+            FaultFlashError::PowerLost => NorFlashErrorKind::Other,
+            FaultFlashError::Flash(error) => error.kind(),
+        }
+    }
+}
+
+/// [`NorFlash`] wrapper that simulates losing power after a number of
+/// erase/write operations.
+///
+/// Every erase or write counts as one operation. Once the configured
+/// operation is reached it is dropped or torn according to [`PowerCut`],
+/// and every access after that fails with [`FaultFlashError::PowerLost`].
+/// Use [`FaultFlash::into_inner`] to "reboot" and inspect what survived.
+pub struct FaultFlash<S> {
+    inner: S,
+    ops: usize,
+    cut: Option<(usize, PowerCut)>,
+    powered: bool,
+}
+
+impl<S: NorFlash> FaultFlash<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            ops: 0,
+            cut: None,
+            powered: true,
+        }
+    }
+
+    /// Lose power at operation `op` (counting from 0).
+    pub fn cut_power_at(mut self, op: usize, cut: PowerCut) -> Self {
+        self.cut = Some((op, cut));
+        self
+    }
+
+    /// Erase and write operations seen so far.
+    pub fn op_count(&self) -> usize {
+        self.ops
+    }
+
+    pub fn is_powered(&self) -> bool {
+        self.powered
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    /// Returns how the current operation should be interrupted, if at all.
+    fn next_op(&mut self) -> Result<Option<PowerCut>, FaultFlashError<S::Error>> {
+        if !self.powered {
+            return Err(FaultFlashError::PowerLost);
+        }
+        let op = self.ops;
+        self.ops += 1;
+        match self.cut {
+            Some((cut_op, cut)) if cut_op == op => {
+                self.powered = false;
+                Ok(Some(cut))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+impl<S: NorFlash> ErrorType for FaultFlash<S> {
+    type Error = FaultFlashError<S::Error>;
+}
+
+impl<S: NorFlash> ReadNorFlash for FaultFlash<S> {
+    const READ_SIZE: usize = S::READ_SIZE;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        if !self.powered {
+            return Err(FaultFlashError::PowerLost);
+        }
+        self.inner
+            .read(offset, bytes)
+            .map_err(FaultFlashError::Flash)
+    }
+
+    fn capacity(&self) -> usize {
+        self.inner.capacity()
+    }
+}
+
+impl<S: NorFlash> NorFlash for FaultFlash<S> {
+    const WRITE_SIZE: usize = S::WRITE_SIZE;
+    const ERASE_SIZE: usize = S::ERASE_SIZE;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        match self.next_op()? {
+            None => self.inner.erase(from, to).map_err(FaultFlashError::Flash),
+            Some(PowerCut::Before) => Err(FaultFlashError::PowerLost),
+            Some(PowerCut::Torn) => {
+                let blocks = (to - from) / S::ERASE_SIZE as u32;
+                let torn_to = from + blocks / 2 * S::ERASE_SIZE as u32;
+                if torn_to > from {
+                    self.inner
+                        .erase(from, torn_to)
+                        .map_err(FaultFlashError::Flash)?;
+                }
+                Err(FaultFlashError::PowerLost)
+            }
+        }
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        match self.next_op()? {
+            None => self
+                .inner
+                .write(offset, bytes)
+                .map_err(FaultFlashError::Flash),
+            Some(PowerCut::Before) => Err(FaultFlashError::PowerLost),
+            Some(PowerCut::Torn) => {
+                let words = bytes.len() / S::WRITE_SIZE;
+                let written = words / 2 * S::WRITE_SIZE;
+                if written > 0 {
+                    self.inner
+                        .write(offset, &bytes[..written])
+                        .map_err(FaultFlashError::Flash)?;
+                }
+                if written < bytes.len() {
+                    // Only some of the bits in the torn word made it
+                    let mut torn_word = vec![0xFF; S::WRITE_SIZE];
+                    for (torn, byte) in torn_word.iter_mut().zip(&bytes[written..]) {
+                        *torn = byte | 0xAA;
+                    }
+                    self.inner
+                        .write(offset + written as u32, &torn_word)
+                        .map_err(FaultFlashError::Flash)?;
+                }
+                Err(FaultFlashError::PowerLost)
+            }
+        }
+    }
+}
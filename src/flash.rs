@@ -0,0 +1,115 @@
+use crate::error::{Result, UpgradeError};
+use embedded_storage::nor_flash::{ErrorType, NorFlash, ReadNorFlash};
+use embedded_storage_async::nor_flash::{
+    NorFlash as AsyncNorFlash, ReadNorFlash as AsyncReadNorFlash,
+};
+use log::error;
+
+/// Lets a blocking [`NorFlash`] driver be used where an async one is expected.
+///
+/// Every operation finishes on the first poll, so this is also how the
+/// blocking API runs the async code underneath.
+pub struct BlockingAsync<S> {
+    inner: S,
+}
+
+impl<S> BlockingAsync<S> {
+    pub fn new(inner: S) -> Self {
+        Self { inner }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: ErrorType> ErrorType for BlockingAsync<S> {
+    type Error = S::Error;
+}
+
+impl<S: ReadNorFlash> AsyncReadNorFlash for BlockingAsync<S> {
+    const READ_SIZE: usize = S::READ_SIZE;
+
+    async fn read(&mut self, offset: u32, bytes: &mut [u8]) -> core::result::Result<(), S::Error> {
+        self.inner.read(offset, bytes)
+    }
+
+    fn capacity(&self) -> usize {
+        self.inner.capacity()
+    }
+}
+
+impl<S: NorFlash> AsyncNorFlash for BlockingAsync<S> {
+    const WRITE_SIZE: usize = S::WRITE_SIZE;
+    const ERASE_SIZE: usize = S::ERASE_SIZE;
+
+    async fn erase(&mut self, from: u32, to: u32) -> core::result::Result<(), S::Error> {
+        self.inner.erase(from, to)
+    }
+
+    async fn write(&mut self, offset: u32, bytes: &[u8]) -> core::result::Result<(), S::Error> {
+        self.inner.write(offset, bytes)
+    }
+}
+
+/// Size of the record logs kept in the resume and report partitions.
+pub(crate) const LOG_SECTOR_SIZE: usize = 0x1000;
+
+/// Log records of `record_size` bytes are padded to whole words.
+pub(crate) fn record_stride<S: AsyncNorFlash>(record_size: usize) -> usize {
+    record_size.next_multiple_of(S::WRITE_SIZE)
+}
+
+/// A log rounded up to whole erase blocks.
+pub(crate) fn log_len<S: AsyncNorFlash>() -> usize {
+    LOG_SECTOR_SIZE.next_multiple_of(S::ERASE_SIZE)
+}
+
+/// Largest `S::WRITE_SIZE` [`write_padded`] can pad to.
+const MAX_WRITE_SIZE: usize = 256;
+
+/// Writes `bytes` at `offset`, padding the last word up to `S::WRITE_SIZE` with 0xFF.
+pub(crate) async fn write_padded<S: AsyncNorFlash>(
+    storage: &mut S,
+    offset: u32,
+    bytes: &[u8],
+) -> Result<()> {
+    if S::WRITE_SIZE > MAX_WRITE_SIZE {
+        error!(
+            "write size {:#x} is larger than {:#x}",
+            S::WRITE_SIZE,
+            MAX_WRITE_SIZE
+        );
+        return Err(UpgradeError::UnsupportedAlignment);
+    }
+    check_aligned(offset as usize, S::WRITE_SIZE)?;
+    let aligned = bytes.len() - bytes.len() % S::WRITE_SIZE;
+    if aligned > 0 {
+        storage
+            .write(offset, &bytes[..aligned])
+            .await
+            .map_err(|_| UpgradeError::StorageError)?;
+    }
+    if aligned < bytes.len() {
+        let mut word = [0xFF; MAX_WRITE_SIZE];
+        word[..bytes.len() - aligned].copy_from_slice(&bytes[aligned..]);
+        storage
+            .write(offset + aligned as u32, &word[..S::WRITE_SIZE])
+            .await
+            .map_err(|_| UpgradeError::StorageError)?;
+    }
+    Ok(())
+}
+
+/// Fails if `value` isn't a multiple of `unit`, i.e. the driver's
+/// granularity doesn't fit a layout the crate or the bootloader fixes.
+pub(crate) fn check_aligned(value: usize, unit: usize) -> Result<()> {
+    if !value.is_multiple_of(unit) {
+        error!(
+            "{:#x} isn't a multiple of the flash granularity {:#x}",
+            value, unit
+        );
+        return Err(UpgradeError::UnsupportedAlignment);
+    }
+    Ok(())
+}
@@ -0,0 +1,305 @@
+use crate::checksum::Sha256Digest;
+use crate::error::{Result, UpgradeError};
+use log::{debug, error};
+use sha2::{Digest, Sha256};
+
+/// First byte of every ESP app image.
+pub const IMAGE_MAGIC: u8 = 0xE9;
+/// Size of the image header including the extended header.
+pub const IMAGE_HEADER_SIZE: usize = 24;
+/// Size of the header in front of every segment.
+pub const SEGMENT_HEADER_SIZE: usize = 8;
+
+const MAX_SEGMENTS: u8 = 16;
+const CHECKSUM_SEED: u8 = 0xEF;
+/// Segments can't be larger than the biggest flash chip.
+const MAX_SEGMENT_SIZE: u32 = 16 * 1024 * 1024;
+
+/// Why an image was rejected.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ImageError {
+    /// Doesn't start with [`IMAGE_MAGIC`], e.g. an HTML error page.
+    BadMagic(u8),
+    TooManySegments(u8),
+    SegmentTooLarge(u32),
+    /// Built for a different chip than the one we're running on.
+    WrongChip {
+        expected: ChipId,
+        found: ChipId,
+    },
+    /// The stream ended before the image did.
+    Truncated,
+    BadChecksum {
+        expected: u8,
+        found: u8,
+    },
+    BadHash,
+    /// The first segment doesn't start with an `esp_app_desc_t`.
+    NoAppDescriptor,
+}
+
+/// Chip id from the extended image header.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct ChipId(pub u16);
+
+impl ChipId {
+    pub const ESP32: Self = Self(0x0000);
+    pub const ESP32S2: Self = Self(0x0002);
+    pub const ESP32C3: Self = Self(0x0005);
+    pub const ESP32S3: Self = Self(0x0009);
+    pub const ESP32C2: Self = Self(0x000C);
+    pub const ESP32C6: Self = Self(0x000D);
+    pub const ESP32H2: Self = Self(0x0010);
+    pub const ESP32P4: Self = Self(0x0012);
+}
+
+/// `esp_image_header_t`: the fixed header at the start of an app image.
+/// [documented here](https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/system/app_image_format.html)
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct ImageHeader {
+    pub segment_count: u8,
+    pub spi_mode: u8,
+    pub spi_speed_size: u8,
+    pub entry_addr: u32,
+    pub chip_id: ChipId,
+    pub min_chip_rev_full: u16,
+    pub max_chip_rev_full: u16,
+    /// A SHA-256 of the image follows the checksum byte.
+    pub hash_appended: bool,
+}
+
+impl TryFrom<[u8; IMAGE_HEADER_SIZE]> for ImageHeader {
+    type Error = UpgradeError;
+    fn try_from(value: [u8; IMAGE_HEADER_SIZE]) -> Result<Self> {
+        if value[0] != IMAGE_MAGIC {
+            return Err(UpgradeError::InvalidImage(ImageError::BadMagic(value[0])));
+        }
+        if value[1] > MAX_SEGMENTS {
+            return Err(UpgradeError::InvalidImage(ImageError::TooManySegments(
+                value[1],
+            )));
+        }
+        Ok(Self {
+            segment_count: value[1],
+            spi_mode: value[2],
+            spi_speed_size: value[3],
+            entry_addr: u32::from_le_bytes(value[4..8].try_into().unwrap()),
+            chip_id: ChipId(u16::from_le_bytes(value[12..14].try_into().unwrap())),
+            min_chip_rev_full: u16::from_le_bytes(value[15..17].try_into().unwrap()),
+            max_chip_rev_full: u16::from_le_bytes(value[17..19].try_into().unwrap()),
+            hash_appended: value[23] == 1,
+        })
+    }
+}
+
+/// `esp_image_segment_header_t`
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct SegmentHeader {
+    pub load_addr: u32,
+    pub data_len: u32,
+}
+
+impl TryFrom<[u8; SEGMENT_HEADER_SIZE]> for SegmentHeader {
+    type Error = UpgradeError;
+    fn try_from(value: [u8; SEGMENT_HEADER_SIZE]) -> Result<Self> {
+        let data_len = u32::from_le_bytes(value[4..8].try_into().unwrap());
+        if data_len > MAX_SEGMENT_SIZE {
+            return Err(UpgradeError::InvalidImage(ImageError::SegmentTooLarge(
+                data_len,
+            )));
+        }
+        Ok(Self {
+            load_addr: u32::from_le_bytes(value[0..4].try_into().unwrap()),
+            data_len,
+        })
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum State {
+    Header,
+    SegmentHeader,
+    SegmentData {
+        remaining: u32,
+    },
+    Padding,
+    Checksum,
+    Hash,
+    /// Anything after the image proper (e.g. a secure boot signature block).
+    Trailer,
+}
+
+/// Checks an ESP app image while it streams past.
+///
+/// Feed it every byte of the image in order with [`ImageValidator::update`],
+/// bad headers are reported as soon as they show up. [`ImageValidator::finish`]
+/// then checks the checksum byte and, if present, the appended SHA-256.
+pub struct ImageValidator {
+    state: State,
+    expected_chip: Option<ChipId>,
+    header: Option<ImageHeader>,
+    /// Collects headers and the appended hash, which can straddle reads.
+    scratch: [u8; 32],
+    scratch_len: usize,
+    segments_left: u8,
+    position: usize,
+    checksum: u8,
+    hasher: Sha256,
+}
+
+impl ImageValidator {
+    pub fn new(expected_chip: Option<ChipId>) -> Self {
+        Self {
+            state: State::Header,
+            expected_chip,
+            header: None,
+            scratch: [0; 32],
+            scratch_len: 0,
+            segments_left: 0,
+            position: 0,
+            checksum: CHECKSUM_SEED,
+            hasher: Sha256::new(),
+        }
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) -> Result<()> {
+        while !data.is_empty() {
+            let used = match self.state {
+                State::Header => self.fill_scratch(data, IMAGE_HEADER_SIZE),
+                State::SegmentHeader => self.fill_scratch(data, SEGMENT_HEADER_SIZE),
+                State::SegmentData { remaining } => {
+                    let len = data.len().min(remaining as usize);
+                    self.checksum = data[..len].iter().fold(self.checksum, |acc, b| acc ^ b);
+                    len
+                }
+                State::Padding => data.len().min(15 - self.position % 16),
+                State::Checksum => 1,
+                State::Hash => self.fill_scratch(data, 32),
+                State::Trailer => data.len(),
+            };
+
+            if matches!(
+                self.state,
+                State::Header
+                    | State::SegmentHeader
+                    | State::SegmentData { .. }
+                    | State::Padding
+                    | State::Checksum
+            ) {
+                self.hasher.update(&data[..used]);
+            }
+            self.position += used;
+            self.advance(&data[..used])?;
+            data = &data[used..];
+        }
+        Ok(())
+    }
+
+    /// Header of the image, once enough of it has been seen.
+    pub fn header(&self) -> Option<&ImageHeader> {
+        self.header.as_ref()
+    }
+
+    pub fn finish(self) -> Result<ImageHeader> {
+        match (self.state, self.header) {
+            (State::Trailer, Some(header)) => Ok(header),
+            _ => {
+                error!("image ended early at {} bytes", self.position);
+                Err(UpgradeError::InvalidImage(ImageError::Truncated))
+            }
+        }
+    }
+
+    /// Collects up to `len` bytes into the scratch buffer, returns how many were taken.
+    fn fill_scratch(&mut self, data: &[u8], len: usize) -> usize {
+        let used = data.len().min(len - self.scratch_len);
+        self.scratch[self.scratch_len..self.scratch_len + used].copy_from_slice(&data[..used]);
+        self.scratch_len += used;
+        used
+    }
+
+    fn advance(&mut self, data: &[u8]) -> Result<()> {
+        self.state = match self.state {
+            State::Header if self.scratch_len == IMAGE_HEADER_SIZE => {
+                let mut header = [0; IMAGE_HEADER_SIZE];
+                header.copy_from_slice(&self.scratch[..IMAGE_HEADER_SIZE]);
+                let header = ImageHeader::try_from(header)?;
+                debug!("image header: {:?}", header);
+                if let Some(expected) = self.expected_chip {
+                    if header.chip_id != expected {
+                        return Err(UpgradeError::InvalidImage(ImageError::WrongChip {
+                            expected,
+                            found: header.chip_id,
+                        }));
+                    }
+                }
+                self.header = Some(header);
+                self.segments_left = header.segment_count;
+                self.scratch_len = 0;
+                self.next_segment()
+            }
+            State::SegmentHeader if self.scratch_len == SEGMENT_HEADER_SIZE => {
+                let mut segment_header = [0; SEGMENT_HEADER_SIZE];
+                segment_header.copy_from_slice(&self.scratch[..SEGMENT_HEADER_SIZE]);
+                let segment = SegmentHeader::try_from(segment_header)?;
+                debug!("image segment: {:?}", segment);
+                self.scratch_len = 0;
+                self.segments_left -= 1;
+                if segment.data_len == 0 {
+                    self.next_segment()
+                } else {
+                    State::SegmentData {
+                        remaining: segment.data_len,
+                    }
+                }
+            }
+            State::SegmentData { remaining } => {
+                let remaining = remaining - data.len() as u32;
+                if remaining == 0 {
+                    self.next_segment()
+                } else {
+                    State::SegmentData { remaining }
+                }
+            }
+            State::Padding if self.position % 16 == 15 => State::Checksum,
+            State::Checksum => {
+                if data[0] != self.checksum {
+                    error!(
+                        "image checksum {:02x}, expected {:02x}",
+                        data[0], self.checksum
+                    );
+                    return Err(UpgradeError::InvalidImage(ImageError::BadChecksum {
+                        expected: self.checksum,
+                        found: data[0],
+                    }));
+                }
+                match self.header {
+                    Some(header) if header.hash_appended => State::Hash,
+                    _ => State::Trailer,
+                }
+            }
+            State::Hash if self.scratch_len == 32 => {
+                let digest: Sha256Digest = self.hasher.clone().finalize().into();
+                if self.scratch[..32] != digest {
+                    error!("appended image hash doesn't match");
+                    return Err(UpgradeError::InvalidImage(ImageError::BadHash));
+                }
+                State::Trailer
+            }
+            state => state,
+        };
+        Ok(())
+    }
+
+    /// State after a header or segment has been fully consumed.
+    /// The checksum byte sits at the end of a 16 byte block, zero padding fills the gap.
+    fn next_segment(&self) -> State {
+        if self.segments_left > 0 {
+            State::SegmentHeader
+        } else if self.position % 16 == 15 {
+            State::Checksum
+        } else {
+            State::Padding
+        }
+    }
+}
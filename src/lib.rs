@@ -2,15 +2,57 @@
 
 extern crate alloc;
 
+pub mod app_desc;
+pub mod auth;
 pub mod botifactory;
+pub mod checksum;
+pub mod decompress;
+pub mod delta;
+pub mod encryption;
 pub mod error;
+#[cfg(feature = "std")]
+pub mod fault_flash;
+pub mod flash;
+pub mod image;
 pub mod partition;
+pub mod progress;
+#[cfg(feature = "std")]
+pub mod ram_flash;
+pub mod redirect;
+pub mod release;
+pub mod report;
+pub mod resume;
+pub mod retry;
 mod seq_crc;
+pub mod signature;
 pub mod storage;
+pub mod tls;
+pub mod update;
 pub mod upgrade_data;
 
+pub use app_desc::*;
+pub use auth::*;
 pub use botifactory::*;
+pub use checksum::*;
+pub use decompress::*;
+pub use delta::*;
+pub use encryption::*;
 pub use error::*;
+#[cfg(feature = "std")]
+pub use fault_flash::*;
+pub use flash::*;
+pub use image::*;
 pub use partition::*;
+pub use progress::*;
+#[cfg(feature = "std")]
+pub use ram_flash::*;
+pub use redirect::*;
+pub use release::*;
+pub use report::*;
+pub use resume::*;
+pub use retry::*;
+pub use signature::*;
 pub use storage::*;
+pub use tls::*;
+pub use update::*;
 pub use upgrade_data::*;
@@ -1,11 +1,18 @@
 use crate::error::{Result, UpgradeError};
-use embedded_storage::nor_flash::NorFlash;
+use crate::resume::RESUME_PARTITION_NAME;
+use alloc::vec::Vec;
+use embedded_storage::nor_flash::{ErrorType, NorFlashErrorKind, ReadNorFlash};
+use embedded_storage_async::nor_flash::ReadNorFlash as AsyncReadNorFlash;
 use esp_partition_table::{
     AppPartitionType, DataPartitionType, PartitionEntry, PartitionTable, PartitionType,
 };
-use log::{debug, info};
 
-pub fn find_ota_partition<S: NorFlash>(storage: &mut S) -> Result<PartitionEntry> {
+/// Address the bootloader expects the partition table at.
+pub const PARTITION_TABLE_OFFSET: u32 = 0x8000;
+/// Size reserved for the partition table.
+pub const PARTITION_TABLE_SIZE: usize = 0xC00;
+
+pub fn find_ota_partition<S: ReadNorFlash>(storage: &mut S) -> Result<PartitionEntry> {
     let table = PartitionTable::default();
 
     for partition in table.iter_nor_flash(storage, false).flatten() {
@@ -17,24 +24,64 @@ pub fn find_ota_partition<S: NorFlash>(storage: &mut S) -> Result<PartitionEntry
     Err(UpgradeError::PartitionNotFound)
 }
 
-pub fn find_running_partition<S: NorFlash>(storage: &mut S, seq: u32) -> Result<PartitionEntry> {
-    let partition_number = ((seq + 1) % 2) as u8;
-    find_partition_by_type(
-        storage,
-        PartitionType::App(AppPartitionType::Ota(partition_number)),
-    )
+/// Partition the bootloader boots for otadata `seq`.
+pub fn find_running_partition<S: ReadNorFlash>(
+    storage: &mut S,
+    seq: u32,
+) -> Result<PartitionEntry> {
+    let slot_count = count_ota_slots(storage)?;
+    find_ota_slot(storage, running_slot(seq, slot_count))
+}
+
+/// Partition the next update goes to by default, the slot after the running one.
+pub fn find_inactive_partition<S: ReadNorFlash>(
+    storage: &mut S,
+    seq: u32,
+) -> Result<PartitionEntry> {
+    let slot_count = count_ota_slots(storage)?;
+    find_ota_slot(storage, next_slot(seq, slot_count))
 }
 
-pub fn find_inactive_partition<S: NorFlash>(storage: &mut S, seq: u32) -> Result<PartitionEntry> {
-    let partition_number = (seq % 2) as u8;
-    find_partition_by_type(
-        storage,
-        PartitionType::App(AppPartitionType::Ota(partition_number)),
-    )
+/// The `ota_<slot>` app partition.
+pub fn find_ota_slot<S: ReadNorFlash>(storage: &mut S, slot: u8) -> Result<PartitionEntry> {
+    find_partition_by_type(storage, PartitionType::App(AppPartitionType::Ota(slot)))
+}
+
+/// Number of `ota_N` app partitions in the partition table.
+pub fn count_ota_slots<S: ReadNorFlash>(storage: &mut S) -> Result<u8> {
+    let table = PartitionTable::default();
+
+    let mut count = 0;
+    for entry in table.iter_nor_flash(storage, false) {
+        if let PartitionType::App(AppPartitionType::Ota(_)) = entry?.type_ {
+            count += 1;
+        }
+    }
+    if count == 0 {
+        return Err(UpgradeError::PartitionNotFound);
+    }
+    Ok(count)
+}
+
+/// OTA slot the bootloader picks for `seq`, like ESP-IDF's `(seq - 1) % ota_app_count`.
+pub fn running_slot(seq: u32, slot_count: u8) -> u8 {
+    (seq.wrapping_sub(1) % slot_count as u32) as u8
+}
+
+/// The slot after the running one.
+pub fn next_slot(seq: u32, slot_count: u8) -> u8 {
+    (seq % slot_count as u32) as u8
+}
+
+/// Smallest seq after `seq` that makes the bootloader boot `slot`.
+pub fn seq_for_slot(seq: u32, slot: u8, slot_count: u8) -> u32 {
+    let count = slot_count as u32;
+    let skip = (slot as u32 + count - next_slot(seq, slot_count) as u32) % count;
+    seq + 1 + skip
 }
 
 /// Find partition entry by type
-pub fn find_partition_by_type<S: NorFlash>(
+pub fn find_partition_by_type<S: ReadNorFlash>(
     storage: &mut S,
     typ: PartitionType,
 ) -> Result<PartitionEntry> {
@@ -51,7 +98,10 @@ pub fn find_partition_by_type<S: NorFlash>(
 }
 
 /// Find partition entry by name
-pub fn find_partition_by_name<S: NorFlash>(storage: &mut S, name: &str) -> Result<PartitionEntry> {
+pub fn find_partition_by_name<S: ReadNorFlash>(
+    storage: &mut S,
+    name: &str,
+) -> Result<PartitionEntry> {
     let table = PartitionTable::default();
 
     for entry in table.iter_nor_flash(storage, false) {
@@ -62,3 +112,103 @@ pub fn find_partition_by_name<S: NorFlash>(storage: &mut S, name: &str) -> Resul
     }
     Err(UpgradeError::PartitionNotFound)
 }
+
+/// The partitions a download works with, found in one pass over the table.
+///
+/// Built once per operation so the copy of the table (0xC00 bytes) can be
+/// dropped before the image streams in.
+pub(crate) struct OtaPartitions {
+    otadata: Option<PartitionEntry>,
+    /// All `ota_N` app partitions, in table order.
+    slots: Vec<PartitionEntry>,
+    /// See [`RESUME_PARTITION_NAME`].
+    pub(crate) resume: Option<PartitionEntry>,
+}
+
+impl OtaPartitions {
+    pub(crate) async fn read<S: AsyncReadNorFlash>(storage: &mut S) -> Result<Self> {
+        let mut table = PartitionTableBuffer::read(storage).await?;
+        let mut partitions = Self {
+            otadata: None,
+            slots: Vec::new(),
+            resume: None,
+        };
+        for entry in PartitionTable::default().iter_nor_flash(&mut table, false) {
+            let entry = entry?;
+            match entry.type_ {
+                PartitionType::Data(DataPartitionType::Ota) => {
+                    partitions.otadata.get_or_insert(entry);
+                }
+                PartitionType::App(AppPartitionType::Ota(_)) => partitions.slots.push(entry),
+                _ if entry.name() == RESUME_PARTITION_NAME => {
+                    partitions.resume.get_or_insert(entry);
+                }
+                _ => {}
+            }
+        }
+        Ok(partitions)
+    }
+
+    pub(crate) fn otadata(&self) -> Result<&PartitionEntry> {
+        self.otadata.as_ref().ok_or(UpgradeError::PartitionNotFound)
+    }
+
+    /// Like [`count_ota_slots`].
+    pub(crate) fn slot_count(&self) -> Result<u8> {
+        match self.slots.len() {
+            0 => Err(UpgradeError::PartitionNotFound),
+            count => Ok(count as u8),
+        }
+    }
+
+    /// Like [`find_ota_slot`].
+    pub(crate) fn ota_slot(&self, slot: u8) -> Result<PartitionEntry> {
+        self.slots
+            .iter()
+            .find(|entry| entry.type_ == PartitionType::App(AppPartitionType::Ota(slot)))
+            .cloned()
+            .ok_or(UpgradeError::PartitionNotFound)
+    }
+}
+
+/// In-memory copy of the partition table.
+///
+/// Async flash is read into this once, the lookups above then run on it.
+pub struct PartitionTableBuffer {
+    data: [u8; PARTITION_TABLE_SIZE],
+}
+
+impl PartitionTableBuffer {
+    pub async fn read<S: AsyncReadNorFlash>(storage: &mut S) -> Result<Self> {
+        let mut data = [0xFF; PARTITION_TABLE_SIZE];
+        storage
+            .read(PARTITION_TABLE_OFFSET, &mut data)
+            .await
+            .map_err(|_| UpgradeError::StorageError)?;
+        Ok(Self { data })
+    }
+}
+
+impl ErrorType for PartitionTableBuffer {
+    type Error = NorFlashErrorKind;
+}
+
+impl ReadNorFlash for PartitionTableBuffer {
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> core::result::Result<(), Self::Error> {
+        let start = offset
+            .checked_sub(PARTITION_TABLE_OFFSET)
+            .ok_or(NorFlashErrorKind::OutOfBounds)? as usize;
+        let data = self
+            .data
+            .get(start..start + bytes.len())
+            .ok_or(NorFlashErrorKind::OutOfBounds)?;
+        bytes.copy_from_slice(data);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        PARTITION_TABLE_OFFSET as usize + PARTITION_TABLE_SIZE
+    }
+}
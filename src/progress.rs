@@ -0,0 +1,100 @@
+/// What a firmware update is busy with.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Phase {
+    /// Erasing the inactive partition up front, see [`crate::EraseMode::Full`].
+    Erasing,
+    /// Streaming the image into flash.
+    Downloading,
+    /// Checking the image, digest and signature.
+    Verifying,
+    /// Switching otadata over to the new image.
+    Committing,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Progress {
+    pub phase: Phase,
+    /// Bytes of the image written so far.
+    pub written: usize,
+    /// Size of the image if known, e.g. from `Content-Length`.
+    pub total: Option<usize>,
+}
+
+impl Progress {
+    /// Percentage done, if the total is known.
+    pub fn percent(&self) -> Option<u8> {
+        self.total
+            .filter(|total| *total > 0)
+            .map(|total| (self.written.min(total) * 100 / total) as u8)
+    }
+}
+
+/// Gets told how a firmware update is going.
+///
+/// Implemented for any `FnMut(Progress)` closure.
+pub trait ProgressObserver {
+    fn on_progress(&mut self, progress: Progress);
+}
+
+impl<F: FnMut(Progress)> ProgressObserver for F {
+    fn on_progress(&mut self, progress: Progress) {
+        self(progress)
+    }
+}
+
+/// Report at least every this many bytes if the total isn't known.
+const DEFAULT_STEP: usize = 16 * 1024;
+
+/// Rate limits calls into a [`ProgressObserver`].
+///
+/// Phase changes are always reported, while downloading the observer is
+/// only called once per percent (or every 16 KiB if the total is unknown).
+pub(crate) struct ProgressReporter<'a> {
+    observer: Option<&'a mut dyn ProgressObserver>,
+    total: Option<usize>,
+    step: usize,
+    phase: Option<Phase>,
+    last_reported: usize,
+}
+
+impl<'a> ProgressReporter<'a> {
+    pub(crate) fn new(
+        observer: Option<&'a mut dyn ProgressObserver>,
+        total: Option<usize>,
+    ) -> Self {
+        let step = match total {
+            Some(total) => (total / 100).max(1),
+            None => DEFAULT_STEP,
+        };
+        Self {
+            observer,
+            total,
+            step,
+            phase: None,
+            last_reported: 0,
+        }
+    }
+
+    pub(crate) fn report(&mut self, phase: Phase, written: usize) {
+        let Some(observer) = self.observer.as_mut() else {
+            return;
+        };
+        let finished = self.total == Some(written);
+        if self.phase == Some(phase)
+            && written.saturating_sub(self.last_reported) < self.step
+            && !finished
+        {
+            return;
+        }
+        if self.phase == Some(phase) && written == self.last_reported {
+            return;
+        }
+        self.phase = Some(phase);
+        self.last_reported = written;
+        observer.on_progress(Progress {
+            phase,
+            written,
+            total: self.total,
+        });
+    }
+}
@@ -0,0 +1,224 @@
+use crate::partition::{PARTITION_TABLE_OFFSET, PARTITION_TABLE_SIZE};
+use alloc::vec;
+use alloc::vec::Vec;
+use embedded_storage::nor_flash::{
+    check_erase, check_read, check_write, ErrorType, NorFlash, NorFlashError, NorFlashErrorKind,
+    ReadNorFlash,
+};
+
+const PARTITION_ENTRY_SIZE: usize = 32;
+const PARTITION_MAGIC: [u8; 2] = [0xAA, 0x50];
+
+const APP_TYPE: u8 = 0x00;
+const DATA_TYPE: u8 = 0x01;
+const FACTORY_SUBTYPE: u8 = 0x00;
+const OTA_SUBTYPE_BASE: u8 = 0x10;
+const OTADATA_SUBTYPE: u8 = 0x00;
+const NVS_SUBTYPE: u8 = 0x02;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum RamFlashError {
+    /// Offset or length not a multiple of the write/erase size.
+    NotAligned,
+    /// Access past the end of the flash.
+    OutOfBounds,
+    /// A write tried to flip a bit from 0 back to 1 without an erase.
+    NotErased { offset: u32 },
+}
+
+impl NorFlashError for RamFlashError {
+    fn kind(&self) -> NorFlashErrorKind {
+        match self {
+            RamFlashError::NotAligned => NorFlashErrorKind::NotAligned,
+            RamFlashError::OutOfBounds => NorFlashErrorKind::OutOfBounds,
+            RamFlashError::NotErased { .. } => NorFlashErrorKind::Other,
+        }
+    }
+}
+
+impl From<NorFlashErrorKind> for RamFlashError {
+    fn from(kind: NorFlashErrorKind) -> Self {
+        match kind {
+            NorFlashErrorKind::NotAligned => RamFlashError::NotAligned,
+            _ => RamFlashError::OutOfBounds,
+        }
+    }
+}
+
+/// In-memory NOR flash for host side testing.
+///
+/// Follows the same rules as real NOR flash: erased bytes read as `0xFF`,
+/// writes can only clear bits and erases have to cover whole sectors.
+/// `WRITE_SIZE` and `ERASE_SIZE` can be tuned to mimic different chips.
+#[derive(Debug, Clone)]
+pub struct RamFlash<const WRITE_SIZE: usize = 1, const ERASE_SIZE: usize = 4096> {
+    data: Vec<u8>,
+}
+
+impl<const WRITE_SIZE: usize, const ERASE_SIZE: usize> RamFlash<WRITE_SIZE, ERASE_SIZE> {
+    /// Blank (fully erased) flash of `size` bytes.
+    pub fn new(size: usize) -> Self {
+        assert!(
+            size.is_multiple_of(ERASE_SIZE),
+            "flash size must be a multiple of ERASE_SIZE"
+        );
+        Self {
+            data: vec![0xFF; size],
+        }
+    }
+
+    /// Start laying out a partition table on a flash of `size` bytes.
+    pub fn builder(size: usize) -> RamFlashBuilder<WRITE_SIZE, ERASE_SIZE> {
+        RamFlashBuilder {
+            flash: Self::new(size),
+            entries: Vec::new(),
+        }
+    }
+
+    /// The usual two slot OTA layout: nvs, otadata, phy_init, ota_0 and ota_1,
+    /// with `app_size` bytes per app slot.
+    pub fn with_ota_layout(app_size: usize) -> Self {
+        let ota_0 = 0x10000;
+        let ota_1 = ota_0 + app_size as u32;
+        Self::builder(ota_1 as usize + app_size)
+            .nvs(0x9000, 0x4000)
+            .otadata(0xD000, 0x2000)
+            .partition("phy_init", DATA_TYPE, 0x01, 0xF000, 0x1000)
+            .ota(0, ota_0, app_size)
+            .ota(1, ota_1, app_size)
+            .build()
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn as_bytes_mut(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+}
+
+impl<const WRITE_SIZE: usize, const ERASE_SIZE: usize> ErrorType
+    for RamFlash<WRITE_SIZE, ERASE_SIZE>
+{
+    type Error = RamFlashError;
+}
+
+impl<const WRITE_SIZE: usize, const ERASE_SIZE: usize> ReadNorFlash
+    for RamFlash<WRITE_SIZE, ERASE_SIZE>
+{
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        check_read(self, offset, bytes.len())?;
+        let offset = offset as usize;
+        bytes.copy_from_slice(&self.data[offset..offset + bytes.len()]);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        self.data.len()
+    }
+}
+
+impl<const WRITE_SIZE: usize, const ERASE_SIZE: usize> NorFlash
+    for RamFlash<WRITE_SIZE, ERASE_SIZE>
+{
+    const WRITE_SIZE: usize = WRITE_SIZE;
+    const ERASE_SIZE: usize = ERASE_SIZE;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        check_erase(self, from, to)?;
+        self.data[from as usize..to as usize].fill(0xFF);
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        check_write(self, offset, bytes.len())?;
+        let start = offset as usize;
+        let target = &mut self.data[start..start + bytes.len()];
+        if let Some(position) = target
+            .iter()
+            .zip(bytes)
+            .position(|(current, new)| current & new != *new)
+        {
+            return Err(RamFlashError::NotErased {
+                offset: offset + position as u32,
+            });
+        }
+        target.copy_from_slice(bytes);
+        Ok(())
+    }
+}
+
+/// Writes a partition table into a [`RamFlash`] in the binary format
+/// `esp_partition_table::PartitionTable` reads.
+pub struct RamFlashBuilder<const WRITE_SIZE: usize, const ERASE_SIZE: usize> {
+    flash: RamFlash<WRITE_SIZE, ERASE_SIZE>,
+    entries: Vec<[u8; PARTITION_ENTRY_SIZE]>,
+}
+
+impl<const WRITE_SIZE: usize, const ERASE_SIZE: usize> RamFlashBuilder<WRITE_SIZE, ERASE_SIZE> {
+    pub fn nvs(self, offset: u32, size: usize) -> Self {
+        self.partition("nvs", DATA_TYPE, NVS_SUBTYPE, offset, size)
+    }
+
+    pub fn otadata(self, offset: u32, size: usize) -> Self {
+        self.partition("otadata", DATA_TYPE, OTADATA_SUBTYPE, offset, size)
+    }
+
+    pub fn factory(self, offset: u32, size: usize) -> Self {
+        self.partition("factory", APP_TYPE, FACTORY_SUBTYPE, offset, size)
+    }
+
+    /// Adds the `ota_<slot>` app partition.
+    pub fn ota(self, slot: u8, offset: u32, size: usize) -> Self {
+        let name = alloc::format!("ota_{}", slot);
+        self.partition(&name, APP_TYPE, OTA_SUBTYPE_BASE + slot, offset, size)
+    }
+
+    /// Adds an arbitrary entry using raw type and subtype codes.
+    pub fn partition(
+        mut self,
+        name: &str,
+        type_: u8,
+        subtype: u8,
+        offset: u32,
+        size: usize,
+    ) -> Self {
+        assert!(name.len() < 16, "partition name too long: {}", name);
+        assert!(
+            offset as usize + size <= self.flash.capacity(),
+            "partition {} doesn't fit in flash",
+            name
+        );
+        assert!(
+            (offset as usize) >= PARTITION_TABLE_OFFSET as usize + PARTITION_TABLE_SIZE,
+            "partition {} overlaps the partition table",
+            name
+        );
+        assert!(
+            (self.entries.len() + 1) * PARTITION_ENTRY_SIZE <= PARTITION_TABLE_SIZE,
+            "too many partitions"
+        );
+
+        let mut entry = [0; PARTITION_ENTRY_SIZE];
+        entry[0..2].copy_from_slice(&PARTITION_MAGIC);
+        entry[2] = type_;
+        entry[3] = subtype;
+        entry[4..8].copy_from_slice(&offset.to_le_bytes());
+        entry[8..12].copy_from_slice(&(size as u32).to_le_bytes());
+        entry[12..12 + name.len()].copy_from_slice(name.as_bytes());
+        self.entries.push(entry);
+        self
+    }
+
+    pub fn build(self) -> RamFlash<WRITE_SIZE, ERASE_SIZE> {
+        let mut flash = self.flash;
+        for (index, entry) in self.entries.iter().enumerate() {
+            let start = PARTITION_TABLE_OFFSET as usize + index * PARTITION_ENTRY_SIZE;
+            flash.data[start..start + PARTITION_ENTRY_SIZE].copy_from_slice(entry);
+        }
+        flash
+    }
+}
@@ -0,0 +1,68 @@
+//! Following redirects, e.g. from the botifactory server to the object
+//! storage the binaries live in.
+
+use alloc::format;
+use alloc::string::{String, ToString};
+
+/// Redirects followed for a download unless configured otherwise, see
+/// [`crate::BotifactoryClient::with_max_redirects`].
+pub const DEFAULT_MAX_REDIRECTS: u8 = 5;
+
+/// Whether `status` redirects to the `Location` header.
+pub fn is_redirect(status: u16) -> bool {
+    matches!(status, 301 | 302 | 307 | 308)
+}
+
+/// `scheme://host:port` of `url`, `None` if it isn't absolute.
+pub fn origin(url: &str) -> Option<&str> {
+    let (scheme, rest) = url.split_once("://")?;
+    let end = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+    Some(&url[..scheme.len() + 3 + end])
+}
+
+/// Whether credentials meant for `from` may be sent to `to`.
+pub fn same_origin(from: &str, to: &str) -> bool {
+    match (origin(from), origin(to)) {
+        (Some(from), Some(to)) => from.eq_ignore_ascii_case(to),
+        _ => false,
+    }
+}
+
+/// Absolute URL a `Location` header sent in response to `base` points at.
+pub fn resolve_location(base: &str, location: &str) -> String {
+    let location = location.trim();
+    if has_scheme(location) {
+        return location.to_string();
+    }
+    let Some(origin) = origin(base) else {
+        return location.to_string();
+    };
+    if let Some(authority) = location.strip_prefix("//") {
+        let (scheme, _) = origin.split_once("://").unwrap_or_default();
+        return format!("{}://{}", scheme, authority);
+    }
+    if location.starts_with('/') {
+        return format!("{}{}", origin, location);
+    }
+    // Relative to the directory of the base path
+    let path = &base[origin.len()..];
+    let path = &path[..path.find(['?', '#']).unwrap_or(path.len())];
+    let directory = &path[..path.rfind('/').map_or(0, |end| end + 1)];
+    if directory.is_empty() {
+        format!("{}/{}", origin, location)
+    } else {
+        format!("{}{}{}", origin, directory, location)
+    }
+}
+
+/// Whether `url` starts with `scheme:`, as opposed to a relative reference
+/// that may still carry a URL in its query.
+fn has_scheme(url: &str) -> bool {
+    let end = url.find([':', '/', '?', '#']).unwrap_or(url.len());
+    let scheme = &url.as_bytes()[..end];
+    url[end..].starts_with(':')
+        && scheme.first().is_some_and(u8::is_ascii_alphabetic)
+        && scheme
+            .iter()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'+' | b'.' | b'-'))
+}
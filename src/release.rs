@@ -0,0 +1,118 @@
+use crate::checksum::{parse_sha256, Sha256Digest};
+use crate::decompress::Encoding;
+use crate::error::{Result, UpgradeError};
+use alloc::string::String;
+use botifactory_types::ReleaseBody;
+use log::error;
+use semver::Version;
+use serde::Deserialize;
+use serde_json_core::str::{EscapedStr, EscapedStringFragment};
+
+/// What the server knows about a release.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Release {
+    pub version: Version,
+    /// The server's id for the release.
+    pub id: Option<u64>,
+    /// Size of the binary in bytes.
+    pub size: Option<usize>,
+    /// SHA-256 of the binary as sent by the server, see [`Self::sha256`].
+    pub hash: Option<String>,
+    /// Where the binary can be downloaded from.
+    pub download_url: Option<String>,
+    /// When the release was created, as sent by the server (RFC 3339).
+    pub created_at: Option<String>,
+    pub notes: Option<String>,
+    /// How the binary is compressed as sent by the server, see [`Self::encoding`].
+    pub encoding: Option<String>,
+}
+
+impl Release {
+    /// Parses the release metadata the server sends, `{"release": {...}}`.
+    /// Only `version` is required, unknown fields are ignored. `hash` and
+    /// `encoding` are kept as they are, so a release this build can't
+    /// download can still be compared against.
+    pub fn from_json(content: &str) -> Result<Self> {
+        let (body, _size): (ReleaseBody, usize) =
+            serde_json_core::from_str(content).map_err(UpgradeError::from)?;
+        let (extras, _size): (ReleaseExtrasBody, usize) =
+            serde_json_core::from_str(content).map_err(UpgradeError::from)?;
+        let release = extras.release;
+
+        Ok(Self {
+            version: body.release.version,
+            id: release.id,
+            size: release.size,
+            hash: release.hash.map(unescape).transpose()?,
+            download_url: release.download_url.map(unescape).transpose()?,
+            created_at: release.created_at.map(unescape).transpose()?,
+            notes: release.notes.map(unescape).transpose()?,
+            encoding: release.encoding.map(unescape).transpose()?,
+        })
+    }
+
+    /// SHA-256 of the binary, pass it on as `expected_sha256`. Fails with
+    /// [`UpgradeError::ChecksumMismatch`] if the server sent something else.
+    pub fn sha256(&self) -> Result<Option<Sha256Digest>> {
+        self.hash
+            .as_deref()
+            .map(|hash| {
+                parse_sha256(hash).ok_or_else(|| {
+                    error!("malformed release hash {}", hash);
+                    UpgradeError::ChecksumMismatch
+                })
+            })
+            .transpose()
+    }
+
+    /// How the binary is compressed, see [`crate::decompress`]. Fails with
+    /// [`UpgradeError::UnsupportedEncoding`] for anything this build can't decode.
+    pub fn encoding(&self) -> Result<Encoding> {
+        match self.encoding.as_deref() {
+            Some(encoding) => Encoding::from_content_encoding(encoding).ok_or_else(|| {
+                error!("unsupported release encoding {}", encoding);
+                UpgradeError::UnsupportedEncoding
+            }),
+            None => Ok(Encoding::Identity),
+        }
+    }
+}
+
+/// The parts of the release metadata `ReleaseBody` doesn't expose.
+#[derive(Deserialize)]
+struct ReleaseExtrasBody<'a> {
+    #[serde(borrow)]
+    release: ReleaseExtras<'a>,
+}
+
+#[derive(Deserialize)]
+struct ReleaseExtras<'a> {
+    #[serde(default)]
+    id: Option<u64>,
+    #[serde(default)]
+    size: Option<usize>,
+    #[serde(default, borrow)]
+    hash: Option<EscapedStr<'a>>,
+    #[serde(default, borrow, alias = "url")]
+    download_url: Option<EscapedStr<'a>>,
+    #[serde(default, borrow)]
+    created_at: Option<EscapedStr<'a>>,
+    #[serde(default, borrow)]
+    notes: Option<EscapedStr<'a>>,
+    #[serde(default, borrow)]
+    encoding: Option<EscapedStr<'a>>,
+}
+
+/// Strings with escapes in them can't be borrowed from the JSON.
+fn unescape(value: EscapedStr) -> Result<String> {
+    let mut unescaped = String::new();
+    for fragment in value.fragments() {
+        match fragment.map_err(|_| {
+            UpgradeError::SerdeError(serde_json_core::de::Error::InvalidEscapeSequence)
+        })? {
+            EscapedStringFragment::NotEscaped(fragment) => unescaped.push_str(fragment),
+            EscapedStringFragment::Escaped(c) => unescaped.push(c),
+        }
+    }
+    Ok(unescaped)
+}
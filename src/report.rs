@@ -0,0 +1,495 @@
+//! Update outcomes for the server, queued in flash while the device is offline.
+//!
+//! Events are appended to a small log in their own data partition, like
+//! [`crate::resume`] does with download progress. Delivered events are
+//! marked with a "sent" record rather than rewritten, so queueing and
+//! acknowledging never need an erase until the log fills up.
+//!
+//! The partition holds two logs, each starting with a header that numbers
+//! it. A full log is compacted into the other one, whose header is written
+//! last, so the old log stays in use until the new one is complete and a
+//! power cut halfway through doesn't lose any events.
+
+use crate::error::{Result, UpgradeError};
+use crate::flash::{log_len, record_stride, write_padded, BlockingAsync, LOG_SECTOR_SIZE};
+use crate::partition::{find_partition_by_name, PartitionTableBuffer};
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use crc::{Crc, CRC_32_ISO_HDLC};
+use embassy_futures::block_on;
+use embedded_storage::nor_flash::NorFlash;
+use embedded_storage_async::nor_flash::NorFlash as AsyncNorFlash;
+use esp_partition_table::PartitionEntry;
+use log::{debug, warn};
+use semver::Version;
+use serde::Serialize;
+
+/// Name of the (optional) data partition undelivered events are kept in.
+///
+/// Add something like `otareport, data, undefined, , 0x2000` to the
+/// partition table to queue events while offline.
+pub const REPORT_PARTITION_NAME: &str = "otareport";
+
+/// Logs in the partition, they take turns.
+const LOG_COUNT: usize = 2;
+const RECORD_SIZE: usize = 128;
+const RECORD_MAGIC: u32 = 0x5450_524F;
+/// Longest version string a queued event can hold.
+const MAX_VERSION_LEN: usize = 52;
+
+const EVENT_RECORD: u8 = 1;
+const SENT_RECORD: u8 = 2;
+const HEADER_RECORD: u8 = 3;
+
+const RECORD_CRC: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+
+/// What happened to an update.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    /// Written and selected for the next boot.
+    Installed = 1,
+    /// Booted and confirmed with [`crate::accept_fw`].
+    Accepted = 2,
+    /// Turned down with [`crate::reject_fw`].
+    Rejected = 3,
+    /// The bootloader went back to the previous firmware.
+    RolledBack = 4,
+    /// The update didn't make it, see the error code.
+    Failed = 5,
+}
+
+impl TryFrom<u8> for Outcome {
+    type Error = UpgradeError;
+    fn try_from(value: u8) -> Result<Self> {
+        match value {
+            1 => Ok(Self::Installed),
+            2 => Ok(Self::Accepted),
+            3 => Ok(Self::Rejected),
+            4 => Ok(Self::RolledBack),
+            5 => Ok(Self::Failed),
+            _ => Err(UpgradeError::InvalidState),
+        }
+    }
+}
+
+/// One update attempt, as reported to the server.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct UpdateEvent {
+    pub from_version: Version,
+    pub to_version: Version,
+    pub outcome: Outcome,
+    /// [`UpgradeError::code`] of what went wrong, if anything.
+    pub error_code: Option<u16>,
+}
+
+impl UpdateEvent {
+    pub fn new(from_version: Version, to_version: Version, outcome: Outcome) -> Self {
+        Self {
+            from_version,
+            to_version,
+            outcome,
+            error_code: None,
+        }
+    }
+
+    /// An update that failed with `error`.
+    pub fn failed(from_version: Version, to_version: Version, error: &UpgradeError) -> Self {
+        Self {
+            error_code: Some(error.code()),
+            ..Self::new(from_version, to_version, Outcome::Failed)
+        }
+    }
+
+    /// Body of the report sent for `device_id`.
+    pub fn to_json(&self, device_id: &str) -> Result<String> {
+        let body = EventBody {
+            device_id,
+            from_version: &self.from_version,
+            to_version: &self.to_version,
+            outcome: self.outcome,
+            error_code: self.error_code,
+        };
+        let mut buffer = [0; 512];
+        let len =
+            serde_json_core::to_slice(&body, &mut buffer).map_err(|_| UpgradeError::OutOfSpace)?;
+        Ok(core::str::from_utf8(&buffer[..len])?.to_string())
+    }
+}
+
+#[derive(Serialize)]
+struct EventBody<'a> {
+    device_id: &'a str,
+    from_version: &'a Version,
+    to_version: &'a Version,
+    outcome: Outcome,
+    error_code: Option<u16>,
+}
+
+/// An event waiting in flash to be delivered.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct QueuedEvent {
+    /// Position in the queue, pass it to [`mark_sent`] once delivered.
+    pub seq: u32,
+    pub event: UpdateEvent,
+}
+
+/// Keeps `event` until it's delivered. Does nothing if there is no report partition.
+///
+/// If the log is full of undelivered events, the oldest one is dropped.
+pub fn queue_event<S: NorFlash>(storage: &mut S, event: &UpdateEvent) -> Result<()> {
+    block_on(queue_event_async(&mut BlockingAsync::new(storage), event))
+}
+
+/// [`queue_event`] for async flash drivers.
+pub async fn queue_event_async<S: AsyncNorFlash>(
+    storage: &mut S,
+    event: &UpdateEvent,
+) -> Result<()> {
+    let Some(mut log) = ReportLog::open(storage).await? else {
+        warn!(
+            "no {} partition, dropping {:?}",
+            REPORT_PARTITION_NAME, event
+        );
+        return Ok(());
+    };
+    log.queue(storage, event).await
+}
+
+/// Events not delivered yet, oldest first.
+pub fn pending_events<S: NorFlash>(storage: &mut S) -> Result<Vec<QueuedEvent>> {
+    block_on(pending_events_async(&mut BlockingAsync::new(storage)))
+}
+
+/// [`pending_events`] for async flash drivers.
+pub async fn pending_events_async<S: AsyncNorFlash>(storage: &mut S) -> Result<Vec<QueuedEvent>> {
+    Ok(ReportLog::open(storage)
+        .await?
+        .map(|log| log.pending().to_vec())
+        .unwrap_or_default())
+}
+
+/// Marks the events up to and including `seq` as delivered.
+pub fn mark_sent<S: NorFlash>(storage: &mut S, seq: u32) -> Result<()> {
+    block_on(mark_sent_async(&mut BlockingAsync::new(storage), seq))
+}
+
+/// [`mark_sent`] for async flash drivers.
+pub async fn mark_sent_async<S: AsyncNorFlash>(storage: &mut S, seq: u32) -> Result<()> {
+    match ReportLog::open(storage).await? {
+        Some(mut log) => log.mark_sent(storage, seq).await,
+        None => Ok(()),
+    }
+}
+
+/// The report partition and what's in it, so a batch of operations (e.g.
+/// marking each delivered event) reads the partition table and scans the
+/// log only once.
+pub(crate) struct ReportLog {
+    partition: PartitionEntry,
+    log: Log,
+}
+
+impl ReportLog {
+    /// `None` if there is no report partition.
+    pub(crate) async fn open<S: AsyncNorFlash>(storage: &mut S) -> Result<Option<Self>> {
+        let Some(partition) = find_report_partition(storage).await? else {
+            return Ok(None);
+        };
+        let log = scan(storage, &partition).await?;
+        Ok(Some(Self { partition, log }))
+    }
+
+    pub(crate) fn pending(&self) -> &[QueuedEvent] {
+        &self.log.pending
+    }
+
+    pub(crate) async fn queue<S: AsyncNorFlash>(
+        &mut self,
+        storage: &mut S,
+        event: &UpdateEvent,
+    ) -> Result<()> {
+        let queued = QueuedEvent {
+            seq: self.log.last_seq + 1,
+            event: event.clone(),
+        };
+        let record = Record::Event(queued.clone()).to_bytes()?;
+        if self.append(storage, &record).await? {
+            self.log.last_seq = queued.seq;
+            self.log.pending.push(queued);
+            return Ok(());
+        }
+        let mut pending = core::mem::take(&mut self.log.pending);
+        pending.push(queued);
+        self.compact(storage, pending).await
+    }
+
+    pub(crate) async fn mark_sent<S: AsyncNorFlash>(
+        &mut self,
+        storage: &mut S,
+        seq: u32,
+    ) -> Result<()> {
+        if self.log.active.is_none() {
+            return Ok(());
+        }
+        let record = Record::Sent(seq).to_bytes()?;
+        if self.append(storage, &record).await? {
+            self.log.pending.retain(|queued| queued.seq > seq);
+            self.log.last_seq = self.log.last_seq.max(seq);
+            return Ok(());
+        }
+        let mut pending = core::mem::take(&mut self.log.pending);
+        pending.retain(|queued| queued.seq > seq);
+        self.compact(storage, pending).await
+    }
+
+    /// Writes `record` to the next free slot, false if the log is full.
+    async fn append<S: AsyncNorFlash>(
+        &mut self,
+        storage: &mut S,
+        record: &[u8; RECORD_SIZE],
+    ) -> Result<bool> {
+        let (Some((sector, _)), Some(slot)) = (self.log.active, self.log.free_slot) else {
+            return Ok(false);
+        };
+        write_record(storage, &self.partition, sector, slot, record).await?;
+        self.log.free_slot = Some(slot + 1).filter(|slot| *slot < slot_count::<S>());
+        Ok(true)
+    }
+
+    async fn compact<S: AsyncNorFlash>(
+        &mut self,
+        storage: &mut S,
+        pending: Vec<QueuedEvent>,
+    ) -> Result<()> {
+        compact(storage, &self.partition, self.log.active, pending).await?;
+        self.log = scan(storage, &self.partition).await?;
+        Ok(())
+    }
+}
+
+async fn find_report_partition<S: AsyncNorFlash>(
+    storage: &mut S,
+) -> Result<Option<PartitionEntry>> {
+    let mut table = PartitionTableBuffer::read(storage).await?;
+    match find_partition_by_name(&mut table, REPORT_PARTITION_NAME) {
+        Ok(partition) if partition.size >= LOG_COUNT * log_len::<S>() => Ok(Some(partition)),
+        Ok(partition) => {
+            warn!(
+                "{} partition is {:#x} bytes but the log needs {:#x}, events are not queued",
+                REPORT_PARTITION_NAME,
+                partition.size,
+                log_len::<S>()
+            );
+            Ok(None)
+        }
+        Err(UpgradeError::PartitionNotFound) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Slots in each log, the first one holds its header.
+fn slot_count<S: AsyncNorFlash>() -> usize {
+    LOG_SECTOR_SIZE / record_stride::<S>(RECORD_SIZE)
+}
+
+fn slot_offset<S: AsyncNorFlash>(partition: &PartitionEntry, sector: usize, slot: usize) -> u32 {
+    partition.offset + (sector * log_len::<S>() + slot * record_stride::<S>(RECORD_SIZE)) as u32
+}
+
+async fn write_record<S: AsyncNorFlash>(
+    storage: &mut S,
+    partition: &PartitionEntry,
+    sector: usize,
+    slot: usize,
+    record: &[u8; RECORD_SIZE],
+) -> Result<()> {
+    write_padded(storage, slot_offset::<S>(partition, sector, slot), record).await
+}
+
+async fn read_record<S: AsyncNorFlash>(
+    storage: &mut S,
+    partition: &PartitionEntry,
+    sector: usize,
+    slot: usize,
+) -> Result<[u8; RECORD_SIZE]> {
+    let mut buffer = [0; RECORD_SIZE];
+    storage
+        .read(slot_offset::<S>(partition, sector, slot), &mut buffer)
+        .await
+        .map_err(|_| UpgradeError::StorageError)?;
+    Ok(buffer)
+}
+
+/// Starts a new log after the `active` one with just the `pending` events,
+/// as many as fit. The header goes in last, until then the `active` log is
+/// the one that counts.
+async fn compact<S: AsyncNorFlash>(
+    storage: &mut S,
+    partition: &PartitionEntry,
+    active: Option<(usize, u32)>,
+    mut pending: Vec<QueuedEvent>,
+) -> Result<()> {
+    let capacity = slot_count::<S>() - 1;
+    if pending.len() > capacity {
+        let dropped = pending.len() - capacity;
+        warn!("report log full, dropping {} undelivered events", dropped);
+        pending.drain(..dropped);
+    }
+    let (sector, generation) = match active {
+        Some((sector, generation)) => ((sector + 1) % LOG_COUNT, generation.wrapping_add(1)),
+        None => (0, 1),
+    };
+    debug!(
+        "compacting report log to {} events in sector {}",
+        pending.len(),
+        sector
+    );
+    let start = slot_offset::<S>(partition, sector, 0);
+    storage
+        .erase(start, start + log_len::<S>() as u32)
+        .await
+        .map_err(|_| UpgradeError::StorageError)?;
+    for (slot, queued) in pending.into_iter().enumerate() {
+        let record = Record::Event(queued).to_bytes()?;
+        write_record(storage, partition, sector, slot + 1, &record).await?;
+    }
+    let header = Record::Header(generation).to_bytes()?;
+    write_record(storage, partition, sector, 0, &header).await
+}
+
+struct Log {
+    /// Sector and generation of the log in use, `None` before the first event.
+    active: Option<(usize, u32)>,
+    pending: Vec<QueuedEvent>,
+    last_seq: u32,
+    /// Next slot of the active log, `None` if it's full.
+    free_slot: Option<usize>,
+}
+
+async fn scan<S: AsyncNorFlash>(storage: &mut S, partition: &PartitionEntry) -> Result<Log> {
+    let mut active: Option<(usize, u32)> = None;
+    for sector in 0..LOG_COUNT {
+        let header = read_record(storage, partition, sector, 0).await?;
+        if let Ok(Record::Header(generation)) = Record::try_from(header) {
+            if active.is_none_or(|(_, newest)| generation > newest) {
+                active = Some((sector, generation));
+            }
+        }
+    }
+
+    let mut events = Vec::new();
+    let mut sent = 0;
+    let mut last_seq = 0;
+    let mut free_slot = None;
+    if let Some((sector, _)) = active {
+        for slot in 1..slot_count::<S>() {
+            let buffer = read_record(storage, partition, sector, slot).await?;
+            if buffer.iter().all(|b| *b == 0xFF) {
+                free_slot = Some(slot);
+                break;
+            }
+            // A record torn by a power cut is skipped
+            match Record::try_from(buffer) {
+                Ok(Record::Event(queued)) => {
+                    last_seq = last_seq.max(queued.seq);
+                    events.push(queued);
+                }
+                Ok(Record::Sent(seq)) => sent = sent.max(seq),
+                Ok(Record::Header(_)) | Err(_) => {}
+            }
+        }
+    }
+    events.retain(|queued| queued.seq > sent);
+    Ok(Log {
+        active,
+        pending: events,
+        last_seq: last_seq.max(sent),
+        free_slot,
+    })
+}
+
+enum Record {
+    Event(QueuedEvent),
+    /// Everything up to this seq was delivered.
+    Sent(u32),
+    /// Start of a log, the highest generation is the one in use.
+    Header(u32),
+}
+
+impl Record {
+    fn to_bytes(&self) -> Result<[u8; RECORD_SIZE]> {
+        let mut ret = [0xFF; RECORD_SIZE];
+        ret[0..4].copy_from_slice(&RECORD_MAGIC.to_le_bytes());
+        match self {
+            Record::Event(queued) => {
+                ret[4] = EVENT_RECORD;
+                ret[5] = queued.event.outcome as u8;
+                ret[6..8].copy_from_slice(&queued.event.error_code.unwrap_or(0).to_le_bytes());
+                ret[8..12].copy_from_slice(&queued.seq.to_le_bytes());
+                write_version(&mut ret[12..64], &queued.event.from_version)?;
+                write_version(&mut ret[64..116], &queued.event.to_version)?;
+            }
+            Record::Sent(seq) => {
+                ret[4] = SENT_RECORD;
+                ret[8..12].copy_from_slice(&seq.to_le_bytes());
+            }
+            Record::Header(generation) => {
+                ret[4] = HEADER_RECORD;
+                ret[8..12].copy_from_slice(&generation.to_le_bytes());
+            }
+        }
+        let crc = RECORD_CRC.checksum(&ret[0..124]);
+        ret[124..128].copy_from_slice(&crc.to_le_bytes());
+        Ok(ret)
+    }
+}
+
+impl TryFrom<[u8; RECORD_SIZE]> for Record {
+    type Error = UpgradeError;
+    fn try_from(value: [u8; RECORD_SIZE]) -> Result<Self> {
+        let magic = u32::from_le_bytes(value[0..4].try_into().unwrap());
+        let crc = u32::from_le_bytes(value[124..128].try_into().unwrap());
+        if magic != RECORD_MAGIC || crc != RECORD_CRC.checksum(&value[0..124]) {
+            return Err(UpgradeError::InvalidCrc);
+        }
+        let seq = u32::from_le_bytes(value[8..12].try_into().unwrap());
+        match value[4] {
+            EVENT_RECORD => {
+                let error_code = u16::from_le_bytes(value[6..8].try_into().unwrap());
+                Ok(Record::Event(QueuedEvent {
+                    seq,
+                    event: UpdateEvent {
+                        from_version: read_version(&value[12..64])?,
+                        to_version: read_version(&value[64..116])?,
+                        outcome: Outcome::try_from(value[5])?,
+                        error_code: (error_code != 0).then_some(error_code),
+                    },
+                }))
+            }
+            SENT_RECORD => Ok(Record::Sent(seq)),
+            HEADER_RECORD => Ok(Record::Header(seq)),
+            _ => Err(UpgradeError::InvalidState),
+        }
+    }
+}
+
+/// Versions are stored as NUL padded strings.
+fn write_version(field: &mut [u8], version: &Version) -> Result<()> {
+    let version = version.to_string();
+    if version.len() > MAX_VERSION_LEN {
+        return Err(UpgradeError::VersionError(format!(
+            "{} is too long to queue",
+            version
+        )));
+    }
+    field.fill(0);
+    field[..version.len()].copy_from_slice(version.as_bytes());
+    Ok(())
+}
+
+fn read_version(field: &[u8]) -> Result<Version> {
+    let len = field.iter().position(|b| *b == 0).unwrap_or(field.len());
+    Ok(Version::parse(core::str::from_utf8(&field[..len])?)?)
+}
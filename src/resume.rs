@@ -0,0 +1,305 @@
+use crate::error::{Result, UpgradeError};
+use crate::flash::{log_len, record_stride, write_padded, BlockingAsync, LOG_SECTOR_SIZE};
+use crate::partition::{find_partition_by_name, next_slot, OtaPartitions, PartitionTableBuffer};
+use crate::upgrade_data::UpgradeInfo;
+use crc::{Crc, CRC_32_ISO_HDLC};
+use embassy_futures::block_on;
+use embedded_storage::nor_flash::NorFlash;
+use embedded_storage_async::nor_flash::NorFlash as AsyncNorFlash;
+use esp_partition_table::PartitionEntry;
+use log::{debug, warn};
+
+/// Name of the (optional) data partition download progress is kept in.
+///
+/// Add something like `otaresume, data, undefined, , 0x1000` to the
+/// partition table to enable resumable downloads.
+pub const RESUME_PARTITION_NAME: &str = "otaresume";
+
+const RECORD_SIZE: usize = 32;
+const RECORD_MAGIC: u32 = 0x4F54_4152;
+
+const RECORD_CRC: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+
+/// Progress of a partially downloaded firmware.
+///
+/// Records are appended to the resume partition one after the other so
+/// updating the progress doesn't need an erase. The last valid record wins,
+/// a record torn by a power cut is simply skipped.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct ResumeInfo {
+    /// otadata seq at the time of the download. A different seq means the
+    /// inactive partition moved.
+    pub seq: u32,
+    /// OTA slot the image is written to.
+    pub slot: u8,
+    /// CRC32 of the release identity (e.g. the version string).
+    pub release_crc: u32,
+    /// Bytes of the image already written to the inactive partition.
+    pub committed: u32,
+}
+
+impl ResumeInfo {
+    pub fn new(seq: u32, slot: u8, release: &str, committed: u32) -> Self {
+        Self {
+            seq,
+            slot,
+            release_crc: RECORD_CRC.checksum(release.as_bytes()),
+            committed,
+        }
+    }
+
+    pub fn matches(&self, seq: u32, slot: u8, release: &str) -> bool {
+        self.seq == seq
+            && self.slot == slot
+            && self.release_crc == RECORD_CRC.checksum(release.as_bytes())
+    }
+
+    /// Latest persisted progress, `None` if there is none or the resume
+    /// partition doesn't exist.
+    pub fn from_flash<S: NorFlash>(storage: &mut S) -> Result<Option<Self>> {
+        block_on(Self::from_flash_async(&mut BlockingAsync::new(storage)))
+    }
+
+    /// [`ResumeInfo::from_flash`] for async flash drivers.
+    pub async fn from_flash_async<S: AsyncNorFlash>(storage: &mut S) -> Result<Option<Self>> {
+        match ResumeLog::find(storage).await? {
+            Some(log) => log.latest(storage).await,
+            None => Ok(None),
+        }
+    }
+
+    pub fn save_to_flash<S: NorFlash>(&self, storage: &mut S) -> Result<()> {
+        block_on(self.save_to_flash_async(&mut BlockingAsync::new(storage)))
+    }
+
+    /// [`ResumeInfo::save_to_flash`] for async flash drivers.
+    pub async fn save_to_flash_async<S: AsyncNorFlash>(&self, storage: &mut S) -> Result<()> {
+        match ResumeLog::find(storage).await? {
+            Some(log) => log.save(storage, self).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Forget any persisted progress.
+    pub fn clear<S: NorFlash>(storage: &mut S) -> Result<()> {
+        block_on(Self::clear_async(&mut BlockingAsync::new(storage)))
+    }
+
+    /// [`ResumeInfo::clear`] for async flash drivers.
+    pub async fn clear_async<S: AsyncNorFlash>(storage: &mut S) -> Result<()> {
+        match ResumeLog::find(storage).await? {
+            Some(log) => log.clear(storage).await,
+            None => Ok(()),
+        }
+    }
+}
+
+/// The resume partition, looked up once for a whole download.
+pub(crate) struct ResumeLog {
+    partition: PartitionEntry,
+}
+
+impl ResumeLog {
+    /// `None` if there is no usable resume partition.
+    pub(crate) async fn find<S: AsyncNorFlash>(storage: &mut S) -> Result<Option<Self>> {
+        let mut table = PartitionTableBuffer::read(storage).await?;
+        match find_partition_by_name(&mut table, RESUME_PARTITION_NAME) {
+            Ok(partition) => Ok(Self::new::<S>(partition)),
+            Err(UpgradeError::PartitionNotFound) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The resume partition of a download, if it has a usable one.
+    pub(crate) fn from_partitions<S: AsyncNorFlash>(partitions: &OtaPartitions) -> Option<Self> {
+        partitions.resume.clone().and_then(Self::new::<S>)
+    }
+
+    /// `None` if `partition` is too small for the log.
+    pub(crate) fn new<S: AsyncNorFlash>(partition: PartitionEntry) -> Option<Self> {
+        if partition.size < log_len::<S>() {
+            warn!(
+                "{} partition is {:#x} bytes but the log needs {:#x}, resumable downloads are disabled",
+                RESUME_PARTITION_NAME,
+                partition.size,
+                log_len::<S>()
+            );
+            return None;
+        }
+        Some(Self { partition })
+    }
+
+    pub(crate) async fn latest<S: AsyncNorFlash>(
+        &self,
+        storage: &mut S,
+    ) -> Result<Option<ResumeInfo>> {
+        let (latest, _) = scan(storage, &self.partition).await?;
+        Ok(latest)
+    }
+
+    pub(crate) async fn save<S: AsyncNorFlash>(
+        &self,
+        storage: &mut S,
+        info: &ResumeInfo,
+    ) -> Result<()> {
+        let (_, free_slot) = scan(storage, &self.partition).await?;
+        let slot = match free_slot {
+            Some(slot) => slot,
+            None => {
+                debug!("resume log full, starting over");
+                erase_log(storage, &self.partition).await?;
+                0
+            }
+        };
+
+        let buffer: [u8; RECORD_SIZE] = (*info).into();
+        write_padded(
+            storage,
+            self.partition.offset + (slot * record_stride::<S>(RECORD_SIZE)) as u32,
+            &buffer,
+        )
+        .await
+    }
+
+    pub(crate) async fn clear<S: AsyncNorFlash>(&self, storage: &mut S) -> Result<()> {
+        let mut buffer = [0; RECORD_SIZE];
+        storage
+            .read(self.partition.offset, &mut buffer)
+            .await
+            .map_err(|_| UpgradeError::StorageError)?;
+        if buffer.iter().all(|b| *b == 0xFF) {
+            return Ok(());
+        }
+        erase_log(storage, &self.partition).await
+    }
+}
+
+/// Bytes of `release` that can be skipped because a previous download of it
+/// to the slot after the running one was interrupted, 0 if it has to start
+/// from the beginning.
+pub fn resume_offset<S: NorFlash>(storage: &mut S, release: &str) -> Result<u32> {
+    resume_offset_for_slot(storage, release, None)
+}
+
+/// [`resume_offset`] for async flash drivers.
+pub async fn resume_offset_async<S: AsyncNorFlash>(storage: &mut S, release: &str) -> Result<u32> {
+    resume_offset_for_slot_async(storage, release, None).await
+}
+
+/// [`resume_offset`] for a download to `target_slot`, see `SaveOptions::target_slot`.
+pub fn resume_offset_for_slot<S: NorFlash>(
+    storage: &mut S,
+    release: &str,
+    target_slot: Option<u8>,
+) -> Result<u32> {
+    block_on(resume_offset_for_slot_async(
+        &mut BlockingAsync::new(storage),
+        release,
+        target_slot,
+    ))
+}
+
+/// [`resume_offset_for_slot`] for async flash drivers.
+pub async fn resume_offset_for_slot_async<S: AsyncNorFlash>(
+    storage: &mut S,
+    release: &str,
+    target_slot: Option<u8>,
+) -> Result<u32> {
+    let partitions = OtaPartitions::read(storage).await?;
+    resume_offset_in(storage, &partitions, release, target_slot).await
+}
+
+/// [`resume_offset_for_slot_async`] with the partitions already looked up.
+pub(crate) async fn resume_offset_in<S: AsyncNorFlash>(
+    storage: &mut S,
+    partitions: &OtaPartitions,
+    release: &str,
+    target_slot: Option<u8>,
+) -> Result<u32> {
+    let upgrade_info = UpgradeInfo::from_partition_async(storage, partitions.otadata()?).await?;
+    let slot = match target_slot {
+        Some(slot) => slot,
+        None => next_slot(upgrade_info.seq, partitions.slot_count()?),
+    };
+    let Some(log) = ResumeLog::from_partitions::<S>(partitions) else {
+        return Ok(0);
+    };
+    committed_len(storage, &log, upgrade_info.seq, slot, release).await
+}
+
+/// Persisted progress of `release` going to `slot` while running `seq`.
+pub(crate) async fn committed_len<S: AsyncNorFlash>(
+    storage: &mut S,
+    log: &ResumeLog,
+    seq: u32,
+    slot: u8,
+    release: &str,
+) -> Result<u32> {
+    match log.latest(storage).await? {
+        Some(resume_info) if resume_info.matches(seq, slot, release) => Ok(resume_info.committed),
+        _ => Ok(0),
+    }
+}
+
+async fn erase_log<S: AsyncNorFlash>(storage: &mut S, partition: &PartitionEntry) -> Result<()> {
+    storage
+        .erase(partition.offset, partition.offset + log_len::<S>() as u32)
+        .await
+        .map_err(|_| UpgradeError::StorageError)
+}
+
+/// Returns the last valid record and the first free slot after it.
+async fn scan<S: AsyncNorFlash>(
+    storage: &mut S,
+    partition: &PartitionEntry,
+) -> Result<(Option<ResumeInfo>, Option<usize>)> {
+    let mut latest = None;
+    let mut buffer = [0; RECORD_SIZE];
+    for slot in 0..LOG_SECTOR_SIZE / record_stride::<S>(RECORD_SIZE) {
+        storage
+            .read(
+                partition.offset + (slot * record_stride::<S>(RECORD_SIZE)) as u32,
+                &mut buffer,
+            )
+            .await
+            .map_err(|_| UpgradeError::StorageError)?;
+        if buffer.iter().all(|b| *b == 0xFF) {
+            return Ok((latest, Some(slot)));
+        }
+        if let Ok(record) = ResumeInfo::try_from(buffer) {
+            latest = Some(record);
+        }
+    }
+    Ok((latest, None))
+}
+
+impl TryFrom<[u8; RECORD_SIZE]> for ResumeInfo {
+    type Error = UpgradeError;
+    fn try_from(value: [u8; RECORD_SIZE]) -> Result<Self> {
+        let magic = u32::from_le_bytes(value[0..4].try_into().unwrap());
+        let crc = u32::from_le_bytes(value[28..32].try_into().unwrap());
+        if magic != RECORD_MAGIC || crc != RECORD_CRC.checksum(&value[0..28]) {
+            return Err(UpgradeError::InvalidCrc);
+        }
+        Ok(Self {
+            seq: u32::from_le_bytes(value[4..8].try_into().unwrap()),
+            slot: value[16],
+            release_crc: u32::from_le_bytes(value[8..12].try_into().unwrap()),
+            committed: u32::from_le_bytes(value[12..16].try_into().unwrap()),
+        })
+    }
+}
+
+impl From<ResumeInfo> for [u8; RECORD_SIZE] {
+    fn from(value: ResumeInfo) -> Self {
+        let mut ret = [0xFF; RECORD_SIZE];
+        ret[0..4].copy_from_slice(&RECORD_MAGIC.to_le_bytes());
+        ret[4..8].copy_from_slice(&value.seq.to_le_bytes());
+        ret[8..12].copy_from_slice(&value.release_crc.to_le_bytes());
+        ret[12..16].copy_from_slice(&value.committed.to_le_bytes());
+        ret[16] = value.slot;
+        let crc = RECORD_CRC.checksum(&ret[0..28]);
+        ret[28..32].copy_from_slice(&crc.to_le_bytes());
+        ret
+    }
+}
@@ -0,0 +1,154 @@
+//! Retries and timeouts for requests to the server.
+//!
+//! The crate doesn't pick a timer, the application injects one through
+//! [`Delay`], e.g. with embassy-time:
+//!
+//! ```ignore
+//! #[derive(Clone)]
+//! struct EmbassyDelay;
+//!
+//! impl Delay for EmbassyDelay {
+//!     async fn delay_ms(&self, ms: u32) {
+//!         embassy_time::Timer::after_millis(ms.into()).await
+//!     }
+//! }
+//! ```
+
+use crate::error::{Result, UpgradeError};
+use core::future::Future;
+use embassy_futures::select::{select, Either};
+use embedded_io_async::{ErrorKind, ErrorType, Read};
+use log::error;
+
+/// Waits, so the client can back off and time out.
+pub trait Delay {
+    fn delay_ms(&self, ms: u32) -> impl Future<Output = ()>;
+}
+
+/// Timer of clients without a [`RetryPolicy`], never fires. Those only
+/// use [`RetryPolicy::NONE`], which doesn't wait for anything.
+///
+/// Only the crate can make one, so it can't be passed to
+/// [`crate::BotifactoryClient::with_retry`].
+#[derive(Clone, Copy, Debug)]
+pub struct NeverFires(());
+
+impl NeverFires {
+    pub(crate) const fn new() -> Self {
+        Self(())
+    }
+}
+
+impl Delay for NeverFires {
+    async fn delay_ms(&self, _ms: u32) {
+        core::future::pending().await
+    }
+}
+
+/// When and how often failed requests are tried again.
+///
+/// Only transient failures are retried (see [`UpgradeError::is_transient`]),
+/// a 4xx response or a bad checksum won't get any better. Interrupted
+/// downloads pick up where they left off if the release is set in
+/// `SaveOptions::release`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RetryPolicy {
+    /// Tries per request, including the first one.
+    pub max_attempts: u32,
+    /// Wait before the first retry, doubled for each one after it.
+    pub initial_backoff_ms: u32,
+    pub max_backoff_ms: u32,
+    /// Randomizes the wait so a fleet that lost the server at the same time
+    /// doesn't come back at the same time. Take it from the hardware RNG.
+    pub jitter_seed: u32,
+    /// How long to wait for the response headers.
+    pub request_timeout_ms: Option<u32>,
+    /// How long the response body may stall.
+    pub idle_timeout_ms: Option<u32>,
+}
+
+impl RetryPolicy {
+    /// One attempt, no timeouts, how the client behaves by default.
+    pub const NONE: Self = Self {
+        max_attempts: 1,
+        initial_backoff_ms: 0,
+        max_backoff_ms: 0,
+        jitter_seed: 0,
+        request_timeout_ms: None,
+        idle_timeout_ms: None,
+    };
+
+    /// Wait before retry number `retry` (starting at 1), somewhere between
+    /// half and all of the exponential backoff depending on `random`.
+    pub fn backoff_ms(&self, retry: u32, random: u32) -> u32 {
+        let backoff = self
+            .initial_backoff_ms
+            .saturating_mul(1 << retry.saturating_sub(1).min(31))
+            .min(self.max_backoff_ms);
+        backoff / 2 + random % (backoff / 2 + 1)
+    }
+}
+
+impl Default for RetryPolicy {
+    /// Three attempts, 1 s then 2 s apart, 30 s timeouts.
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_backoff_ms: 1000,
+            max_backoff_ms: 30_000,
+            jitter_seed: 0,
+            request_timeout_ms: Some(30_000),
+            idle_timeout_ms: Some(30_000),
+        }
+    }
+}
+
+/// xorshift32, plenty for spreading out retries.
+pub(crate) fn next_random(state: &mut u32) -> u32 {
+    // 0 would stay 0
+    let mut x = if *state == 0 { 0x9E37_79B9 } else { *state };
+    x ^= x << 13;
+    x ^= x >> 17;
+    x ^= x << 5;
+    *state = x;
+    x
+}
+
+/// Fails with [`UpgradeError::Timeout`] if `future` takes longer than `timeout_ms`.
+pub(crate) async fn with_timeout<W: Delay, F: Future>(
+    delay: &W,
+    timeout_ms: Option<u32>,
+    future: F,
+) -> Result<F::Output> {
+    let Some(timeout_ms) = timeout_ms else {
+        return Ok(future.await);
+    };
+    match select(future, delay.delay_ms(timeout_ms)).await {
+        Either::First(output) => Ok(output),
+        Either::Second(()) => {
+            error!("timed out after {} ms", timeout_ms);
+            Err(UpgradeError::Timeout)
+        }
+    }
+}
+
+/// [`Read`] adapter that fails if `R` doesn't produce anything for `timeout_ms`.
+pub(crate) struct IdleTimeout<'d, R, W> {
+    pub reader: R,
+    pub delay: &'d W,
+    pub timeout_ms: Option<u32>,
+}
+
+impl<R: Read, W> ErrorType for IdleTimeout<'_, R, W> {
+    type Error = ErrorKind;
+}
+
+impl<R: Read, W: Delay> Read for IdleTimeout<'_, R, W> {
+    async fn read(&mut self, buf: &mut [u8]) -> core::result::Result<usize, Self::Error> {
+        use embedded_io_async::Error;
+        match with_timeout(self.delay, self.timeout_ms, self.reader.read(buf)).await {
+            Ok(result) => result.map_err(|error| error.kind()),
+            Err(_) => Err(ErrorKind::TimedOut),
+        }
+    }
+}
@@ -0,0 +1,48 @@
+//! Ed25519 signed firmware. The keys and signatures can always be passed
+//! around, checking them needs the `ed25519` feature.
+
+use crate::checksum::decode_hex;
+#[cfg(feature = "ed25519")]
+use crate::checksum::Sha256Digest;
+#[cfg(feature = "ed25519")]
+use crate::error::{Result, UpgradeError};
+#[cfg(feature = "ed25519")]
+use ed25519_dalek::{Signature, VerifyingKey};
+#[cfg(feature = "ed25519")]
+use log::{debug, error};
+
+/// Raw Ed25519 public key, e.g. `*include_bytes!("firmware_signing.pub")`.
+pub type Ed25519PublicKey = [u8; 32];
+/// Raw detached Ed25519 signature.
+pub type Ed25519Signature = [u8; 64];
+
+/// Checks `signature` against the image digest with each trusted key.
+///
+/// The build pipeline signs the 32 byte SHA-256 digest of the image rather
+/// than the image itself, that way the image can be verified while it
+/// streams into flash.
+#[cfg(feature = "ed25519")]
+pub fn verify_signature(
+    digest: &Sha256Digest,
+    signature: &Ed25519Signature,
+    trusted_keys: &[Ed25519PublicKey],
+) -> Result<()> {
+    let signature = Signature::from_bytes(signature);
+    for key in trusted_keys {
+        let Ok(key) = VerifyingKey::from_bytes(key) else {
+            error!("ignoring malformed public key {:02x?}", key);
+            continue;
+        };
+        if key.verify_strict(digest, &signature).is_ok() {
+            debug!("signature verified");
+            return Ok(());
+        }
+    }
+    error!("no trusted key matches the firmware signature");
+    Err(UpgradeError::InvalidSignature)
+}
+
+/// Parses a hex encoded signature as sent in the `X-Signature` header.
+pub fn parse_signature(value: &str) -> Option<Ed25519Signature> {
+    decode_hex(value.trim())
+}
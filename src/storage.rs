@@ -1,35 +1,259 @@
+use crate::checksum::Sha256Digest;
+use crate::decompress::{Decompressor, Encoding};
+#[cfg(feature = "encryption")]
+use crate::encryption::Decryptor;
+use crate::encryption::FirmwareKey;
 use crate::error::{Result, UpgradeError};
-use crate::partition::find_inactive_partition;
-use crate::partition::find_running_partition;
+use crate::flash::{check_aligned, BlockingAsync};
+use crate::image::{ChipId, ImageValidator};
+use crate::partition::{next_slot, running_slot, seq_for_slot, OtaPartitions};
+use crate::progress::{Phase, ProgressObserver, ProgressReporter};
+use crate::resume::{committed_len, ResumeInfo, ResumeLog};
+#[cfg(feature = "ed25519")]
+use crate::signature::verify_signature;
+use crate::signature::{Ed25519PublicKey, Ed25519Signature};
 use crate::upgrade_data::{AppOTAState, UpgradeInfo};
 use core::sync::atomic::Ordering;
+use embassy_futures::block_on;
 use embedded_io_async::Read;
 use embedded_storage::nor_flash::NorFlash;
+use embedded_storage_async::nor_flash::NorFlash as AsyncNorFlash;
 use log::{debug, error, info, warn};
 use portable_atomic::AtomicBool;
+use sha2::{Digest, Sha256};
 
-/// Size of a flash sector
+/// Size of the chunks the image is streamed into flash in
 const SECTOR_SIZE: usize = 4096;
 
 static IS_SAVING: AtomicBool = AtomicBool::new(false);
 
+/// When the target partition gets erased.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub enum EraseMode {
+    /// Erase each block right before it's written, and only as far as the
+    /// image goes. Keeps the connection busy and spares unused sectors.
+    #[default]
+    Lazy,
+    /// Erase the whole partition before the download starts. For flash that
+    /// can't be erased in small steps, or where that's much slower.
+    Full,
+}
+
+/// Optional behaviour for [`save_new_fw_with_options`].
+#[derive(Default)]
+pub struct SaveOptions<'a> {
+    /// Identity of the release being written (e.g. its version).
+    /// When set, progress is persisted after every sector so an interrupted
+    /// download can be picked up again. See [`crate::resume`].
+    pub release: Option<&'a str>,
+    /// Offset into the image the reader starts at.
+    /// Has to be 0 or the offset returned by [`crate::resume::resume_offset`] for `release`.
+    pub resume_from: u32,
+    /// SHA-256 the whole image has to hash to. The new firmware is only
+    /// selected for boot if it matches.
+    pub expected_sha256: Option<Sha256Digest>,
+    /// Chip the image has to be built for. Any chip is accepted if `None`,
+    /// the rest of the image is validated either way.
+    pub chip_id: Option<ChipId>,
+    /// Total size of the image if known, e.g. from `Content-Length` or
+    /// [`crate::Release::size`]. An image that can't fit the slot is refused
+    /// before anything is erased, and the transfer has to deliver exactly
+    /// this many bytes.
+    pub image_len: Option<usize>,
+    /// How the payload is compressed, it's decompressed on the way to flash.
+    /// Compressed downloads can't be resumed.
+    pub encoding: Encoding,
+    /// OTA slot to write the image to, instead of the one after the running
+    /// slot. Lets a known-good image stay in another slot. Resuming a download
+    /// has to target the same slot as the interrupted attempt.
+    pub target_slot: Option<u8>,
+    /// See [`EraseMode`].
+    pub erase_mode: EraseMode,
+    /// Keys the payload may be encrypted with. When not empty, the payload
+    /// has to be encrypted with one of them, see [`crate::encryption`].
+    /// Encrypted downloads can't be resumed. Without the `encryption`
+    /// feature such an update fails with [`UpgradeError::UnsupportedEncoding`].
+    pub decryption_keys: &'a [FirmwareKey],
+    /// Gets told how the update is going, see [`crate::progress`].
+    pub progress: Option<&'a mut dyn ProgressObserver>,
+    /// Keys trusted to sign firmware. When not empty, the image is only
+    /// selected for boot with a valid `signature` from one of them. Without
+    /// the `ed25519` feature such an update fails with
+    /// [`UpgradeError::InvalidSignature`] before anything is erased.
+    pub trusted_keys: &'a [Ed25519PublicKey],
+    /// Detached Ed25519 signature over the SHA-256 of the image.
+    pub signature: Option<Ed25519Signature>,
+}
+
+impl SaveOptions<'_> {
+    /// Whether the payload is compressed or encrypted rather than the plain image.
+    pub(crate) fn is_transformed(&self) -> bool {
+        !self.decryption_keys.is_empty() || self.encoding != Encoding::Identity
+    }
+
+    /// Fails for options this build can't honour, before anything is
+    /// downloaded or erased.
+    pub(crate) fn check_features(&self) -> Result<()> {
+        #[cfg(not(feature = "encryption"))]
+        if !self.decryption_keys.is_empty() {
+            error!("decryption keys given, but built without the encryption feature");
+            return Err(UpgradeError::UnsupportedEncoding);
+        }
+        #[cfg(not(feature = "ed25519"))]
+        if !self.trusted_keys.is_empty() {
+            error!("trusted keys given, but built without the ed25519 feature");
+            return Err(UpgradeError::InvalidSignature);
+        }
+        Ok(())
+    }
+
+    /// The same options again, e.g. for another attempt at a download.
+    pub(crate) fn reborrow(&mut self) -> SaveOptions<'_> {
+        SaveOptions {
+            release: self.release,
+            resume_from: self.resume_from,
+            expected_sha256: self.expected_sha256,
+            chip_id: self.chip_id,
+            image_len: self.image_len,
+            encoding: self.encoding,
+            target_slot: self.target_slot,
+            erase_mode: self.erase_mode,
+            decryption_keys: self.decryption_keys,
+            progress: match &mut self.progress {
+                Some(progress) => Some(&mut **progress),
+                None => None,
+            },
+            trusted_keys: self.trusted_keys,
+            signature: self.signature,
+        }
+    }
+}
+
 pub async fn save_new_fw<S: NorFlash, R: Read>(storage: &mut S, binary_reader: R) -> Result<()> {
-    if IS_SAVING.swap(true, Ordering::SeqCst) {
-        info!("download already in progress");
-        return Err(UpgradeError::DLInProgress);
+    save_new_fw_with_options(storage, binary_reader, SaveOptions::default()).await
+}
+
+pub async fn save_new_fw_with_options<S: NorFlash, R: Read>(
+    storage: &mut S,
+    binary_reader: R,
+    options: SaveOptions<'_>,
+) -> Result<()> {
+    save_new_fw_with_options_async(&mut BlockingAsync::new(storage), binary_reader, options).await
+}
+
+/// [`save_new_fw`] for async flash drivers, so erases and writes don't
+/// block the executor.
+pub async fn save_new_fw_async<S: AsyncNorFlash, R: Read>(
+    storage: &mut S,
+    binary_reader: R,
+) -> Result<()> {
+    save_new_fw_with_options_async(storage, binary_reader, SaveOptions::default()).await
+}
+
+/// [`save_new_fw_with_options`] for async flash drivers.
+pub async fn save_new_fw_with_options_async<S: AsyncNorFlash, R: Read>(
+    storage: &mut S,
+    binary_reader: R,
+    options: SaveOptions<'_>,
+) -> Result<()> {
+    let session = SaveSession::start(storage).await?;
+    save_new_fw_in_session(storage, binary_reader, options, session).await
+}
+
+/// [`save_new_fw_with_options_async`] in a session the caller already
+/// started, e.g. to work out the resume offset first.
+pub(crate) async fn save_new_fw_in_session<S: AsyncNorFlash, R: Read>(
+    storage: &mut S,
+    binary_reader: R,
+    mut options: SaveOptions<'_>,
+    session: SaveSession,
+) -> Result<()> {
+    options.check_features()?;
+    if options.is_transformed() {
+        if options.resume_from != 0 {
+            error!("compressed or encrypted downloads can't be resumed");
+            return Err(UpgradeError::InvalidState);
+        }
+        // Progress is counted in image bytes, which don't line up with the payload
+        options.release = None;
+    }
+    #[cfg(feature = "encryption")]
+    let binary_reader = Decryptor::new(binary_reader, options.decryption_keys).await?;
+    let source = Decompressor::new(binary_reader, options.encoding)?;
+    save_image(storage, source, options, session).await
+}
+
+/// A running download: only one may write to flash at a time. Start it
+/// before reading any of the OTA state the download depends on, it holds
+/// the partitions looked up for it.
+pub(crate) struct SaveSession {
+    _saving: SavingGuard,
+    pub(crate) partitions: OtaPartitions,
+}
+
+impl SaveSession {
+    pub(crate) async fn start<S: AsyncNorFlash>(storage: &mut S) -> Result<Self> {
+        let saving = SavingGuard::acquire()?;
+        Ok(Self {
+            _saving: saving,
+            partitions: OtaPartitions::read(storage).await?,
+        })
+    }
+}
+
+/// Clears the running flag again when dropped.
+struct SavingGuard(());
+
+impl SavingGuard {
+    fn acquire() -> Result<Self> {
+        if IS_SAVING.swap(true, Ordering::SeqCst) {
+            info!("download already in progress");
+            return Err(UpgradeError::DLInProgress);
+        }
+        Ok(Self(()))
+    }
+}
+
+impl Drop for SavingGuard {
+    fn drop(&mut self) {
+        IS_SAVING.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Where the bytes of the new image come from.
+pub(crate) trait ImageSource<S> {
+    /// Like [`Read::read`], with the flash passed in for sources that need it.
+    async fn read(&mut self, storage: &mut S, buf: &mut [u8]) -> Result<usize>;
+}
+
+/// The image as it comes off the wire.
+impl<S, R: Read> ImageSource<S> for Decompressor<R> {
+    async fn read(&mut self, _storage: &mut S, buf: &mut [u8]) -> Result<usize> {
+        Ok(Read::read(self, buf).await?)
     }
+}
 
-    let res = save_new_fw_internal(storage, binary_reader).await;
-    IS_SAVING.store(false, Ordering::SeqCst);
-    res
+/// Writes the image from `source` to the inactive partition and, once it
+/// checks out, selects it for the next boot.
+pub(crate) async fn save_image<S: AsyncNorFlash, I: ImageSource<S>>(
+    storage: &mut S,
+    source: I,
+    options: SaveOptions<'_>,
+    session: SaveSession,
+) -> Result<()> {
+    save_new_fw_internal(storage, source, options, session.partitions).await
 }
-async fn save_new_fw_internal<S: NorFlash, R: Read>(
+async fn save_new_fw_internal<S: AsyncNorFlash, I: ImageSource<S>>(
     storage: &mut S,
-    mut binary_reader: R,
+    mut source: I,
+    mut options: SaveOptions<'_>,
+    partitions: OtaPartitions,
 ) -> Result<()> {
     debug!("starting download");
+    let mut progress = ProgressReporter::new(options.progress.take(), options.image_len);
 
-    let upgrade_info = match UpgradeInfo::from_flash(storage) {
+    let upgrade_info = match UpgradeInfo::from_partition_async(storage, partitions.otadata()?).await
+    {
         Ok(info) => info,
         Err(e) => {
             return Err(e);
@@ -40,64 +264,213 @@ async fn save_new_fw_internal<S: NorFlash, R: Read>(
         warn!("booting into new fw.");
         return Err(UpgradeError::BootingIntoNewFW);
     }
-    let _ = find_running_partition(storage, upgrade_info.seq)?;
-    let inactive_partition = find_inactive_partition(storage, upgrade_info.seq)?;
-
-    debug!(
-        "erasing: from {:x} to {:x}",
-        inactive_partition.offset,
-        inactive_partition.offset + inactive_partition.size as u32
-    );
-    debug!(
-        "erasing: from {} to {}",
-        inactive_partition.offset,
-        inactive_partition.offset + inactive_partition.size as u32
-    );
-    storage
-        .erase(
-            inactive_partition.offset,
-            inactive_partition.offset + inactive_partition.size as u32,
-        )
-        .map_err(|_| UpgradeError::StorageError)?;
-
-    upgrade_info.save_to_flash(storage)?;
+    let slot_count = partitions.slot_count()?;
+    let running = running_slot(upgrade_info.seq, slot_count);
+    let target_slot = options
+        .target_slot
+        .unwrap_or_else(|| next_slot(upgrade_info.seq, slot_count));
+    if target_slot == running || target_slot >= slot_count {
+        error!(
+            "can't write slot {}, running {} of {}",
+            target_slot, running, slot_count
+        );
+        return Err(UpgradeError::InvalidSlot(target_slot));
+    }
+    let target_partition = partitions.ota_slot(target_slot)?;
+    debug!("writing slot {} ({})", target_slot, target_partition.name());
+    if let Some(image_len) = options.image_len {
+        if image_len > target_partition.size {
+            error!(
+                "image of {} bytes doesn't fit {} bytes of {}",
+                image_len,
+                target_partition.size,
+                target_partition.name()
+            );
+            return Err(UpgradeError::OutOfSpace);
+        }
+    }
+    // Full chunks are written as they are, only the last one gets padded
+    check_aligned(SECTOR_SIZE, S::WRITE_SIZE)?;
+    let resume_log = ResumeLog::from_partitions::<S>(&partitions);
+
+    let start = options.resume_from as usize;
+    if start > 0 {
+        let Some(release) = options.release else {
+            error!("resuming requires a release");
+            return Err(UpgradeError::InvalidState);
+        };
+        let committed = match &resume_log {
+            Some(log) => {
+                committed_len(storage, log, upgrade_info.seq, target_slot, release).await?
+            }
+            None => 0,
+        };
+        if committed != options.resume_from {
+            error!("no matching progress to resume from at {}", start);
+            return Err(UpgradeError::InvalidState);
+        }
+        info!("resuming download at {}", start);
+    } else if let Some(log) = &resume_log {
+        // Progress left over from an older download no longer describes the partition
+        log.clear(storage).await?;
+    }
+
+    // Bytes of the partition that are erased and ready to be written
+    let mut erased_len = start;
+    if options.erase_mode == EraseMode::Full {
+        progress.report(Phase::Erasing, start);
+        debug!(
+            "erasing: from {:x} to {:x}",
+            target_partition.offset + start as u32,
+            target_partition.offset + target_partition.size as u32
+        );
+        storage
+            .erase(
+                target_partition.offset + start as u32,
+                target_partition.offset + target_partition.size as u32,
+            )
+            .await
+            .map_err(|_| UpgradeError::StorageError)?;
+        erased_len = target_partition.size;
+    }
 
     let mut write_buffer = [0; SECTOR_SIZE];
-    let mut saved_len = 0;
+    let mut hasher = Sha256::new();
+    let mut validator = ImageValidator::new(options.chip_id);
+    // The digest covers the whole image, including what an earlier attempt wrote
+    let mut hashed_len = 0;
+    while hashed_len < start {
+        let len = (start - hashed_len).min(SECTOR_SIZE);
+        storage
+            .read(
+                target_partition.offset + hashed_len as u32,
+                &mut write_buffer[..len],
+            )
+            .await
+            .map_err(|_| UpgradeError::StorageError)?;
+        hasher.update(&write_buffer[..len]);
+        validator.update(&write_buffer[..len])?;
+        hashed_len += len;
+    }
+
+    let mut saved_len = start;
     let mut done_reading = false;
+    progress.report(Phase::Downloading, saved_len);
 
     while !done_reading {
         let mut amount_read = 0;
         while amount_read < SECTOR_SIZE {
-            let size = binary_reader
-                .read(&mut write_buffer[amount_read..])
-                .await
-                .map_err(|_| UpgradeError::StorageError)?;
+            let size = source
+                .read(storage, &mut write_buffer[amount_read..])
+                .await?;
             if size == 0 {
                 done_reading = true;
                 break;
             }
             amount_read += size;
         }
-        if amount_read + saved_len > inactive_partition.size {
+        if amount_read + saved_len > target_partition.size {
             return Err(UpgradeError::OutOfSpace);
         }
+        if let Some(image_len) = options.image_len {
+            if amount_read + saved_len > image_len {
+                error!("image is longer than the declared {} bytes", image_len);
+                return Err(UpgradeError::LengthMismatch {
+                    expected: image_len,
+                    received: amount_read + saved_len,
+                });
+            }
+        }
+        validator.update(&write_buffer[0..amount_read])?;
 
+        if saved_len + amount_read > erased_len {
+            let erase_to = (saved_len + amount_read)
+                .next_multiple_of(S::ERASE_SIZE)
+                .min(target_partition.size);
+            debug!("erasing: from {:x} to {:x}", erased_len, erase_to);
+            storage
+                .erase(
+                    target_partition.offset + erased_len as u32,
+                    target_partition.offset + erase_to as u32,
+                )
+                .await
+                .map_err(|_| UpgradeError::StorageError)?;
+            erased_len = erase_to;
+        }
+        let padded_len = amount_read.next_multiple_of(S::WRITE_SIZE);
+        write_buffer[amount_read..padded_len].fill(0xFF);
         storage
             .write(
-                inactive_partition.offset + saved_len as u32,
-                &write_buffer[0..amount_read],
+                target_partition.offset + saved_len as u32,
+                &write_buffer[0..padded_len],
             )
+            .await
             .map_err(|_| UpgradeError::StorageError)?;
+        hasher.update(&write_buffer[0..amount_read]);
         saved_len += amount_read;
+        progress.report(Phase::Downloading, saved_len);
+
+        // Resuming starts with an erase, so only whole erase blocks count as done
+        if let (Some(release), Some(log)) = (options.release, &resume_log) {
+            if !done_reading && saved_len.is_multiple_of(S::ERASE_SIZE) {
+                let progress =
+                    ResumeInfo::new(upgrade_info.seq, target_slot, release, saved_len as u32);
+                log.save(storage, &progress).await?;
+            }
+        }
     }
 
-    let new_upgrade_info = UpgradeInfo::new(upgrade_info.seq + 1, [0xFF; 20]);
-    new_upgrade_info.save_to_flash(storage)
+    progress.report(Phase::Verifying, saved_len);
+    // A short image keeps its progress, the rest of it may still arrive
+    if let Some(image_len) = options.image_len {
+        if saved_len != image_len {
+            error!("transfer ended after {} of {} bytes", saved_len, image_len);
+            return Err(UpgradeError::LengthMismatch {
+                expected: image_len,
+                received: saved_len,
+            });
+        }
+    }
+    let image_header = validator.finish()?;
+    debug!("image for chip {:?} validated", image_header.chip_id);
+    if let Some(log) = &resume_log {
+        log.clear(storage).await?;
+    }
+
+    let digest: Sha256Digest = hasher.finalize().into();
+    match options.expected_sha256 {
+        Some(expected) if expected != digest => {
+            error!(
+                "sha256 mismatch: expected {:02x?}, got {:02x?}",
+                expected, digest
+            );
+            return Err(UpgradeError::ChecksumMismatch);
+        }
+        Some(_) => debug!("sha256 verified"),
+        None => warn!("no sha256 to verify the image against"),
+    }
+
+    #[cfg(feature = "ed25519")]
+    if !options.trusted_keys.is_empty() {
+        let signature = options.signature.ok_or(UpgradeError::MissingSignature)?;
+        verify_signature(&digest, &signature, options.trusted_keys)?;
+    }
+
+    progress.report(Phase::Committing, saved_len);
+    let new_seq = seq_for_slot(upgrade_info.seq, target_slot, slot_count);
+    let new_upgrade_info = UpgradeInfo::new(new_seq, [0xFF; 20]);
+    new_upgrade_info
+        .save_to_partition_async(storage, partitions.otadata()?)
+        .await
 }
 
 pub fn accept_fw<S: NorFlash>(storage: &mut S) -> Result<()> {
-    let mut upgrade_info = UpgradeInfo::from_flash(storage)?;
+    block_on(accept_fw_async(&mut BlockingAsync::new(storage)))
+}
+
+/// [`accept_fw`] for async flash drivers.
+pub async fn accept_fw_async<S: AsyncNorFlash>(storage: &mut S) -> Result<()> {
+    let mut upgrade_info = UpgradeInfo::from_flash_async(storage).await?;
     let mut should_write = true;
 
     match upgrade_info.state {
@@ -108,8 +481,9 @@ pub fn accept_fw<S: NorFlash>(storage: &mut S) -> Result<()> {
             warn!("Accepted upgrade from state {:?}.", upgrade_info.state);
         }
         AppOTAState::Invalid | AppOTAState::Aborted => {
-            warn!("Rolled back but not marked by bootloader. Saving manually");
-            upgrade_info.seq -= 1;
+            // Never selected, the bootloader already rolled back to the other entry
+            error!("selected otadata entry is {:?}", upgrade_info.state);
+            should_write = false;
         }
         AppOTAState::Valid => {
             should_write = false;
@@ -118,13 +492,18 @@ pub fn accept_fw<S: NorFlash>(storage: &mut S) -> Result<()> {
     }
     if should_write {
         upgrade_info.state = AppOTAState::Valid;
-        upgrade_info.save_to_flash(storage)?
+        upgrade_info.save_to_flash_async(storage).await?
     }
     Ok(())
 }
 
 pub fn reject_fw<S: NorFlash>(storage: &mut S) -> Result<()> {
-    let mut upgrade_info = UpgradeInfo::from_flash(storage)?;
+    block_on(reject_fw_async(&mut BlockingAsync::new(storage)))
+}
+
+/// [`reject_fw`] for async flash drivers.
+pub async fn reject_fw_async<S: AsyncNorFlash>(storage: &mut S) -> Result<()> {
+    let mut upgrade_info = UpgradeInfo::from_flash_async(storage).await?;
     let mut should_write = false;
 
     match upgrade_info.state {
@@ -148,7 +527,7 @@ pub fn reject_fw<S: NorFlash>(storage: &mut S) -> Result<()> {
 
     if should_write {
         upgrade_info.state = AppOTAState::Invalid;
-        upgrade_info.save_to_flash(storage)?;
+        upgrade_info.save_to_flash_async(storage).await?;
     }
     Ok(())
 }
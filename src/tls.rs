@@ -0,0 +1,55 @@
+//! TLS settings for [`crate::BotifactoryClient::new_tls`].
+//!
+//! The server is authenticated with a pre-shared key. There is no CA or
+//! public key pinning: reqwless 0.13 runs the handshake through embedded-tls
+//! without looking at the server certificate, and its esp-mbedtls backend,
+//! the one that takes a CA chain, isn't in the released crate. A server
+//! without the key can't complete the handshake, which fails with
+//! [`UpgradeError::UntrustedServer`].
+//!
+//! Every trust option authenticates the server, encryption alone isn't
+//! offered here. A client that has to accept any server can still be built
+//! with [`crate::BotifactoryClient::new`] from its own `HttpClient`.
+
+use crate::error::{Result, UpgradeError};
+use log::error;
+use reqwless::client::{TlsConfig, TlsVerify};
+
+/// How the server is authenticated during the handshake.
+#[derive(Clone, Copy, Debug)]
+pub enum ServerTrust<'a> {
+    /// TLS-PSK, only a server holding the same key is accepted.
+    Psk { identity: &'a [u8], psk: &'a [u8] },
+}
+
+pub struct TlsOptions<'a> {
+    /// Randomness for the handshake, take it from the hardware RNG.
+    pub seed: u64,
+    /// TLS record buffers, 16640 bytes each to handle any record size.
+    pub read_buffer: &'a mut [u8],
+    pub write_buffer: &'a mut [u8],
+    pub trust: ServerTrust<'a>,
+    /// Let `http://` URLs through, e.g. for a server on the local network.
+    pub allow_http: bool,
+}
+
+impl<'a> TlsOptions<'a> {
+    pub(crate) fn into_config(self) -> TlsConfig<'a> {
+        let ServerTrust::Psk { identity, psk } = self.trust;
+        let verify = TlsVerify::Psk { identity, psk };
+        TlsConfig::new(self.seed, self.read_buffer, self.write_buffer, verify)
+    }
+}
+
+/// Refuses `url` unless it's `https://`, or `http://` with `allow_http`.
+pub fn require_https(url: &str, allow_http: bool) -> Result<()> {
+    let scheme = url.split_once("://").map(|(scheme, _)| scheme);
+    match scheme {
+        Some(scheme) if scheme.eq_ignore_ascii_case("https") => Ok(()),
+        Some(scheme) if allow_http && scheme.eq_ignore_ascii_case("http") => Ok(()),
+        _ => {
+            error!("refusing insecure url {}", url);
+            Err(UpgradeError::InsecureUrl)
+        }
+    }
+}
@@ -0,0 +1,89 @@
+use core::cmp::Ordering;
+use log::debug;
+use semver::Version;
+
+/// Which versions offered by the server should be taken.
+///
+/// The default only takes stable updates within the running major version.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub struct UpdatePolicy {
+    /// Take pre-release versions such as `2.1.0-rc.1`.
+    pub allow_prerelease: bool,
+    /// Take versions older than the running one, e.g. a rollback pushed from the server.
+    pub allow_downgrade: bool,
+    /// Take versions with a different major version than the running one.
+    pub allow_major: bool,
+}
+
+/// Why an offered version was passed over.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum IgnoreReason {
+    Prerelease,
+    MajorVersion,
+    Downgrade,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum UpdateDecision {
+    /// The server has the version that's already running.
+    UpToDate,
+    /// The server has a newer version the policy allows.
+    UpdateAvailable(Version),
+    /// The server has an older version and the policy allows downgrades.
+    DowngradeOffered(Version),
+    /// The server has a different version but the policy rules it out.
+    Ignored {
+        version: Version,
+        reason: IgnoreReason,
+    },
+}
+
+impl UpdateDecision {
+    /// Whether the offered firmware should be downloaded.
+    pub fn should_update(&self) -> bool {
+        matches!(
+            self,
+            UpdateDecision::UpdateAvailable(_) | UpdateDecision::DowngradeOffered(_)
+        )
+    }
+
+    /// The version to download, if any.
+    pub fn version(&self) -> Option<&Version> {
+        match self {
+            UpdateDecision::UpdateAvailable(version)
+            | UpdateDecision::DowngradeOffered(version) => Some(version),
+            _ => None,
+        }
+    }
+}
+
+impl UpdatePolicy {
+    /// Decides whether to move from `current` to the `offered` version.
+    ///
+    /// Build metadata is ignored, as semver precedence says.
+    pub fn decide(&self, current: &Version, offered: &Version) -> UpdateDecision {
+        let ignored = |reason| {
+            debug!("ignoring version {} ({:?})", offered, reason);
+            UpdateDecision::Ignored {
+                version: offered.clone(),
+                reason,
+            }
+        };
+
+        let ordering = offered.cmp_precedence(current);
+        if ordering == Ordering::Equal {
+            return UpdateDecision::UpToDate;
+        }
+        if !offered.pre.is_empty() && !self.allow_prerelease {
+            return ignored(IgnoreReason::Prerelease);
+        }
+        if offered.major != current.major && !self.allow_major {
+            return ignored(IgnoreReason::MajorVersion);
+        }
+        match ordering {
+            Ordering::Greater => UpdateDecision::UpdateAvailable(offered.clone()),
+            _ if self.allow_downgrade => UpdateDecision::DowngradeOffered(offered.clone()),
+            _ => ignored(IgnoreReason::Downgrade),
+        }
+    }
+}
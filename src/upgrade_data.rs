@@ -1,11 +1,18 @@
 use crate::error::{Result, UpgradeError};
-use crate::partition::find_ota_partition;
+use crate::flash::{check_aligned, write_padded, BlockingAsync};
+use crate::partition::{find_ota_partition, PartitionTableBuffer};
 use crate::seq_crc::esp_crc;
 use alloc::fmt::Display;
 use core::fmt::Formatter;
+use embassy_futures::block_on;
 use embedded_storage::nor_flash::NorFlash;
+use embedded_storage_async::nor_flash::NorFlash as AsyncNorFlash;
+use esp_partition_table::PartitionEntry;
+use log::debug;
 
+/// Distance between the two otadata entries, fixed by the bootloader.
 const SECTOR_SIZE: usize = 0x1000;
+const ENTRY_SIZE: usize = 32;
 
 /// These aren't really arbitrary/crate invented states.
 /// They come from the espressive bootloader
@@ -72,22 +79,30 @@ pub struct UpgradeInfo {
 }
 
 impl UpgradeInfo {
+    /// The entry the bootloader boots from.
+    ///
+    /// Like ESP-IDF, both sectors are read and the selectable entry with the
+    /// highest seq wins. Sector 0 wins a tie.
     pub fn from_flash<S: NorFlash>(storage: &mut S) -> Result<Self> {
-        let ota_partition = find_ota_partition(storage)?;
-        let mut buffer = [0; 32];
-        storage
-            .read(ota_partition.offset, &mut buffer)
-            .map_err(|_| UpgradeError::StorageError)?;
-
-        if let Ok(upgrade_info) = UpgradeInfo::try_from(buffer) {
-            return Ok(upgrade_info);
-        }
+        block_on(Self::from_flash_async(&mut BlockingAsync::new(storage)))
+    }
 
-        storage
-            .read(ota_partition.offset + SECTOR_SIZE as u32, &mut buffer)
-            .map_err(|_| UpgradeError::StorageError)?;
+    /// [`UpgradeInfo::from_flash`] for async flash drivers.
+    pub async fn from_flash_async<S: AsyncNorFlash>(storage: &mut S) -> Result<Self> {
+        let ota_partition = find_ota_partition(&mut PartitionTableBuffer::read(storage).await?)?;
+        Self::from_partition_async(storage, &ota_partition).await
+    }
 
-        UpgradeInfo::try_from(buffer).map_err(|_| UpgradeError::StorageError)
+    /// [`UpgradeInfo::from_flash_async`] with the otadata partition already looked up.
+    pub(crate) async fn from_partition_async<S: AsyncNorFlash>(
+        storage: &mut S,
+        ota_partition: &PartitionEntry,
+    ) -> Result<Self> {
+        let entries = read_entries(storage, ota_partition).await?;
+        match active_sector(&entries) {
+            Some(sector) => Ok(entries[sector].unwrap()),
+            None => Err(UpgradeError::StorageError),
+        }
     }
 
     pub fn new(seq: u32, label: [u8; 20]) -> Self {
@@ -105,32 +120,83 @@ impl UpgradeInfo {
         self.state == AppOTAState::Valid || self.state == AppOTAState::Undefined
     }
 
+    /// Whether the bootloader would consider booting this entry at all.
+    pub fn is_selectable(&self) -> bool {
+        self.seq != u32::MAX && !matches!(self.state, AppOTAState::Invalid | AppOTAState::Aborted)
+    }
+
+    /// Saves the entry the way ESP-IDF does.
+    ///
+    /// A new seq goes into the sector that isn't active, so the active entry
+    /// survives if power is lost halfway through. A state change of the active
+    /// seq rewrites the active sector, falling back to the other entry if
+    /// that write is interrupted.
     pub fn save_to_flash<S: NorFlash>(&self, storage: &mut S) -> Result<()> {
-        let ota_partition = find_ota_partition(storage)?;
-        let buffer: [u8; 32] = (*self).into();
+        block_on(self.save_to_flash_async(&mut BlockingAsync::new(storage)))
+    }
 
-        // Write sector 1
+    /// [`UpgradeInfo::save_to_flash`] for async flash drivers.
+    pub async fn save_to_flash_async<S: AsyncNorFlash>(&self, storage: &mut S) -> Result<()> {
+        let ota_partition = find_ota_partition(&mut PartitionTableBuffer::read(storage).await?)?;
+        self.save_to_partition_async(storage, &ota_partition).await
+    }
+
+    /// [`UpgradeInfo::save_to_flash_async`] with the otadata partition already looked up.
+    pub(crate) async fn save_to_partition_async<S: AsyncNorFlash>(
+        &self,
+        storage: &mut S,
+        ota_partition: &PartitionEntry,
+    ) -> Result<()> {
+        let entries = read_entries(storage, ota_partition).await?;
+        let sector = match active_sector(&entries) {
+            Some(active) if entries[active].unwrap().seq == self.seq => active,
+            Some(active) => 1 - active,
+            None => 0,
+        };
+        debug!("writing otadata seq {} to sector {}", self.seq, sector);
+
+        // Each entry needs its own erase block so the other one survives
+        check_aligned(SECTOR_SIZE, S::ERASE_SIZE)?;
+        let offset = ota_partition.offset + (sector * SECTOR_SIZE) as u32;
+        let buffer: [u8; ENTRY_SIZE] = (*self).into();
         storage
             .erase(
-                ota_partition.offset,
-                ota_partition.offset + SECTOR_SIZE as u32,
+                offset,
+                offset + ENTRY_SIZE.next_multiple_of(S::ERASE_SIZE) as u32,
             )
+            .await
             .map_err(|_| UpgradeError::StorageError)?;
-        storage
-            .write(ota_partition.offset, &buffer)
-            .map_err(|_| UpgradeError::StorageError)?;
+        write_padded(storage, offset, &buffer).await
+    }
+}
 
-        // Write sector 2
+/// Both otadata entries, `None` for blank or corrupted ones.
+async fn read_entries<S: AsyncNorFlash>(
+    storage: &mut S,
+    ota_partition: &PartitionEntry,
+) -> Result<[Option<UpgradeInfo>; 2]> {
+    let mut entries = [None; 2];
+    for (sector, entry) in entries.iter_mut().enumerate() {
+        let mut buffer = [0; 32];
         storage
-            .erase(
-                ota_partition.offset + SECTOR_SIZE as u32,
-                ota_partition.offset + 2 * SECTOR_SIZE as u32,
+            .read(
+                ota_partition.offset + (sector * SECTOR_SIZE) as u32,
+                &mut buffer,
             )
+            .await
             .map_err(|_| UpgradeError::StorageError)?;
-        storage
-            .write(ota_partition.offset + SECTOR_SIZE as u32, &buffer)
-            .map_err(|_| UpgradeError::StorageError)?;
-        Ok(())
+        *entry = UpgradeInfo::try_from(buffer).ok();
+    }
+    Ok(entries)
+}
+
+/// `bootloader_common_get_active_otadata`
+fn active_sector(entries: &[Option<UpgradeInfo>; 2]) -> Option<usize> {
+    match entries.map(|entry| entry.filter(UpgradeInfo::is_selectable)) {
+        [Some(first), Some(second)] if second.seq > first.seq => Some(1),
+        [Some(_), _] => Some(0),
+        [None, Some(_)] => Some(1),
+        [None, None] => None,
     }
 }
 
@@ -0,0 +1,100 @@
+mod common;
+
+use botifactory_ota_nostd::{
+    accept_fw, find_partition_by_name, resume_offset, save_new_fw, AppOTAState, RamFlash,
+    ResumeInfo, UpgradeError, UpgradeInfo, RESUME_PARTITION_NAME,
+};
+use common::{save_lock, test_image, APP_SIZE};
+use embassy_futures::block_on;
+use embedded_storage::nor_flash::ReadNorFlash;
+
+fn layout<const W: usize, const E: usize>() -> RamFlash<W, E> {
+    let mut flash = RamFlash::builder(0x10000 + 2 * APP_SIZE)
+        .nvs(0x9000, 0x4000)
+        .otadata(0xD000, 0x2000)
+        .partition(RESUME_PARTITION_NAME, 0x01, 0x06, 0xF000, 0x1000)
+        .ota(0, 0x10000, APP_SIZE)
+        .ota(1, 0x10000 + APP_SIZE as u32, APP_SIZE)
+        .build();
+    let mut info = UpgradeInfo::new(1, [0xFF; 20]);
+    info.state = AppOTAState::Valid;
+    info.save_to_flash(&mut flash).unwrap();
+    flash
+}
+
+/// Full update and accept, with an image that doesn't end on a word boundary.
+fn update<const W: usize, const E: usize>() {
+    let mut flash = layout::<W, E>();
+    // Trailing bytes after the image proper are allowed
+    let mut image = test_image(10_000);
+    image.extend_from_slice(&[0xA5; 5]);
+
+    block_on(save_new_fw(&mut flash, image.as_slice())).unwrap();
+    accept_fw(&mut flash).unwrap();
+
+    let ota_1 = find_partition_by_name(&mut flash, "ota_1").unwrap();
+    let mut written = vec![0; image.len().next_multiple_of(W)];
+    flash.read(ota_1.offset, &mut written).unwrap();
+    assert_eq!(written[..image.len()], image);
+    assert!(written[image.len()..].iter().all(|b| *b == 0xFF));
+    let info = UpgradeInfo::from_flash(&mut flash).unwrap();
+    assert_eq!((info.seq, info.state), (2, AppOTAState::Valid));
+}
+
+#[test]
+fn pads_writes_to_write_size() {
+    let _lock = save_lock();
+    update::<4, 4096>();
+    update::<16, 4096>();
+    update::<256, 4096>();
+}
+
+#[test]
+fn works_with_small_erase_blocks() {
+    let _lock = save_lock();
+    update::<1, 256>();
+    update::<4, 1024>();
+}
+
+#[test]
+fn resume_records_are_padded() {
+    let mut flash = layout::<64, 4096>();
+    for committed in [4096, 8192, 12288] {
+        ResumeInfo::new(1, 1, "1.0.0", committed)
+            .save_to_flash(&mut flash)
+            .unwrap();
+    }
+    assert_eq!(resume_offset(&mut flash, "1.0.0").unwrap(), 12288);
+}
+
+#[test]
+fn otadata_needs_an_erase_block_per_entry() {
+    let mut flash: RamFlash<4, 0x2000> = RamFlash::builder(0x30000)
+        .otadata(0xE000, 0x2000)
+        .ota(0, 0x10000, 0x10000)
+        .ota(1, 0x20000, 0x10000)
+        .build();
+
+    let result = UpgradeInfo::new(1, [0xFF; 20]).save_to_flash(&mut flash);
+
+    assert!(matches!(result, Err(UpgradeError::UnsupportedAlignment)));
+}
+
+#[test]
+fn resume_log_larger_than_its_partition_is_disabled() {
+    let _lock = save_lock();
+    let mut flash: RamFlash<4, 4096> = RamFlash::builder(0x10000 + 2 * APP_SIZE)
+        .otadata(0xD000, 0x2000)
+        .partition(RESUME_PARTITION_NAME, 0x01, 0x06, 0xF000, 0x800)
+        .ota(0, 0x10000, APP_SIZE)
+        .ota(1, 0x10000 + APP_SIZE as u32, APP_SIZE)
+        .build();
+    let mut info = UpgradeInfo::new(1, [0xFF; 20]);
+    info.state = AppOTAState::Valid;
+    info.save_to_flash(&mut flash).unwrap();
+
+    block_on(save_new_fw(&mut flash, test_image(10_000).as_slice())).unwrap();
+
+    assert_eq!(ResumeInfo::from_flash(&mut flash).unwrap(), None);
+    assert_eq!(resume_offset(&mut flash, "1.0.0").unwrap(), 0);
+}
@@ -0,0 +1,61 @@
+mod common;
+
+use botifactory_ota_nostd::{
+    find_partition_by_name, save_new_fw, AppDescriptor, AppOTAState, ImageError, UpgradeError,
+};
+use common::{app_image, flash_with_state, save_lock, test_image};
+use embassy_futures::block_on;
+use embedded_storage::nor_flash::NorFlash;
+use semver::Version;
+
+#[test]
+fn reads_running_descriptor() {
+    // seq 1 runs ota_0
+    let mut flash = flash_with_state(1, AppOTAState::Valid);
+    let ota_0 = find_partition_by_name(&mut flash, "ota_0").unwrap();
+    flash.write(ota_0.offset, &app_image("1.4.2")).unwrap();
+
+    let desc = AppDescriptor::running(&mut flash).unwrap();
+    assert_eq!(desc.version(), "1.4.2");
+    assert_eq!(desc.semver().unwrap(), Version::new(1, 4, 2));
+    assert_eq!(desc.project_name(), "botifarm");
+    assert_eq!(desc.time(), "12:34:56");
+    assert_eq!(desc.date(), "Oct 17 2026");
+    assert_eq!(desc.idf_version(), "v5.3.1");
+    assert_eq!(desc.secure_version, 2);
+    assert_eq!(desc.app_elf_sha256, [0x5A; 32]);
+}
+
+#[test]
+fn reads_staged_descriptor() {
+    let _lock = save_lock();
+    let mut flash = flash_with_state(1, AppOTAState::Valid);
+    let image = app_image("2.0.0-rc.1");
+    block_on(save_new_fw(&mut flash, image.as_slice())).unwrap();
+
+    // The new firmware went into the partition that was inactive at seq 1.
+    let ota_1 = find_partition_by_name(&mut flash, "ota_1").unwrap();
+    let desc = AppDescriptor::from_partition(&mut flash, &ota_1).unwrap();
+    assert_eq!(desc.version(), "2.0.0-rc.1");
+}
+
+#[test]
+fn empty_partition_has_no_descriptor() {
+    let mut flash = flash_with_state(1, AppOTAState::Valid);
+    assert!(matches!(
+        AppDescriptor::inactive(&mut flash),
+        Err(UpgradeError::InvalidImage(ImageError::BadMagic(0xFF)))
+    ));
+}
+
+#[test]
+fn image_without_descriptor_is_rejected() {
+    let mut flash = flash_with_state(1, AppOTAState::Valid);
+    let ota_0 = find_partition_by_name(&mut flash, "ota_0").unwrap();
+    flash.write(ota_0.offset, &test_image(1000)).unwrap();
+
+    assert!(matches!(
+        AppDescriptor::running(&mut flash),
+        Err(UpgradeError::InvalidImage(ImageError::NoAppDescriptor))
+    ));
+}
@@ -0,0 +1,173 @@
+mod common;
+
+use botifactory_ota_nostd::{
+    accept_fw_async, find_partition_by_name, pending_events, resume_offset_async,
+    save_new_fw_async, save_new_fw_with_options_async, AppOTAState, BotifactoryClient, Outcome,
+    PartitionTableBuffer, RamFlash, SaveOptions, UpdateEvent, UpgradeInfo,
+};
+use common::http::FakeServer;
+use common::{
+    flash_with_state, read_partition, reporting_flash, resumable_flash_with_state, save_lock,
+    test_image, FlakyReader,
+};
+use embassy_futures::{block_on, yield_now};
+use embedded_storage::nor_flash::{ErrorType, NorFlash, ReadNorFlash};
+use embedded_storage_async::nor_flash::{
+    NorFlash as AsyncNorFlash, ReadNorFlash as AsyncReadNorFlash,
+};
+use reqwless::client::HttpClient;
+use semver::Version;
+
+/// Async driver that hands control back to the executor before every operation.
+struct YieldingFlash {
+    inner: RamFlash,
+    yields: usize,
+}
+
+impl ErrorType for YieldingFlash {
+    type Error = <RamFlash as ErrorType>::Error;
+}
+
+impl AsyncReadNorFlash for YieldingFlash {
+    const READ_SIZE: usize = <RamFlash as ReadNorFlash>::READ_SIZE;
+
+    async fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        yield_now().await;
+        self.yields += 1;
+        self.inner.read(offset, bytes)
+    }
+
+    fn capacity(&self) -> usize {
+        self.inner.capacity()
+    }
+}
+
+impl AsyncNorFlash for YieldingFlash {
+    const WRITE_SIZE: usize = <RamFlash as NorFlash>::WRITE_SIZE;
+    const ERASE_SIZE: usize = <RamFlash as NorFlash>::ERASE_SIZE;
+
+    async fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        yield_now().await;
+        self.yields += 1;
+        self.inner.erase(from, to)
+    }
+
+    async fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        yield_now().await;
+        self.yields += 1;
+        self.inner.write(offset, bytes)
+    }
+}
+
+fn yielding(inner: RamFlash) -> YieldingFlash {
+    YieldingFlash { inner, yields: 0 }
+}
+
+#[test]
+fn updates_through_async_flash() {
+    let _lock = save_lock();
+    let mut flash = yielding(flash_with_state(1, AppOTAState::Valid));
+    let image = test_image(20_000);
+
+    block_on(save_new_fw_async(&mut flash, image.as_slice())).unwrap();
+    assert!(flash.yields > 0);
+
+    let info = block_on(UpgradeInfo::from_flash_async(&mut flash)).unwrap();
+    assert_eq!((info.seq, info.state), (2, AppOTAState::New));
+    block_on(accept_fw_async(&mut flash)).unwrap();
+
+    let mut flash = flash.inner;
+    assert_eq!(read_partition(&mut flash, "ota_1", image.len()), image);
+    let info = UpgradeInfo::from_flash(&mut flash).unwrap();
+    assert_eq!((info.seq, info.state), (2, AppOTAState::Valid));
+}
+
+#[test]
+fn resumes_through_async_flash() {
+    let _lock = save_lock();
+    let mut flash = yielding(resumable_flash_with_state(1, AppOTAState::Valid));
+    let image = test_image(20_000);
+    let options = || SaveOptions {
+        release: Some("2.0.0"),
+        ..Default::default()
+    };
+
+    let reader = FlakyReader {
+        data: &image,
+        position: 0,
+        fail_at: 10_000,
+    };
+    assert!(block_on(save_new_fw_with_options_async(
+        &mut flash,
+        reader,
+        options()
+    ))
+    .is_err());
+    let offset = block_on(resume_offset_async(&mut flash, "2.0.0")).unwrap();
+    assert_eq!(offset, 8192);
+
+    let options = SaveOptions {
+        resume_from: offset,
+        ..options()
+    };
+    block_on(save_new_fw_with_options_async(
+        &mut flash,
+        &image[offset as usize..],
+        options,
+    ))
+    .unwrap();
+    assert_eq!(
+        read_partition(&mut flash.inner, "ota_1", image.len()),
+        image
+    );
+}
+
+#[test]
+fn looks_up_partitions_in_table_copy() {
+    let mut flash = yielding(flash_with_state(1, AppOTAState::Valid));
+
+    let mut table = block_on(PartitionTableBuffer::read(&mut flash)).unwrap();
+
+    for name in ["nvs", "otadata", "ota_0", "ota_1"] {
+        let copy = find_partition_by_name(&mut table, name).unwrap();
+        let direct = find_partition_by_name(&mut flash.inner, name).unwrap();
+        assert_eq!((copy.offset, copy.size), (direct.offset, direct.size));
+    }
+}
+
+#[test]
+fn queues_reports_through_async_flash() {
+    let mut flash = yielding(reporting_flash());
+    let server = FakeServer::default();
+    let mut client = BotifactoryClient::new(
+        "http://ota.example.com/bot/stable/latest/binary".to_string(),
+        HttpClient::new(&server, &server),
+    )
+    .with_report_url("http://ota.example.com/bot/report".to_string());
+    let installed = UpdateEvent::new(
+        Version::new(1, 0, 0),
+        Version::new(1, 1, 0),
+        Outcome::Installed,
+    );
+    let accepted = UpdateEvent::new(
+        Version::new(1, 0, 0),
+        Version::new(1, 1, 0),
+        Outcome::Accepted,
+    );
+
+    server.respond("503 Service Unavailable", &[], b"");
+    block_on(client.report_or_queue_async(&mut flash, "bot-1", &installed)).unwrap();
+    assert_eq!(pending_events(&mut flash.inner).unwrap().len(), 1);
+
+    // The queued event goes out first
+    server.respond("204 No Content", &[], b"");
+    server.respond("204 No Content", &[], b"");
+    block_on(client.report_or_queue_async(&mut flash, "bot-1", &accepted)).unwrap();
+    assert!(flash.yields > 0);
+    assert!(pending_events(&mut flash.inner).unwrap().is_empty());
+
+    let requests = server.requests();
+    assert_eq!(requests.len(), 3);
+    assert!(requests[1].ends_with(&installed.to_json("bot-1").unwrap()));
+    assert!(requests[2].ends_with(&accepted.to_json("bot-1").unwrap()));
+}
@@ -0,0 +1,96 @@
+use botifactory_ota_nostd::{sign_request, Credentials, UpgradeError};
+use reqwless::request::Method;
+
+const URL: &str = "https://ota.example.com/bot/stable/latest";
+
+fn now() -> u64 {
+    1760702400
+}
+
+#[test]
+fn bearer_and_api_key_headers() {
+    assert!(Credentials::None.headers(Method::GET, URL, &[]).is_empty());
+    assert_eq!(
+        Credentials::Bearer("t0ken".into()).headers(Method::GET, URL, &[]),
+        [("authorization", "Bearer t0ken".to_string())]
+    );
+    assert_eq!(
+        Credentials::ApiKey {
+            device_id: "bot-7".into(),
+            key: "k3y".into(),
+        }
+        .headers(Method::GET, URL, &[]),
+        [
+            ("x-device-id", "bot-7".to_string()),
+            ("x-api-key", "k3y".to_string())
+        ]
+    );
+}
+
+#[test]
+fn hmac_signs_method_url_timestamp_and_body() {
+    let credentials = Credentials::Hmac {
+        key_id: "bot-7".into(),
+        secret: b"secret".to_vec(),
+        clock: Box::new(now),
+    };
+
+    assert_eq!(
+        credentials.headers(Method::GET, URL, &[]),
+        [
+            (
+                "authorization",
+                "HMAC-SHA256 keyId=bot-7,signature=cff0cb03914548c8dc6b0c268828ddb79003da509f9824bd5ca311677244ee1e".to_string()
+            ),
+            ("x-timestamp", "1760702400".to_string())
+        ]
+    );
+
+    let signature = sign_request(
+        b"secret",
+        Method::POST,
+        "https://ota.example.com/bot/stable/reports",
+        "1760702400",
+        br#"{"a":1}"#,
+    );
+    assert_eq!(
+        signature[..4],
+        [0x1a, 0x09, 0x3b, 0x1c],
+        "body is part of the signature"
+    );
+}
+
+#[test]
+fn debug_output_hides_secrets() {
+    let credentials = [
+        Credentials::Bearer("t0ken".into()),
+        Credentials::ApiKey {
+            device_id: "bot-7".into(),
+            key: "k3y".into(),
+        },
+        Credentials::Hmac {
+            key_id: "bot-7".into(),
+            secret: b"secret".to_vec(),
+            clock: Box::new(now),
+        },
+    ];
+
+    for credentials in credentials {
+        let debug = format!("{:?}", credentials);
+        assert!(
+            !debug.contains("t0ken") && !debug.contains("k3y"),
+            "{}",
+            debug
+        );
+        assert!(!debug.contains("115, 101, 99"), "{}", debug);
+    }
+    assert!(format!("{:?}", Credentials::None).contains("None"));
+}
+
+#[test]
+fn unauthorized_has_its_own_code() {
+    assert_ne!(
+        UpgradeError::Unauthorized.code(),
+        UpgradeError::RequestError.code()
+    );
+}
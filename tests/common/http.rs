@@ -0,0 +1,163 @@
+//! Canned HTTP responses for driving `BotifactoryClient` without a network.
+
+use core::future::pending;
+use core::net::{IpAddr, Ipv4Addr, SocketAddr};
+use embedded_io::ErrorKind;
+use embedded_io_async::{ErrorType, Read, Write};
+use embedded_nal_async::{AddrType, Dns, TcpConnect};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+
+/// Answers each connection with the next queued response and keeps the
+/// requests it was sent. Doubles as the DNS resolver.
+#[derive(Default)]
+pub struct FakeServer {
+    responses: RefCell<VecDeque<Queued>>,
+    requests: RefCell<Vec<String>>,
+}
+
+struct Queued {
+    response: Vec<u8>,
+    /// Hang once the response is sent rather than ending the body.
+    stall: bool,
+}
+
+impl FakeServer {
+    pub fn respond(&self, status: &str, headers: &[(&str, &str)], body: &[u8]) {
+        self.queue(status, headers, body.len(), body, false);
+    }
+
+    /// Sends the first `body.len()` of `content_length` bytes, then hangs.
+    pub fn respond_partially(&self, status: &str, content_length: usize, body: &[u8]) {
+        self.queue(status, &[], content_length, body, true);
+    }
+
+    /// Takes the request but never answers.
+    pub fn stall(&self) {
+        self.responses.borrow_mut().push_back(Queued {
+            response: Vec::new(),
+            stall: true,
+        });
+    }
+
+    fn queue(
+        &self,
+        status: &str,
+        headers: &[(&str, &str)],
+        content_length: usize,
+        body: &[u8],
+        stall: bool,
+    ) {
+        let mut response = format!(
+            "HTTP/1.1 {}\r\ncontent-length: {}\r\n",
+            status, content_length
+        );
+        for (name, value) in headers {
+            response.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        response.push_str("\r\n");
+        let mut response = response.into_bytes();
+        response.extend_from_slice(body);
+        self.responses
+            .borrow_mut()
+            .push_back(Queued { response, stall });
+    }
+
+    pub fn redirect(&self, location: &str) {
+        self.respond("302 Found", &[("location", location)], b"");
+    }
+
+    /// Requests received so far, head and body as sent.
+    pub fn requests(&self) -> Vec<String> {
+        self.requests.borrow().clone()
+    }
+}
+
+pub struct FakeConnection<'a> {
+    server: &'a FakeServer,
+    request: Vec<u8>,
+    response: Option<Queued>,
+    position: usize,
+}
+
+impl FakeConnection<'_> {
+    /// The request is complete once the client starts reading the answer.
+    fn finish_request(&mut self) {
+        if self.response.is_none() {
+            let request = String::from_utf8_lossy(&self.request).into_owned();
+            self.server.requests.borrow_mut().push(request);
+            let response = self.server.responses.borrow_mut().pop_front();
+            self.response = Some(response.expect("no response queued"));
+        }
+    }
+}
+
+impl ErrorType for FakeConnection<'_> {
+    type Error = ErrorKind;
+}
+
+impl Read for FakeConnection<'_> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, ErrorKind> {
+        self.finish_request();
+        let Queued { response, stall } = self.response.as_ref().unwrap();
+        if *stall && self.position == response.len() {
+            return pending().await;
+        }
+        let len = buf.len().min(response.len() - self.position);
+        buf[..len].copy_from_slice(&response[self.position..self.position + len]);
+        self.position += len;
+        Ok(len)
+    }
+}
+
+impl Write for FakeConnection<'_> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, ErrorKind> {
+        self.request.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+}
+
+impl TcpConnect for FakeServer {
+    type Error = ErrorKind;
+    type Connection<'a> = FakeConnection<'a>;
+
+    async fn connect<'a>(&'a self, _remote: SocketAddr) -> Result<FakeConnection<'a>, ErrorKind> {
+        Ok(FakeConnection {
+            server: self,
+            request: Vec::new(),
+            response: None,
+            position: 0,
+        })
+    }
+}
+
+impl Dns for FakeServer {
+    type Error = ErrorKind;
+
+    async fn get_host_by_name(
+        &self,
+        _host: &str,
+        _addr_type: AddrType,
+    ) -> Result<IpAddr, ErrorKind> {
+        Ok(IpAddr::V4(Ipv4Addr::LOCALHOST))
+    }
+
+    async fn get_host_by_address(
+        &self,
+        _addr: IpAddr,
+        _result: &mut [u8],
+    ) -> Result<usize, ErrorKind> {
+        Err(ErrorKind::Unsupported)
+    }
+}
+
+/// Header `name` of a recorded request, matched case-insensitively.
+pub fn header<'r>(request: &'r str, name: &str) -> Option<&'r str> {
+    request
+        .split("\r\n\r\n")
+        .next()?
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(header, _)| header.trim().eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim())
+}
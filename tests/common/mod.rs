@@ -0,0 +1,180 @@
+#![allow(dead_code)]
+
+pub mod http;
+
+use botifactory_ota_nostd::{
+    find_ota_partition, find_partition_by_name, AppOTAState, ChipId, RamFlash, UpgradeInfo,
+    REPORT_PARTITION_NAME, RESUME_PARTITION_NAME,
+};
+use embedded_storage::nor_flash::ReadNorFlash;
+use sha2::{Digest, Sha256};
+use std::sync::{Mutex, MutexGuard};
+
+/// Size of each app slot in the test layout.
+pub const APP_SIZE: usize = 0x10000;
+
+/// `save_new_fw` refuses to run concurrently, so tests that call it take this lock.
+static SAVE_LOCK: Mutex<()> = Mutex::new(());
+
+pub fn save_lock() -> MutexGuard<'static, ()> {
+    SAVE_LOCK
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Flash with the standard two slot layout and an accepted otadata entry.
+pub fn flash_with_state(seq: u32, state: AppOTAState) -> RamFlash {
+    let mut flash = RamFlash::with_ota_layout(APP_SIZE);
+    write_upgrade_info(&mut flash, seq, state);
+    flash
+}
+
+/// Flash right after an update: `seq - 1` accepted in one otadata sector,
+/// `seq` in `state` in the other.
+pub fn flash_after_update(seq: u32, state: AppOTAState) -> RamFlash {
+    let mut flash = flash_with_state(seq - 1, AppOTAState::Valid);
+    write_upgrade_info(&mut flash, seq, state);
+    flash
+}
+
+pub fn write_upgrade_info(flash: &mut RamFlash, seq: u32, state: AppOTAState) {
+    let mut info = UpgradeInfo::new(seq, [0xFF; 20]);
+    info.state = state;
+    info.save_to_flash(flash).unwrap();
+}
+
+/// Raw bytes of otadata sector `index`.
+pub fn otadata_entry(flash: &mut RamFlash, index: u32) -> [u8; 32] {
+    let otadata = find_ota_partition(flash).unwrap();
+    let mut buffer = [0; 32];
+    flash
+        .read(otadata.offset + index * 0x1000, &mut buffer)
+        .unwrap();
+    buffer
+}
+
+pub fn read_partition(flash: &mut RamFlash, name: &str, len: usize) -> Vec<u8> {
+    let partition = find_partition_by_name(flash, name).unwrap();
+    let mut buffer = vec![0; len];
+    flash.read(partition.offset, &mut buffer).unwrap();
+    buffer
+}
+
+/// Deterministic, valid ESP app image of at least `len` bytes.
+pub fn test_image(len: usize) -> Vec<u8> {
+    let data_len = (len.saturating_sub(65) + 3) & !3;
+    let data: Vec<u8> = (0..data_len).map(|i| (i * 7 + i / 251) as u8).collect();
+    esp_image(ChipId::ESP32C3, &[&data])
+}
+
+/// Builds an app image the way esptool does, with an appended SHA-256.
+pub fn esp_image(chip_id: ChipId, segments: &[&[u8]]) -> Vec<u8> {
+    let mut image = vec![0xE9, segments.len() as u8, 0x02, 0x20];
+    image.extend_from_slice(&0x4038_0000u32.to_le_bytes());
+    let mut extended_header = [0; 16];
+    extended_header[0] = 0xEE;
+    extended_header[4..6].copy_from_slice(&chip_id.0.to_le_bytes());
+    extended_header[15] = 1;
+    image.extend_from_slice(&extended_header);
+
+    let mut checksum = 0xEF;
+    for (index, segment) in segments.iter().enumerate() {
+        image.extend_from_slice(&(0x3C00_0000 + index as u32 * 0x10000).to_le_bytes());
+        image.extend_from_slice(&(segment.len() as u32).to_le_bytes());
+        image.extend_from_slice(segment);
+        checksum = segment.iter().fold(checksum, |acc, b| acc ^ b);
+    }
+    while image.len() % 16 != 15 {
+        image.push(0);
+    }
+    image.push(checksum);
+    let digest = Sha256::digest(&image);
+    image.extend_from_slice(&digest);
+    image
+}
+
+/// Two slot layout plus an `otaresume` partition.
+pub fn resumable_flash_with_state(seq: u32, state: AppOTAState) -> RamFlash {
+    let mut flash = RamFlash::builder(0x10000 + 2 * APP_SIZE)
+        .nvs(0x9000, 0x4000)
+        .otadata(0xD000, 0x2000)
+        .partition(RESUME_PARTITION_NAME, 0x01, 0x06, 0xF000, 0x1000)
+        .ota(0, 0x10000, APP_SIZE)
+        .ota(1, 0x10000 + APP_SIZE as u32, APP_SIZE)
+        .build();
+    write_upgrade_info(&mut flash, seq, state);
+    flash
+}
+
+/// Reader that fails ("drops the connection") after `fail_at` bytes.
+pub struct FlakyReader<'a> {
+    pub data: &'a [u8],
+    pub position: usize,
+    pub fail_at: usize,
+}
+
+impl embedded_io_async::ErrorType for FlakyReader<'_> {
+    type Error = embedded_io::ErrorKind;
+}
+
+impl embedded_io_async::Read for FlakyReader<'_> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        if self.position >= self.fail_at {
+            return Err(embedded_io::ErrorKind::ConnectionReset);
+        }
+        let end = self
+            .data
+            .len()
+            .min(self.fail_at)
+            .min(self.position + buf.len());
+        let len = end - self.position;
+        buf[..len].copy_from_slice(&self.data[self.position..end]);
+        self.position = end;
+        Ok(len)
+    }
+}
+
+/// Two slot layout plus an `otareport` partition.
+pub fn reporting_flash() -> RamFlash {
+    let mut flash = RamFlash::builder(0x10000 + 2 * APP_SIZE)
+        .nvs(0x9000, 0x2000)
+        .partition(REPORT_PARTITION_NAME, 0x01, 0x06, 0xB000, 0x2000)
+        .otadata(0xD000, 0x2000)
+        .ota(0, 0x10000, APP_SIZE)
+        .ota(1, 0x10000 + APP_SIZE as u32, APP_SIZE)
+        .build();
+    write_upgrade_info(&mut flash, 1, AppOTAState::Valid);
+    flash
+}
+
+/// A connection that never delivers anything.
+pub struct Stalled;
+
+impl embedded_io_async::ErrorType for Stalled {
+    type Error = core::convert::Infallible;
+}
+
+impl embedded_io_async::Read for Stalled {
+    async fn read(&mut self, _buf: &mut [u8]) -> Result<usize, Self::Error> {
+        core::future::pending().await
+    }
+}
+
+/// `esp_app_desc_t` for `version`, as the IDF build fills it in.
+pub fn app_desc(version: &str) -> Vec<u8> {
+    let mut desc = vec![0; 256];
+    desc[0..4].copy_from_slice(&0xABCD_5432u32.to_le_bytes());
+    desc[4..8].copy_from_slice(&2u32.to_le_bytes());
+    desc[16..16 + version.len()].copy_from_slice(version.as_bytes());
+    desc[48..57].copy_from_slice(b"botifarm\0");
+    desc[80..88].copy_from_slice(b"12:34:56");
+    desc[96..107].copy_from_slice(b"Oct 17 2026");
+    desc[112..118].copy_from_slice(b"v5.3.1");
+    desc[144..176].copy_from_slice(&[0x5A; 32]);
+    desc
+}
+
+/// Valid app image whose first segment carries an app descriptor for `version`.
+pub fn app_image(version: &str) -> Vec<u8> {
+    esp_image(ChipId::ESP32C3, &[&app_desc(version), &[0x42; 1000]])
+}
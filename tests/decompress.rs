@@ -0,0 +1,267 @@
+mod common;
+
+use botifactory_ota_nostd::{
+    save_new_fw_with_options, AppOTAState, BotifactoryClient, Decompressor, Encoding, SaveOptions,
+    UpgradeError, UpgradeInfo,
+};
+use common::http::{header, FakeServer};
+use common::{flash_with_state, read_partition, save_lock, test_image};
+use embassy_futures::block_on;
+use embedded_io_async::Read;
+use reqwless::client::HttpClient;
+
+/// Compressed with `zlib.compressobj(9, zlib.DEFLATED, 9)`, a 512 byte window.
+const SMALL_WINDOW_ZLIB: [u8; 124] = [
+    0x18, 0xd3, 0xed, 0xc7, 0xc7, 0x0d, 0x03, 0x21, 0x10, 0x00, 0xc0, 0x56, 0x2e, 0xd7, 0x05, 0x0b,
+    0x1c, 0x26, 0x07, 0x13, 0xce, 0x49, 0x6e, 0xdd, 0x15, 0x6c, 0x01, 0x96, 0x98, 0xdf, 0x40, 0x28,
+    0xea, 0xa4, 0xac, 0x84, 0xfc, 0x98, 0x42, 0xa1, 0x13, 0x8c, 0xa3, 0xf7, 0x95, 0xcc, 0xcc, 0xd7,
+    0x9b, 0x24, 0x50, 0xfd, 0xfd, 0x9a, 0xc7, 0xf1, 0xbb, 0xf8, 0x5a, 0x5c, 0x63, 0x0b, 0x71, 0xcd,
+    0x70, 0x46, 0xdb, 0x38, 0x7e, 0x01, 0xa4, 0xdb, 0xf4, 0x5c, 0x6d, 0x87, 0x95, 0xda, 0xae, 0xc7,
+    0xf1, 0x9f, 0x26, 0x3a, 0x10, 0x32, 0x9a, 0xf6, 0xd9, 0x4c, 0x14, 0xdb, 0x38, 0x7e, 0x9d, 0xf8,
+    0x2e, 0x75, 0xb2, 0x8c, 0x9f, 0x49, 0xf7, 0xf7, 0x3e, 0x8e, 0x5f, 0x95, 0xef, 0xa1, 0xb2, 0x3c,
+    0xb8, 0xca, 0x81, 0x48, 0x91, 0xff, 0xf5, 0x3f, 0x6b, 0x23, 0x05, 0x0c,
+];
+
+fn small_window_data() -> Vec<u8> {
+    (0..2000)
+        .map(|i| b"botifactory ota "[i % 16] ^ (i / 300) as u8)
+        .collect()
+}
+
+/// Greedy heatshrink encoder, the same bit layout as the reference one.
+fn heatshrink(data: &[u8], window_sz2: u8, lookahead_sz2: u8) -> Vec<u8> {
+    let mut bits = Vec::new();
+    let mut push = |value: usize, count: u8| {
+        for bit in (0..count).rev() {
+            bits.push((value >> bit) & 1 == 1);
+        }
+    };
+    let mut position = 0;
+    while position < data.len() {
+        let max_len = (1 << lookahead_sz2).min(data.len() - position);
+        let (distance, len) = (1..=position.min(1 << window_sz2))
+            .map(|distance| {
+                let len = (0..max_len)
+                    .take_while(|i| data[position - distance + i] == data[position + i])
+                    .count();
+                (distance, len)
+            })
+            .max_by_key(|(_, len)| *len)
+            .unwrap_or((0, 0));
+        if len >= 2 {
+            push(0, 1);
+            push(distance - 1, window_sz2);
+            push(len - 1, lookahead_sz2);
+            position += len;
+        } else {
+            push(1, 1);
+            push(data[position] as usize, 8);
+            position += 1;
+        }
+    }
+    bits.chunks(8)
+        .map(|byte| {
+            byte.iter()
+                .enumerate()
+                .fold(0, |acc, (i, bit)| acc | (u8::from(*bit) << (7 - i)))
+        })
+        .collect()
+}
+
+/// Decompresses all of `payload`, reading `chunk` bytes at a time.
+fn decompress_all(payload: &[u8], encoding: Encoding, chunk: usize) -> Vec<u8> {
+    let mut decompressor = Decompressor::new(payload, encoding).unwrap();
+    let mut out = Vec::new();
+    let mut buffer = vec![0; chunk];
+    loop {
+        let len = block_on(decompressor.read(&mut buffer)).unwrap();
+        if len == 0 {
+            return out;
+        }
+        out.extend_from_slice(&buffer[..len]);
+    }
+}
+
+#[test]
+fn parses_content_encoding() {
+    assert_eq!(
+        Encoding::from_content_encoding("identity"),
+        Some(Encoding::Identity)
+    );
+    assert_eq!(
+        Encoding::from_content_encoding(" deflate"),
+        Some(Encoding::Deflate)
+    );
+    assert_eq!(
+        Encoding::from_content_encoding("heatshrink"),
+        Some(Encoding::Heatshrink {
+            window_sz2: 8,
+            lookahead_sz2: 4
+        })
+    );
+    assert_eq!(
+        Encoding::from_content_encoding("heatshrink-11-4"),
+        Some(Encoding::Heatshrink {
+            window_sz2: 11,
+            lookahead_sz2: 4
+        })
+    );
+    assert_eq!(Encoding::from_content_encoding("heatshrink-4-4"), None);
+    assert_eq!(Encoding::from_content_encoding("gzip"), None);
+}
+
+#[test]
+fn inflates_small_window_stream() {
+    for chunk in [1, 7, 4096] {
+        assert_eq!(
+            decompress_all(&SMALL_WINDOW_ZLIB, Encoding::Deflate, chunk),
+            small_window_data()
+        );
+    }
+}
+
+#[test]
+fn decodes_heatshrink_stream() {
+    // Literal 'a', then 3 bytes from 1 back, padded with zero bits
+    let encoding = Encoding::from_content_encoding("heatshrink-8-4").unwrap();
+    assert_eq!(decompress_all(&[0xB0, 0x80, 0x08], encoding, 16), b"aaaa");
+
+    let data = small_window_data();
+    let encoding = Encoding::Heatshrink {
+        window_sz2: 10,
+        lookahead_sz2: 5,
+    };
+    let compressed = heatshrink(&data, 10, 5);
+    assert!(compressed.len() < data.len() / 2);
+    for chunk in [1, 33, 4096] {
+        assert_eq!(decompress_all(&compressed, encoding, chunk), data);
+    }
+}
+
+#[test]
+fn saves_deflate_compressed_fw() {
+    let _lock = save_lock();
+    let mut flash = flash_with_state(1, AppOTAState::Valid);
+    let image = test_image(30_000);
+    let compressed = miniz_oxide::deflate::compress_to_vec_zlib(&image, 6);
+    assert!(compressed.len() < image.len());
+
+    let options = SaveOptions {
+        encoding: Encoding::Deflate,
+        ..Default::default()
+    };
+    block_on(save_new_fw_with_options(
+        &mut flash,
+        compressed.as_slice(),
+        options,
+    ))
+    .unwrap();
+
+    assert_eq!(read_partition(&mut flash, "ota_1", image.len()), image);
+    assert_eq!(UpgradeInfo::from_flash(&mut flash).unwrap().seq, 2);
+}
+
+#[test]
+fn saves_heatshrink_compressed_fw() {
+    let _lock = save_lock();
+    let mut flash = flash_with_state(1, AppOTAState::Valid);
+    let image = test_image(10_000);
+    let compressed = heatshrink(&image, 8, 4);
+
+    let options = SaveOptions {
+        encoding: Encoding::Heatshrink {
+            window_sz2: 8,
+            lookahead_sz2: 4,
+        },
+        ..Default::default()
+    };
+    block_on(save_new_fw_with_options(
+        &mut flash,
+        compressed.as_slice(),
+        options,
+    ))
+    .unwrap();
+
+    assert_eq!(read_partition(&mut flash, "ota_1", image.len()), image);
+    assert_eq!(UpgradeInfo::from_flash(&mut flash).unwrap().seq, 2);
+}
+
+#[test]
+fn corrupt_deflate_stream_is_rejected() {
+    let _lock = save_lock();
+    let mut flash = flash_with_state(1, AppOTAState::Valid);
+    let mut compressed = miniz_oxide::deflate::compress_to_vec_zlib(&test_image(10_000), 6);
+    let len = compressed.len();
+    compressed[len - 2] ^= 0xFF;
+
+    let options = SaveOptions {
+        encoding: Encoding::Deflate,
+        ..Default::default()
+    };
+    let result = block_on(save_new_fw_with_options(
+        &mut flash,
+        compressed.as_slice(),
+        options,
+    ));
+
+    assert!(matches!(result, Err(UpgradeError::InvalidCompressedData)));
+    assert_eq!(UpgradeInfo::from_flash(&mut flash).unwrap().seq, 1);
+}
+
+#[test]
+fn compressed_download_cant_resume() {
+    let _lock = save_lock();
+    let mut flash = flash_with_state(1, AppOTAState::Valid);
+
+    let options = SaveOptions {
+        release: Some("1.2.3"),
+        resume_from: 4096,
+        encoding: Encoding::Deflate,
+        ..Default::default()
+    };
+    let result = block_on(save_new_fw_with_options(&mut flash, &[][..], options));
+
+    assert!(matches!(result, Err(UpgradeError::InvalidState)));
+}
+
+const BINARY: &str = "http://ota.example.com/bot/stable/latest/binary";
+
+#[test]
+fn plain_download_doesnt_offer_compression() {
+    let _lock = save_lock();
+    let mut flash = flash_with_state(1, AppOTAState::Valid);
+    let image = test_image(10_000);
+    let server = FakeServer::default();
+    server.respond("200 OK", &[], &image);
+
+    let mut client = BotifactoryClient::new(BINARY.to_string(), HttpClient::new(&server, &server));
+    block_on(client.read_binary_resumable(&mut flash, "1.2.3")).unwrap();
+
+    assert_eq!(read_partition(&mut flash, "ota_1", image.len()), image);
+    assert_eq!(
+        header(&server.requests()[0], "accept-encoding"),
+        Some("identity")
+    );
+}
+
+#[test]
+fn compression_is_offered_when_asked_for() {
+    let _lock = save_lock();
+    let mut flash = flash_with_state(1, AppOTAState::Valid);
+    let image = test_image(10_000);
+    let compressed = miniz_oxide::deflate::compress_to_vec_zlib(&image, 6);
+    let server = FakeServer::default();
+    server.respond("200 OK", &[("content-encoding", "deflate")], &compressed);
+
+    let mut client = BotifactoryClient::new(BINARY.to_string(), HttpClient::new(&server, &server));
+    let options = SaveOptions {
+        encoding: Encoding::Deflate,
+        ..Default::default()
+    };
+    block_on(client.read_binary_with_options(&mut flash, options)).unwrap();
+
+    assert_eq!(read_partition(&mut flash, "ota_1", image.len()), image);
+    let requests = server.requests();
+    let offered = header(&requests[0], "accept-encoding").unwrap();
+    assert!(offered.contains("deflate"));
+}
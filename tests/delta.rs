@@ -0,0 +1,211 @@
+mod common;
+
+use botifactory_ota_nostd::{
+    find_partition_by_name, save_delta_fw, save_delta_fw_with_options, save_new_fw, AppOTAState,
+    RamFlash, SaveOptions, UpgradeError, UpgradeInfo, PATCH_MAGIC,
+};
+use common::{flash_with_state, read_partition, save_lock, test_image, Stalled, APP_SIZE};
+use core::convert::Infallible;
+use core::future::Future;
+use core::pin::pin;
+use core::task::{Context, Waker};
+use embassy_futures::block_on;
+use embedded_io_async::{ErrorType, Read};
+use embedded_storage::nor_flash::NorFlash;
+use sha2::{Digest, Sha256};
+
+/// One patch record: bytes added to the old image, bytes copied as they
+/// are, then how far to move in the old image.
+struct Record<'a> {
+    diff: &'a [u8],
+    extra: &'a [u8],
+    seek: i32,
+}
+
+fn patch(new_len: usize, records: &[Record]) -> Vec<u8> {
+    let mut patch = PATCH_MAGIC.to_vec();
+    patch.extend_from_slice(&(new_len as u32).to_le_bytes());
+    for record in records {
+        patch.extend_from_slice(&(record.diff.len() as u32).to_le_bytes());
+        patch.extend_from_slice(&(record.extra.len() as u32).to_le_bytes());
+        patch.extend_from_slice(&record.seek.to_le_bytes());
+        patch.extend_from_slice(record.diff);
+        patch.extend_from_slice(record.extra);
+    }
+    patch
+}
+
+/// Encodes `new` as blocks taken from `old` at the positions in `sources`,
+/// with whatever `new` has past the last block as extra bytes.
+fn encode(old: &[u8], new: &[u8], block: usize, sources: &[usize]) -> Vec<u8> {
+    let diffs: Vec<Vec<u8>> = sources
+        .iter()
+        .enumerate()
+        .map(|(index, source)| {
+            (0..block)
+                .map(|i| new[index * block + i].wrapping_sub(old[source + i]))
+                .collect()
+        })
+        .collect();
+    let tail = &new[sources.len() * block..];
+    // Patching starts at the beginning of the old image
+    let mut records = vec![Record {
+        diff: &[],
+        extra: &[],
+        seek: sources[0] as i32,
+    }];
+    records.extend(diffs.iter().enumerate().map(|(index, diff)| {
+        let next = sources.get(index + 1).copied().unwrap_or(0);
+        Record {
+            diff,
+            extra: if index + 1 == sources.len() {
+                tail
+            } else {
+                &[]
+            },
+            seek: next as i32 - (sources[index] + block) as i32,
+        }
+    }));
+    patch(new.len(), &records)
+}
+
+/// Running firmware `old` in ota_0.
+fn flash_running(old: &[u8]) -> RamFlash {
+    let mut flash = flash_with_state(1, AppOTAState::Valid);
+    let ota_0 = find_partition_by_name(&mut flash, "ota_0").unwrap();
+    flash.write(ota_0.offset, old).unwrap();
+    flash
+}
+
+#[test]
+fn applies_small_change() {
+    let _lock = save_lock();
+    let old = test_image(30_000);
+    let mut new = test_image(34_000);
+    new[100..110].copy_from_slice(&old[100..110]);
+    let mut flash = flash_running(&old);
+
+    let patch = encode(&old, &new, old.len(), &[0]);
+    block_on(save_delta_fw(&mut flash, patch.as_slice())).unwrap();
+
+    assert_eq!(read_partition(&mut flash, "ota_1", new.len()), new);
+    let info = UpgradeInfo::from_flash(&mut flash).unwrap();
+    assert_eq!((info.seq, info.state), (2, AppOTAState::New));
+}
+
+#[test]
+fn seeks_around_old_image() {
+    let _lock = save_lock();
+    let old = test_image(40_000);
+    let new = test_image(20_000);
+    let mut flash = flash_running(&old);
+
+    // Blocks come from all over the old image, forwards and backwards
+    let sources = [30_000, 1, 17_777, 17_000, 5_003, 0];
+    let patch = encode(&old, &new, 3_000, &sources);
+    block_on(save_delta_fw(&mut flash, patch.as_slice())).unwrap();
+
+    assert_eq!(read_partition(&mut flash, "ota_1", new.len()), new);
+}
+
+#[test]
+fn checks_rebuilt_image() {
+    let _lock = save_lock();
+    let old = test_image(10_000);
+    let new = test_image(12_000);
+    let mut flash = flash_running(&old);
+    let patch = encode(&old, &new, old.len(), &[0]);
+
+    let options = SaveOptions {
+        expected_sha256: Some(Sha256::digest(&old).into()),
+        ..Default::default()
+    };
+    let result = block_on(save_delta_fw_with_options(
+        &mut flash,
+        patch.as_slice(),
+        options,
+    ));
+    assert!(matches!(result, Err(UpgradeError::ChecksumMismatch)));
+
+    let options = SaveOptions {
+        expected_sha256: Some(Sha256::digest(&new).into()),
+        ..Default::default()
+    };
+    block_on(save_delta_fw_with_options(
+        &mut flash,
+        patch.as_slice(),
+        options,
+    ))
+    .unwrap();
+    assert_eq!(UpgradeInfo::from_flash(&mut flash).unwrap().seq, 2);
+}
+
+#[test]
+fn rejects_bad_patches() {
+    let _lock = save_lock();
+    let old = test_image(10_000);
+    let new = test_image(12_000);
+    let good = encode(&old, &new, old.len(), &[0]);
+
+    let mut bad_magic = good.clone();
+    bad_magic[0] = b'X';
+    let truncated = good[..good.len() - 100].to_vec();
+    let past_old_image = encode(&vec![0; APP_SIZE + 1000], &new, 1000, &[APP_SIZE]);
+    let past_new_image = patch(
+        10,
+        &[Record {
+            diff: &[0; 20],
+            extra: &[],
+            seek: 0,
+        }],
+    );
+
+    for patch in [bad_magic, truncated, past_old_image, past_new_image] {
+        let mut flash = flash_running(&old);
+        let result = block_on(save_delta_fw(&mut flash, patch.as_slice()));
+        assert!(
+            matches!(result, Err(UpgradeError::InvalidPatch)),
+            "{:?}",
+            result
+        );
+        assert_eq!(UpgradeInfo::from_flash(&mut flash).unwrap().seq, 1);
+    }
+}
+
+/// A patch that mustn't be looked at.
+struct Untouched;
+
+impl ErrorType for Untouched {
+    type Error = Infallible;
+}
+
+impl Read for Untouched {
+    async fn read(&mut self, _buf: &mut [u8]) -> Result<usize, Infallible> {
+        panic!("patch read while another download was running");
+    }
+}
+
+#[test]
+fn refused_before_reading_while_another_download_runs() {
+    let _lock = save_lock();
+    let old = test_image(30_000);
+    let new = test_image(34_000);
+
+    let mut other = flash_with_state(1, AppOTAState::Valid);
+    let mut flash = flash_running(&old);
+    {
+        let mut download = pin!(save_new_fw(&mut other, Stalled));
+        let mut context = Context::from_waker(Waker::noop());
+        assert!(download.as_mut().poll(&mut context).is_pending());
+
+        assert!(matches!(
+            block_on(save_delta_fw(&mut flash, Untouched)),
+            Err(UpgradeError::DLInProgress)
+        ));
+    }
+
+    // Dropping the stalled download lets the next one through
+    let patch = encode(&old, &new, old.len(), &[0]);
+    block_on(save_delta_fw(&mut flash, patch.as_slice())).unwrap();
+    assert_eq!(read_partition(&mut flash, "ota_1", new.len()), new);
+}
@@ -0,0 +1,170 @@
+mod common;
+
+use botifactory_ota_nostd::{
+    encrypt_payload, save_new_fw_with_options, AppOTAState, Cipher, CipherKey, Encoding,
+    EncryptionHeader, FirmwareKey, SaveOptions, UpgradeError, UpgradeInfo, ENCRYPTED_HEADER_SIZE,
+};
+use common::{flash_with_state, read_partition, save_lock, test_image};
+use embassy_futures::block_on;
+
+const NONCE: [u8; 16] = [
+    0xf0, 0xf1, 0xf2, 0xf3, 0xf4, 0xf5, 0xf6, 0xf7, 0xf8, 0xf9, 0xfa, 0xfb, 0xfc, 0xfd, 0xfe, 0xff,
+];
+
+fn keys() -> [FirmwareKey; 3] {
+    [
+        FirmwareKey {
+            id: 1,
+            key: CipherKey::Aes128Ctr([0x11; 16]),
+        },
+        FirmwareKey {
+            id: 2,
+            key: CipherKey::Aes256Ctr([0x22; 32]),
+        },
+        FirmwareKey {
+            id: 3,
+            key: CipherKey::ChaCha20([0x33; 32]),
+        },
+    ]
+}
+
+#[test]
+fn aes_128_ctr_matches_nist_vector() {
+    // NIST SP 800-38A, F.5.1
+    let key = FirmwareKey {
+        id: 0,
+        key: CipherKey::Aes128Ctr([
+            0x2b, 0x7e, 0x15, 0x16, 0x28, 0xae, 0xd2, 0xa6, 0xab, 0xf7, 0x15, 0x88, 0x09, 0xcf,
+            0x4f, 0x3c,
+        ]),
+    };
+    let plaintext = [
+        0x6b, 0xc1, 0xbe, 0xe2, 0x2e, 0x40, 0x9f, 0x96, 0xe9, 0x3d, 0x7e, 0x11, 0x73, 0x93, 0x17,
+        0x2a,
+    ];
+
+    let encrypted = encrypt_payload(&key, NONCE, &plaintext);
+
+    assert_eq!(
+        encrypted[ENCRYPTED_HEADER_SIZE..],
+        [
+            0x87, 0x4d, 0x61, 0x91, 0xb6, 0x20, 0xe3, 0x26, 0x1b, 0xef, 0x68, 0x64, 0x99, 0x0d,
+            0xb6, 0xce
+        ]
+    );
+}
+
+#[test]
+fn header_round_trips() {
+    let encrypted = encrypt_payload(&keys()[2], NONCE, b"firmware");
+    let header: [u8; ENCRYPTED_HEADER_SIZE] =
+        encrypted[..ENCRYPTED_HEADER_SIZE].try_into().unwrap();
+
+    let parsed = EncryptionHeader::try_from(header).unwrap();
+
+    assert_eq!(
+        parsed,
+        EncryptionHeader {
+            cipher: Cipher::ChaCha20,
+            key_id: 3,
+            nonce: NONCE,
+        }
+    );
+    assert_eq!(<[u8; ENCRYPTED_HEADER_SIZE]>::from(parsed), header);
+}
+
+#[test]
+fn saves_encrypted_fw_with_each_cipher() {
+    let _lock = save_lock();
+    let keys = keys();
+    let image = test_image(20_000);
+    for key in &keys {
+        let mut flash = flash_with_state(1, AppOTAState::Valid);
+        let encrypted = encrypt_payload(key, NONCE, &image);
+        assert_ne!(encrypted[ENCRYPTED_HEADER_SIZE..], image[..]);
+
+        let options = SaveOptions {
+            decryption_keys: &keys,
+            ..Default::default()
+        };
+        block_on(save_new_fw_with_options(
+            &mut flash,
+            encrypted.as_slice(),
+            options,
+        ))
+        .unwrap();
+
+        assert_eq!(read_partition(&mut flash, "ota_1", image.len()), image);
+        assert_eq!(UpgradeInfo::from_flash(&mut flash).unwrap().seq, 2);
+    }
+}
+
+#[test]
+fn saves_compressed_then_encrypted_fw() {
+    let _lock = save_lock();
+    let keys = keys();
+    let mut flash = flash_with_state(1, AppOTAState::Valid);
+    let image = test_image(20_000);
+    let compressed = miniz_oxide::deflate::compress_to_vec_zlib(&image, 6);
+    let encrypted = encrypt_payload(&keys[1], NONCE, &compressed);
+
+    let options = SaveOptions {
+        encoding: Encoding::Deflate,
+        decryption_keys: &keys,
+        ..Default::default()
+    };
+    block_on(save_new_fw_with_options(
+        &mut flash,
+        encrypted.as_slice(),
+        options,
+    ))
+    .unwrap();
+
+    assert_eq!(read_partition(&mut flash, "ota_1", image.len()), image);
+}
+
+#[test]
+fn unknown_key_is_rejected() {
+    let _lock = save_lock();
+    let mut flash = flash_with_state(1, AppOTAState::Valid);
+    let other_key = FirmwareKey {
+        id: 9,
+        key: CipherKey::Aes128Ctr([0x99; 16]),
+    };
+    let encrypted = encrypt_payload(&other_key, NONCE, &test_image(1000));
+
+    let keys = keys();
+    let options = SaveOptions {
+        decryption_keys: &keys,
+        ..Default::default()
+    };
+    let result = block_on(save_new_fw_with_options(
+        &mut flash,
+        encrypted.as_slice(),
+        options,
+    ));
+
+    assert!(matches!(result, Err(UpgradeError::MissingDecryptionKey(9))));
+    assert_eq!(UpgradeInfo::from_flash(&mut flash).unwrap().seq, 1);
+}
+
+#[test]
+fn plaintext_is_rejected_when_keys_are_set() {
+    let _lock = save_lock();
+    let mut flash = flash_with_state(1, AppOTAState::Valid);
+    let image = test_image(1000);
+
+    let keys = keys();
+    let options = SaveOptions {
+        decryption_keys: &keys,
+        ..Default::default()
+    };
+    let result = block_on(save_new_fw_with_options(
+        &mut flash,
+        image.as_slice(),
+        options,
+    ));
+
+    assert!(matches!(result, Err(UpgradeError::InvalidEncryptedPayload)));
+    assert_eq!(UpgradeInfo::from_flash(&mut flash).unwrap().seq, 1);
+}
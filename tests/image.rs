@@ -0,0 +1,129 @@
+mod common;
+
+use botifactory_ota_nostd::{
+    save_new_fw, save_new_fw_with_options, AppOTAState, ChipId, ImageError, ImageValidator,
+    SaveOptions, UpgradeError, UpgradeInfo,
+};
+use common::{esp_image, flash_with_state, save_lock, test_image};
+use embassy_futures::block_on;
+
+fn validate(image: &[u8], chunk_size: usize) -> Result<(), UpgradeError> {
+    let mut validator = ImageValidator::new(None);
+    for chunk in image.chunks(chunk_size) {
+        validator.update(chunk)?;
+    }
+    validator.finish().map(|_| ())
+}
+
+fn image_error(result: Result<(), UpgradeError>) -> ImageError {
+    match result {
+        Err(UpgradeError::InvalidImage(error)) => error,
+        other => panic!("expected an image error, got {:?}", other),
+    }
+}
+
+#[test]
+fn accepts_valid_image_in_any_chunking() {
+    let image = esp_image(ChipId::ESP32S3, &[&[1, 2, 3, 4], &[], &[5; 4000]]);
+    for chunk_size in [1, 7, 16, 4096, image.len()] {
+        validate(&image, chunk_size).unwrap();
+    }
+}
+
+#[test]
+fn parses_header() {
+    let image = test_image(1000);
+    let mut validator = ImageValidator::new(Some(ChipId::ESP32C3));
+    validator.update(&image).unwrap();
+    let header = validator.finish().unwrap();
+
+    assert_eq!(header.segment_count, 1);
+    assert_eq!(header.entry_addr, 0x4038_0000);
+    assert_eq!(header.chip_id, ChipId::ESP32C3);
+    assert!(header.hash_appended);
+}
+
+#[test]
+fn rejects_html_error_page() {
+    let page = b"<html><body>502 Bad Gateway</body></html>";
+    assert_eq!(image_error(validate(page, 64)), ImageError::BadMagic(b'<'));
+}
+
+#[test]
+fn rejects_wrong_chip() {
+    let mut validator = ImageValidator::new(Some(ChipId::ESP32));
+    assert_eq!(
+        image_error(validator.update(&test_image(100))),
+        ImageError::WrongChip {
+            expected: ChipId::ESP32,
+            found: ChipId::ESP32C3
+        }
+    );
+}
+
+#[test]
+fn rejects_bad_checksum() {
+    let mut image = esp_image(ChipId::ESP32, &[&[1, 2, 3, 4]]);
+    // Drop the appended hash so the checksum is what trips
+    image[23] = 0;
+    image.truncate(image.len() - 32);
+    image[33] ^= 0xFF;
+
+    assert!(matches!(
+        image_error(validate(&image, 16)),
+        ImageError::BadChecksum { .. }
+    ));
+}
+
+#[test]
+fn rejects_bad_appended_hash() {
+    let mut image = test_image(1000);
+    let last = image.len() - 1;
+    image[last] ^= 0x01;
+
+    assert_eq!(image_error(validate(&image, 100)), ImageError::BadHash);
+}
+
+#[test]
+fn rejects_truncated_image() {
+    let image = test_image(1000);
+    assert_eq!(
+        image_error(validate(&image[..500], 100)),
+        ImageError::Truncated
+    );
+}
+
+#[test]
+fn save_new_fw_refuses_invalid_image() {
+    let _lock = save_lock();
+    let mut flash = flash_with_state(1, AppOTAState::Valid);
+
+    let result = block_on(save_new_fw(&mut flash, &b"<html>Not Found</html>"[..]));
+
+    assert!(matches!(result, Err(UpgradeError::InvalidImage(_))));
+    let info = UpgradeInfo::from_flash(&mut flash).unwrap();
+    assert_eq!(info.seq, 1);
+    assert_eq!(info.state, AppOTAState::Valid);
+}
+
+#[test]
+fn save_new_fw_checks_chip_id() {
+    let _lock = save_lock();
+    let mut flash = flash_with_state(1, AppOTAState::Valid);
+    let options = SaveOptions {
+        chip_id: Some(ChipId::ESP32S3),
+        ..Default::default()
+    };
+
+    let result = block_on(save_new_fw_with_options(
+        &mut flash,
+        test_image(1000).as_slice(),
+        options,
+    ));
+
+    assert!(matches!(
+        result,
+        Err(UpgradeError::InvalidImage(ImageError::WrongChip { .. }))
+    ));
+    assert_eq!(UpgradeInfo::from_flash(&mut flash).unwrap().seq, 1);
+}
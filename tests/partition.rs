@@ -0,0 +1,117 @@
+mod common;
+
+use botifactory_ota_nostd::{
+    count_ota_slots, find_inactive_partition, find_running_partition, running_slot, save_new_fw,
+    save_new_fw_with_options, seq_for_slot, AppOTAState, RamFlash, SaveOptions, UpgradeError,
+    UpgradeInfo,
+};
+use common::{read_partition, save_lock, test_image, write_upgrade_info, APP_SIZE};
+use embassy_futures::block_on;
+
+/// Layout with `ota_0`, `ota_1` and `ota_2`.
+fn three_slot_flash(seq: u32) -> RamFlash {
+    let mut flash = RamFlash::builder(0x10000 + 3 * APP_SIZE)
+        .nvs(0x9000, 0x4000)
+        .otadata(0xD000, 0x2000)
+        .ota(0, 0x10000, APP_SIZE)
+        .ota(1, 0x10000 + APP_SIZE as u32, APP_SIZE)
+        .ota(2, 0x10000 + 2 * APP_SIZE as u32, APP_SIZE)
+        .build();
+    write_upgrade_info(&mut flash, seq, AppOTAState::Valid);
+    flash
+}
+
+#[test]
+fn maps_seq_like_the_bootloader() {
+    assert_eq!(running_slot(1, 2), 0);
+    assert_eq!(running_slot(2, 2), 1);
+    assert_eq!(running_slot(3, 3), 2);
+    assert_eq!(running_slot(4, 3), 0);
+
+    for seq in 1..20 {
+        for slot in 0..3 {
+            let new_seq = seq_for_slot(seq, slot, 3);
+            assert!(new_seq > seq && new_seq <= seq + 3);
+            assert_eq!(running_slot(new_seq, 3), slot);
+        }
+    }
+}
+
+#[test]
+fn finds_partitions_in_three_slot_table() {
+    let mut flash = three_slot_flash(3);
+    assert_eq!(count_ota_slots(&mut flash).unwrap(), 3);
+    assert_eq!(
+        find_running_partition(&mut flash, 3).unwrap().name(),
+        "ota_2"
+    );
+    assert_eq!(
+        find_inactive_partition(&mut flash, 3).unwrap().name(),
+        "ota_0"
+    );
+}
+
+#[test]
+fn updates_cycle_through_all_slots() {
+    let _lock = save_lock();
+    let mut flash = three_slot_flash(1);
+    for (expected_seq, slot) in [(2, "ota_1"), (3, "ota_2"), (4, "ota_0")] {
+        let image = test_image(1000 * expected_seq as usize);
+        block_on(save_new_fw(&mut flash, image.as_slice())).unwrap();
+
+        assert_eq!(read_partition(&mut flash, slot, image.len()), image);
+        assert_eq!(
+            UpgradeInfo::from_flash(&mut flash).unwrap().seq,
+            expected_seq
+        );
+        write_upgrade_info(&mut flash, expected_seq, AppOTAState::Valid);
+    }
+}
+
+#[test]
+fn writes_chosen_slot() {
+    let _lock = save_lock();
+    // Running ota_0, keep ota_1 as a known-good image and update ota_2
+    let mut flash = three_slot_flash(1);
+    let image = test_image(5000);
+    let options = SaveOptions {
+        target_slot: Some(2),
+        ..Default::default()
+    };
+
+    block_on(save_new_fw_with_options(
+        &mut flash,
+        image.as_slice(),
+        options,
+    ))
+    .unwrap();
+
+    assert_eq!(read_partition(&mut flash, "ota_2", image.len()), image);
+    assert!(read_partition(&mut flash, "ota_1", 16)
+        .iter()
+        .all(|b| *b == 0xFF));
+    let info = UpgradeInfo::from_flash(&mut flash).unwrap();
+    assert_eq!(info.seq, 3);
+    assert_eq!(running_slot(info.seq, 3), 2);
+}
+
+#[test]
+fn refuses_running_or_missing_slot() {
+    let _lock = save_lock();
+    for slot in [0, 3] {
+        let mut flash = three_slot_flash(1);
+        let options = SaveOptions {
+            target_slot: Some(slot),
+            ..Default::default()
+        };
+
+        let result = block_on(save_new_fw_with_options(
+            &mut flash,
+            test_image(100).as_slice(),
+            options,
+        ));
+
+        assert!(matches!(result, Err(UpgradeError::InvalidSlot(s)) if s == slot));
+        assert_eq!(UpgradeInfo::from_flash(&mut flash).unwrap().seq, 1);
+    }
+}
@@ -0,0 +1,201 @@
+//! Replays every erase/write of an otadata or firmware update with power
+//! cut at that point, then checks the flash still boots something sane.
+
+mod common;
+
+use botifactory_ota_nostd::{
+    accept_fw, find_ota_partition, find_running_partition, reject_fw, resume_offset, save_new_fw,
+    save_new_fw_with_options, AppOTAState, FaultFlash, PowerCut, RamFlash, Result, SaveOptions,
+    UpgradeInfo,
+};
+use common::{
+    flash_after_update, flash_with_state, read_partition, resumable_flash_with_state, save_lock,
+    test_image,
+};
+use embassy_futures::block_on;
+use embedded_storage::nor_flash::ReadNorFlash;
+
+const OLD_IMAGE_LEN: usize = 6000;
+const NEW_IMAGE_LEN: usize = 9000;
+
+/// Runs `operation` once to count its flash operations, then once more per
+/// operation and [`PowerCut`] mode with power lost at that point, handing
+/// the "rebooted" flash to `check`.
+fn replay_power_cuts(
+    setup: impl Fn() -> RamFlash,
+    operation: impl Fn(&mut FaultFlash<RamFlash>) -> Result<()>,
+    check: impl Fn(&mut RamFlash),
+) {
+    let mut flash = FaultFlash::new(setup());
+    operation(&mut flash).unwrap();
+    let total_ops = flash.op_count();
+    check(&mut flash.into_inner());
+
+    for op in 0..total_ops {
+        for cut in [PowerCut::Before, PowerCut::Torn] {
+            let mut flash = FaultFlash::new(setup()).cut_power_at(op, cut);
+            assert!(
+                operation(&mut flash).is_err(),
+                "operation survived power cut at op {} ({:?})",
+                op,
+                cut
+            );
+            let mut rebooted = flash.into_inner();
+            check(&mut rebooted);
+        }
+    }
+}
+
+/// Valid otadata entries found in either sector.
+fn valid_entries(flash: &mut RamFlash) -> Vec<UpgradeInfo> {
+    let otadata = find_ota_partition(flash).unwrap();
+    (0..2)
+        .filter_map(|sector| {
+            let mut buffer = [0; 32];
+            flash
+                .read(otadata.offset + sector * 0x1000, &mut buffer)
+                .unwrap();
+            UpgradeInfo::try_from(buffer).ok()
+        })
+        .collect()
+}
+
+fn running_image(flash: &mut RamFlash, seq: u32, len: usize) -> Vec<u8> {
+    let partition = find_running_partition(flash, seq).unwrap();
+    let mut buffer = vec![0; len];
+    flash.read(partition.offset, &mut buffer).unwrap();
+    buffer
+}
+
+fn setup_running_fw() -> RamFlash {
+    let mut flash = flash_with_state(1, AppOTAState::Valid);
+    let ota_0 = botifactory_ota_nostd::find_partition_by_name(&mut flash, "ota_0").unwrap();
+    let start = ota_0.offset as usize;
+    let old_image = old_image();
+    flash.as_bytes_mut()[start..start + old_image.len()].copy_from_slice(&old_image);
+    flash
+}
+
+fn old_image() -> Vec<u8> {
+    test_image(OLD_IMAGE_LEN).iter().map(|b| !b).collect()
+}
+
+#[test]
+fn save_new_fw_survives_power_loss() {
+    let _lock = save_lock();
+    let new_image = test_image(NEW_IMAGE_LEN);
+
+    replay_power_cuts(
+        setup_running_fw,
+        |flash| block_on(save_new_fw(flash, new_image.as_slice())),
+        |flash| {
+            let info = UpgradeInfo::from_flash(flash).expect("otadata lost");
+            match info.seq {
+                1 => {
+                    assert_eq!(info.state, AppOTAState::Valid);
+                    assert_eq!(running_image(flash, 1, old_image().len()), old_image());
+                }
+                2 => {
+                    assert_eq!(info.state, AppOTAState::New);
+                    assert_eq!(running_image(flash, 2, new_image.len()), new_image);
+                }
+                seq => panic!("unexpected seq {}", seq),
+            }
+
+            // The bootloader considers every valid entry, not just the one we pick
+            for entry in valid_entries(flash) {
+                match entry.seq {
+                    1 => assert_eq!(running_image(flash, 1, old_image().len()), old_image()),
+                    2 => assert_eq!(running_image(flash, 2, new_image.len()), new_image),
+                    seq => panic!("unexpected seq {}", seq),
+                }
+            }
+        },
+    );
+}
+
+#[test]
+fn resume_progress_survives_power_loss() {
+    let _lock = save_lock();
+    let new_image = test_image(NEW_IMAGE_LEN);
+    replay_power_cuts(
+        || resumable_flash_with_state(1, AppOTAState::Valid),
+        |flash| {
+            let options = SaveOptions {
+                release: Some("2.0.0"),
+                ..Default::default()
+            };
+            block_on(save_new_fw_with_options(
+                flash,
+                new_image.as_slice(),
+                options,
+            ))
+        },
+        |flash| {
+            let info = UpgradeInfo::from_flash(flash).expect("otadata lost");
+            if info.seq == 1 {
+                // Whatever progress survived has to describe what's really in flash
+                let offset = resume_offset(flash, "2.0.0").unwrap() as usize;
+                assert_eq!(read_partition(flash, "ota_1", offset), new_image[..offset]);
+            } else {
+                assert_eq!(running_image(flash, info.seq, new_image.len()), new_image);
+            }
+        },
+    );
+}
+
+#[test]
+fn accept_fw_survives_power_loss() {
+    replay_power_cuts(
+        || flash_after_update(2, AppOTAState::PendingVerify),
+        accept_fw,
+        |flash| {
+            let info = UpgradeInfo::from_flash(flash).expect("otadata lost");
+            match info.seq {
+                // A torn rewrite of the active entry falls back to the previous one
+                1 => assert_eq!(info.state, AppOTAState::Valid),
+                2 => assert!(matches!(
+                    info.state,
+                    AppOTAState::PendingVerify | AppOTAState::Valid
+                )),
+                seq => panic!("unexpected seq {}", seq),
+            }
+        },
+    );
+}
+
+#[test]
+fn reject_fw_survives_power_loss() {
+    replay_power_cuts(
+        || flash_after_update(2, AppOTAState::New),
+        reject_fw,
+        |flash| {
+            let info = UpgradeInfo::from_flash(flash).expect("otadata lost");
+            match info.seq {
+                1 => assert_eq!(info.state, AppOTAState::Valid),
+                2 => assert_eq!(info.state, AppOTAState::New),
+                seq => panic!("unexpected seq {}", seq),
+            }
+        },
+    );
+}
+
+#[test]
+fn save_to_flash_survives_power_loss() {
+    replay_power_cuts(
+        || flash_with_state(7, AppOTAState::Valid),
+        |flash| {
+            let mut info = UpgradeInfo::new(8, [0xFF; 20]);
+            info.state = AppOTAState::New;
+            info.save_to_flash(flash)
+        },
+        |flash| {
+            let info = UpgradeInfo::from_flash(flash).expect("otadata lost");
+            match info.seq {
+                7 => assert_eq!(info.state, AppOTAState::Valid),
+                8 => assert_eq!(info.state, AppOTAState::New),
+                seq => panic!("unexpected seq {}", seq),
+            }
+        },
+    );
+}
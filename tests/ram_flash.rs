@@ -0,0 +1,73 @@
+use botifactory_ota_nostd::{
+    find_ota_partition, find_partition_by_name, find_partition_by_type, RamFlash, RamFlashError,
+};
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+use esp_partition_table::{AppPartitionType, PartitionType};
+
+#[test]
+fn new_flash_is_erased() {
+    let mut flash: RamFlash = RamFlash::new(0x2000);
+    let mut buffer = [0; 16];
+    flash.read(0x1000, &mut buffer).unwrap();
+    assert_eq!(buffer, [0xFF; 16]);
+}
+
+#[test]
+fn write_can_only_clear_bits() {
+    let mut flash: RamFlash = RamFlash::new(0x1000);
+    flash.write(0, &[0b1010_1010]).unwrap();
+    flash.write(0, &[0b1000_1000]).unwrap();
+    assert_eq!(
+        flash.write(0, &[0b1111_1111]),
+        Err(RamFlashError::NotErased { offset: 0 })
+    );
+    assert_eq!(flash.as_bytes()[0], 0b1000_1000);
+
+    flash.erase(0, 0x1000).unwrap();
+    flash.write(0, &[0x55]).unwrap();
+    assert_eq!(flash.as_bytes()[0], 0x55);
+}
+
+#[test]
+fn erase_must_be_sector_aligned() {
+    let mut flash: RamFlash = RamFlash::new(0x2000);
+    assert_eq!(flash.erase(0x10, 0x1000), Err(RamFlashError::NotAligned));
+    assert_eq!(flash.erase(0, 0x800), Err(RamFlashError::NotAligned));
+    assert_eq!(flash.erase(0, 0x3000), Err(RamFlashError::OutOfBounds));
+    flash.erase(0x1000, 0x2000).unwrap();
+}
+
+#[test]
+fn write_size_is_enforced() {
+    let mut flash: RamFlash<4, 0x1000> = RamFlash::new(0x1000);
+    assert_eq!(flash.write(2, &[0; 4]), Err(RamFlashError::NotAligned));
+    assert_eq!(flash.write(0, &[0; 3]), Err(RamFlashError::NotAligned));
+    flash.write(4, &[0; 8]).unwrap();
+}
+
+#[test]
+fn builder_lays_out_partition_table() {
+    let mut flash: RamFlash = RamFlash::builder(0x40000)
+        .nvs(0x9000, 0x5000)
+        .otadata(0xE000, 0x2000)
+        .ota(0, 0x10000, 0x10000)
+        .ota(1, 0x20000, 0x10000)
+        .factory(0x30000, 0x10000)
+        .build();
+
+    let otadata = find_ota_partition(&mut flash).unwrap();
+    assert_eq!(otadata.offset, 0xE000);
+    assert_eq!(otadata.size, 0x2000);
+
+    let ota_1 =
+        find_partition_by_type(&mut flash, PartitionType::App(AppPartitionType::Ota(1))).unwrap();
+    assert_eq!(ota_1.name(), "ota_1");
+    assert_eq!(ota_1.offset, 0x20000);
+
+    let nvs = find_partition_by_name(&mut flash, "nvs").unwrap();
+    assert_eq!(nvs.offset, 0x9000);
+    assert_eq!(nvs.size, 0x5000);
+
+    let factory = find_partition_by_name(&mut flash, "factory").unwrap();
+    assert_eq!(factory.type_, PartitionType::App(AppPartitionType::Factory));
+}
@@ -0,0 +1,114 @@
+mod common;
+
+use botifactory_ota_nostd::{
+    is_redirect, origin, resolve_location, same_origin, AppOTAState, BotifactoryClient,
+    Credentials, UpgradeError,
+};
+use common::http::{header, FakeServer};
+use common::{flash_with_state, read_partition, save_lock, test_image};
+use embassy_futures::block_on;
+use reqwless::client::HttpClient;
+
+const BINARY: &str = "https://ota.example.com/bot/stable/latest/binary?x=1";
+
+#[test]
+fn follows_only_redirect_statuses() {
+    for status in [301, 302, 307, 308] {
+        assert!(is_redirect(status));
+    }
+    for status in [200, 206, 300, 303, 304, 404] {
+        assert!(!is_redirect(status));
+    }
+}
+
+#[test]
+fn resolves_locations() {
+    let s3 = "https://bucket.s3.example.com/fw/bot.bin?X-Amz-Signature=abc";
+    assert_eq!(resolve_location(BINARY, s3), s3);
+    assert_eq!(
+        resolve_location(BINARY, "//cdn.example.com/fw.bin"),
+        "https://cdn.example.com/fw.bin"
+    );
+    assert_eq!(
+        resolve_location(BINARY, "/files/42.bin"),
+        "https://ota.example.com/files/42.bin"
+    );
+    assert_eq!(
+        resolve_location(BINARY, "42.bin"),
+        "https://ota.example.com/bot/stable/latest/42.bin"
+    );
+    assert_eq!(
+        resolve_location("https://ota.example.com", "fw.bin"),
+        "https://ota.example.com/fw.bin"
+    );
+    // A URL in the query doesn't make the location absolute
+    assert_eq!(
+        resolve_location(BINARY, "/dl?next=https://x"),
+        "https://ota.example.com/dl?next=https://x"
+    );
+    assert_eq!(
+        resolve_location(BINARY, "dl?next=https://x"),
+        "https://ota.example.com/bot/stable/latest/dl?next=https://x"
+    );
+}
+
+#[test]
+fn credentials_stay_on_the_same_origin() {
+    assert_eq!(origin(BINARY), Some("https://ota.example.com"));
+    assert_eq!(
+        origin("http://10.0.0.2:8000?q"),
+        Some("http://10.0.0.2:8000")
+    );
+    assert_eq!(origin("/relative"), None);
+
+    assert!(same_origin(BINARY, "https://OTA.example.com/files/42.bin"));
+    assert!(!same_origin(BINARY, "https://bucket.s3.example.com/fw.bin"));
+    assert!(!same_origin(BINARY, "http://ota.example.com/files/42.bin"));
+    assert!(!same_origin(
+        BINARY,
+        "https://ota.example.com:8443/files/42.bin"
+    ));
+}
+
+const START: &str = "http://ota.example.com/bot/stable/latest/binary";
+
+#[test]
+fn download_follows_redirect_without_credentials_to_other_hosts() {
+    let _lock = save_lock();
+    let mut flash = flash_with_state(1, AppOTAState::Valid);
+    let image = test_image(10_000);
+    let server = FakeServer::default();
+    server.redirect("/fw/latest.bin");
+    server.redirect("http://cdn.example.com/fw/bot.bin");
+    server.respond("200 OK", &[], &image);
+
+    let mut client = BotifactoryClient::new(START.to_string(), HttpClient::new(&server, &server))
+        .with_credentials(Credentials::Bearer("secret".to_string()));
+    block_on(client.read_binary(&mut flash)).unwrap();
+
+    assert_eq!(read_partition(&mut flash, "ota_1", image.len()), image);
+    let requests = server.requests();
+    assert_eq!(requests.len(), 3);
+    assert!(requests[1].starts_with("GET /fw/latest.bin "));
+    assert_eq!(header(&requests[1], "host"), Some("ota.example.com"));
+    assert_eq!(header(&requests[1], "authorization"), Some("Bearer secret"));
+    assert_eq!(header(&requests[2], "host"), Some("cdn.example.com"));
+    assert_eq!(header(&requests[2], "authorization"), None);
+}
+
+#[test]
+fn download_stops_after_max_redirects() {
+    let _lock = save_lock();
+    let mut flash = flash_with_state(1, AppOTAState::Valid);
+    let server = FakeServer::default();
+    server.redirect("/one");
+    server.redirect("/two");
+
+    let mut client = BotifactoryClient::new(START.to_string(), HttpClient::new(&server, &server))
+        .with_max_redirects(1);
+    assert!(matches!(
+        block_on(client.read_binary(&mut flash)),
+        Err(UpgradeError::TooManyRedirects)
+    ));
+    assert_eq!(server.requests().len(), 2);
+}
@@ -0,0 +1,104 @@
+mod common;
+
+use botifactory_ota_nostd::{parse_sha256, BotifactoryClient, Encoding, Release, UpgradeError};
+use common::http::FakeServer;
+use embassy_futures::block_on;
+use reqwless::client::HttpClient;
+use semver::Version;
+
+const HASH: &str = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+
+#[test]
+fn parses_full_release() {
+    let json = format!(
+        r#"{{"release": {{
+            "id": 42,
+            "version": "1.4.0-rc.1",
+            "size": 123456,
+            "hash": "{HASH}",
+            "download_url": "https:\/\/ota.example.com\/bot\/stable\/42\/binary",
+            "created_at": "2026-10-17T12:00:00Z",
+            "notes": "Fixes \"boot loop\"\nFaster wifi",
+            "encoding": "heatshrink",
+            "channel_id": 7
+        }}}}"#
+    );
+
+    let release = Release::from_json(&json).unwrap();
+
+    assert_eq!(
+        release,
+        Release {
+            version: Version::parse("1.4.0-rc.1").unwrap(),
+            id: Some(42),
+            size: Some(123456),
+            hash: Some(HASH.into()),
+            download_url: Some("https://ota.example.com/bot/stable/42/binary".into()),
+            created_at: Some("2026-10-17T12:00:00Z".into()),
+            notes: Some("Fixes \"boot loop\"\nFaster wifi".into()),
+            encoding: Some("heatshrink".into()),
+        }
+    );
+    assert_eq!(release.sha256().unwrap(), parse_sha256(HASH));
+    assert_eq!(
+        release.encoding().unwrap(),
+        Encoding::Heatshrink {
+            window_sz2: 8,
+            lookahead_sz2: 4
+        }
+    );
+}
+
+#[test]
+fn only_version_is_required() {
+    let release =
+        Release::from_json(r#"{"release": {"version": "2.0.0", "notes": null}}"#).unwrap();
+
+    assert_eq!(release.version, Version::new(2, 0, 0));
+    assert_eq!(release.id, None);
+    assert_eq!(release.hash, None);
+    assert_eq!(release.notes, None);
+    assert_eq!(release.encoding().unwrap(), Encoding::Identity);
+}
+
+#[test]
+fn rejects_bad_metadata() {
+    assert!(matches!(
+        Release::from_json(r#"{"release": {"id": 1}}"#),
+        Err(UpgradeError::SerdeError(_))
+    ));
+}
+
+#[test]
+fn unusable_hash_or_encoding_only_fails_the_download() {
+    let release =
+        Release::from_json(r#"{"release": {"version": "1.0.0", "hash": "abc", "encoding": "br"}}"#)
+            .unwrap();
+
+    assert_eq!(release.version, Version::new(1, 0, 0));
+    assert!(matches!(
+        release.sha256(),
+        Err(UpgradeError::ChecksumMismatch)
+    ));
+    assert!(matches!(
+        release.encoding(),
+        Err(UpgradeError::UnsupportedEncoding)
+    ));
+}
+
+#[test]
+fn oversized_release_is_refused() {
+    let server = FakeServer::default();
+    let notes = "a".repeat(5000);
+    let json = format!(r#"{{"release": {{"version": "2.0.0", "notes": "{notes}"}}}}"#);
+    server.respond("200 OK", &[], json.as_bytes());
+    let mut client = BotifactoryClient::new(
+        "http://ota.example.com/bot/stable/latest".to_string(),
+        HttpClient::new(&server, &server),
+    );
+
+    assert!(matches!(
+        block_on(client.read_release()),
+        Err(UpgradeError::ReleaseTooLarge)
+    ));
+}
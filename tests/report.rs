@@ -0,0 +1,125 @@
+mod common;
+
+use botifactory_ota_nostd::{
+    mark_sent, pending_events, queue_event, AppOTAState, FaultFlash, Outcome, PowerCut,
+    UpdateEvent, UpgradeError,
+};
+use common::{flash_with_state, reporting_flash};
+use semver::Version;
+
+fn event(to_patch: u64) -> UpdateEvent {
+    UpdateEvent::new(
+        Version::new(1, 0, 0),
+        Version::new(1, 1, to_patch),
+        Outcome::Installed,
+    )
+}
+
+#[test]
+fn queued_events_survive_until_sent() {
+    let mut flash = reporting_flash();
+    let failed = UpdateEvent::failed(
+        Version::new(1, 0, 0),
+        Version::parse("1.1.0-rc.1+build.5").unwrap(),
+        &UpgradeError::ChecksumMismatch,
+    );
+
+    queue_event(&mut flash, &event(0)).unwrap();
+    queue_event(&mut flash, &failed).unwrap();
+    queue_event(&mut flash, &event(2)).unwrap();
+
+    let pending = pending_events(&mut flash).unwrap();
+    assert_eq!(pending.len(), 3);
+    assert_eq!(pending[1].event, failed);
+    assert_eq!(pending[1].event.error_code, Some(15));
+
+    mark_sent(&mut flash, pending[1].seq).unwrap();
+
+    let pending = pending_events(&mut flash).unwrap();
+    assert_eq!(pending.len(), 1);
+    assert_eq!(pending[0].event, event(2));
+}
+
+#[test]
+fn full_log_keeps_newest_pending_events() {
+    let mut flash = reporting_flash();
+    for patch in 0..40 {
+        queue_event(&mut flash, &event(patch)).unwrap();
+        if patch == 4 {
+            let pending = pending_events(&mut flash).unwrap();
+            mark_sent(&mut flash, pending.last().unwrap().seq).unwrap();
+        }
+    }
+
+    let pending = pending_events(&mut flash).unwrap();
+    assert_eq!(pending.len(), 31);
+    assert_eq!(pending[0].event, event(9));
+    assert_eq!(pending[30].event, event(39));
+    assert!(pending.windows(2).all(|w| w[0].seq < w[1].seq));
+}
+
+#[test]
+fn compaction_survives_power_cuts() {
+    let mut full = reporting_flash();
+    for patch in 0..31 {
+        queue_event(&mut full, &event(patch)).unwrap();
+    }
+    let before: Vec<_> = (0..31).map(event).collect();
+    let after: Vec<_> = (1..32).map(event).collect();
+
+    let mut flash = FaultFlash::new(full.clone());
+    queue_event(&mut flash, &event(31)).unwrap();
+    let total_ops = flash.op_count();
+
+    for op in 0..total_ops {
+        for cut in [PowerCut::Before, PowerCut::Torn] {
+            let mut flash = FaultFlash::new(full.clone()).cut_power_at(op, cut);
+            assert!(queue_event(&mut flash, &event(31)).is_err());
+            let mut rebooted = flash.into_inner();
+            let pending: Vec<_> = pending_events(&mut rebooted)
+                .unwrap()
+                .into_iter()
+                .map(|queued| queued.event)
+                .collect();
+            assert!(
+                pending == before || pending == after,
+                "lost events with power cut at op {} ({:?})",
+                op,
+                cut
+            );
+        }
+    }
+}
+
+#[test]
+fn missing_partition_queues_nothing() {
+    let mut flash = flash_with_state(1, AppOTAState::Valid);
+
+    queue_event(&mut flash, &event(0)).unwrap();
+
+    assert!(pending_events(&mut flash).unwrap().is_empty());
+}
+
+#[test]
+fn event_serializes_to_json() {
+    let failed = UpdateEvent::failed(
+        Version::new(1, 0, 0),
+        Version::new(1, 1, 0),
+        &UpgradeError::RequestError,
+    );
+
+    assert_eq!(
+        failed.to_json("bot-7").unwrap(),
+        r#"{"device_id":"bot-7","from_version":"1.0.0","to_version":"1.1.0","outcome":"failed","error_code":11}"#
+    );
+    assert_eq!(
+        UpdateEvent::new(
+            Version::new(1, 1, 0),
+            Version::new(1, 0, 0),
+            Outcome::RolledBack
+        )
+        .to_json("bot-7")
+        .unwrap(),
+        r#"{"device_id":"bot-7","from_version":"1.1.0","to_version":"1.0.0","outcome":"rolled_back","error_code":null}"#
+    );
+}
@@ -0,0 +1,248 @@
+mod common;
+
+use botifactory_ota_nostd::{
+    resume_offset, resume_offset_for_slot, save_new_fw, save_new_fw_with_options, AppOTAState,
+    BotifactoryClient, RamFlash, ResumeInfo, SaveOptions, UpgradeError, UpgradeInfo,
+    PARTITION_TABLE_OFFSET, RESUME_PARTITION_NAME,
+};
+use common::http::FakeServer;
+use common::{
+    read_partition, resumable_flash_with_state, save_lock, test_image, write_upgrade_info,
+    FlakyReader, Stalled, APP_SIZE,
+};
+use core::future::Future;
+use core::pin::pin;
+use core::task::{Context, Waker};
+use embassy_futures::block_on;
+use embedded_storage::nor_flash::{ErrorType, NorFlash, ReadNorFlash};
+use reqwless::client::HttpClient;
+use sha2::{Digest, Sha256};
+
+const RELEASE: &str = "1.2.3";
+const BINARY: &str = "http://ota.example.com/bot/stable/latest/binary";
+
+#[test]
+fn interrupted_download_resumes_at_last_sector() {
+    let _lock = save_lock();
+    let mut flash = resumable_flash_with_state(1, AppOTAState::Valid);
+    let image = test_image(30_000);
+    let options = SaveOptions {
+        release: Some(RELEASE),
+        ..Default::default()
+    };
+
+    let reader = FlakyReader {
+        data: &image,
+        position: 0,
+        fail_at: 10_000,
+    };
+    assert!(block_on(save_new_fw_with_options(&mut flash, reader, options)).is_err());
+    assert_eq!(UpgradeInfo::from_flash(&mut flash).unwrap().seq, 1);
+
+    let offset = resume_offset(&mut flash, RELEASE).unwrap();
+    assert_eq!(offset, 8192);
+    assert_eq!(resume_offset(&mut flash, "1.2.4").unwrap(), 0);
+
+    // The digest still covers the part written before the interruption
+    let options = SaveOptions {
+        resume_from: offset,
+        release: Some(RELEASE),
+        expected_sha256: Some(Sha256::digest(&image).into()),
+        ..Default::default()
+    };
+    block_on(save_new_fw_with_options(
+        &mut flash,
+        &image[offset as usize..],
+        options,
+    ))
+    .unwrap();
+
+    assert_eq!(read_partition(&mut flash, "ota_1", image.len()), image);
+    assert_eq!(UpgradeInfo::from_flash(&mut flash).unwrap().seq, 2);
+    assert_eq!(ResumeInfo::from_flash(&mut flash).unwrap(), None);
+}
+
+#[test]
+fn resume_without_matching_progress_is_refused() {
+    let _lock = save_lock();
+    let mut flash = resumable_flash_with_state(1, AppOTAState::Valid);
+    let options = SaveOptions {
+        release: Some(RELEASE),
+        resume_from: 4096,
+        ..Default::default()
+    };
+
+    let result = block_on(save_new_fw_with_options(
+        &mut flash,
+        test_image(100).as_slice(),
+        options,
+    ));
+
+    assert!(matches!(result, Err(UpgradeError::InvalidState)));
+}
+
+#[test]
+fn progress_from_another_seq_is_ignored() {
+    let mut flash = resumable_flash_with_state(3, AppOTAState::Valid);
+    ResumeInfo::new(2, 1, RELEASE, 4096)
+        .save_to_flash(&mut flash)
+        .unwrap();
+
+    assert_eq!(resume_offset(&mut flash, RELEASE).unwrap(), 0);
+}
+
+#[test]
+fn progress_for_another_slot_is_ignored() {
+    let _lock = save_lock();
+    let mut flash = RamFlash::builder(0x10000 + 3 * APP_SIZE)
+        .nvs(0x9000, 0x4000)
+        .otadata(0xD000, 0x2000)
+        .partition(RESUME_PARTITION_NAME, 0x01, 0x06, 0xF000, 0x1000)
+        .ota(0, 0x10000, APP_SIZE)
+        .ota(1, 0x10000 + APP_SIZE as u32, APP_SIZE)
+        .ota(2, 0x10000 + 2 * APP_SIZE as u32, APP_SIZE)
+        .build();
+    write_upgrade_info(&mut flash, 1, AppOTAState::Valid);
+    let image = test_image(30_000);
+    let options = SaveOptions {
+        release: Some(RELEASE),
+        target_slot: Some(2),
+        ..Default::default()
+    };
+    let reader = FlakyReader {
+        data: &image,
+        position: 0,
+        fail_at: 10_000,
+    };
+    assert!(block_on(save_new_fw_with_options(&mut flash, reader, options)).is_err());
+
+    assert_eq!(
+        resume_offset_for_slot(&mut flash, RELEASE, Some(2)).unwrap(),
+        8192
+    );
+    // The default slot is ota_1, which has none of the image
+    assert_eq!(resume_offset(&mut flash, RELEASE).unwrap(), 0);
+    let options = SaveOptions {
+        release: Some(RELEASE),
+        resume_from: 8192,
+        ..Default::default()
+    };
+    let result = block_on(save_new_fw_with_options(
+        &mut flash,
+        &image[8192..],
+        options,
+    ));
+    assert!(matches!(result, Err(UpgradeError::InvalidState)));
+}
+
+#[test]
+fn latest_record_wins_after_log_wraps() {
+    let mut flash = resumable_flash_with_state(1, AppOTAState::Valid);
+    for sector in 1..=200 {
+        ResumeInfo::new(1, 1, RELEASE, sector * 4096)
+            .save_to_flash(&mut flash)
+            .unwrap();
+    }
+
+    assert_eq!(resume_offset(&mut flash, RELEASE).unwrap(), 200 * 4096);
+    ResumeInfo::clear(&mut flash).unwrap();
+    assert_eq!(resume_offset(&mut flash, RELEASE).unwrap(), 0);
+}
+
+#[test]
+fn resume_partition_is_optional() {
+    let mut flash = common::flash_with_state(1, AppOTAState::Valid);
+    ResumeInfo::new(1, 1, RELEASE, 4096)
+        .save_to_flash(&mut flash)
+        .unwrap();
+
+    assert_eq!(resume_offset(&mut flash, RELEASE).unwrap(), 0);
+}
+
+/// Counts how often the partition table is read.
+struct CountingFlash {
+    flash: RamFlash,
+    table_reads: usize,
+}
+
+impl ErrorType for CountingFlash {
+    type Error = <RamFlash as ErrorType>::Error;
+}
+
+impl ReadNorFlash for CountingFlash {
+    const READ_SIZE: usize = <RamFlash>::READ_SIZE;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        if offset == PARTITION_TABLE_OFFSET {
+            self.table_reads += 1;
+        }
+        self.flash.read(offset, bytes)
+    }
+
+    fn capacity(&self) -> usize {
+        self.flash.capacity()
+    }
+}
+
+impl NorFlash for CountingFlash {
+    const WRITE_SIZE: usize = <RamFlash>::WRITE_SIZE;
+    const ERASE_SIZE: usize = <RamFlash>::ERASE_SIZE;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        self.flash.erase(from, to)
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.flash.write(offset, bytes)
+    }
+}
+
+#[test]
+fn partition_table_is_read_once_per_download() {
+    let _lock = save_lock();
+    let mut flash = CountingFlash {
+        flash: resumable_flash_with_state(1, AppOTAState::Valid),
+        table_reads: 0,
+    };
+    let image = test_image(30_000);
+    let options = SaveOptions {
+        release: Some(RELEASE),
+        ..Default::default()
+    };
+
+    block_on(save_new_fw_with_options(
+        &mut flash,
+        image.as_slice(),
+        options,
+    ))
+    .unwrap();
+
+    assert_eq!(flash.table_reads, 1);
+    assert_eq!(
+        read_partition(&mut flash.flash, "ota_1", image.len()),
+        image
+    );
+}
+
+#[test]
+fn resumable_download_waits_for_running_download_before_reading_progress() {
+    let _lock = save_lock();
+    let mut other = resumable_flash_with_state(1, AppOTAState::Valid);
+    let mut flash = CountingFlash {
+        flash: resumable_flash_with_state(1, AppOTAState::Valid),
+        table_reads: 0,
+    };
+    let server = FakeServer::default();
+    let mut client = BotifactoryClient::new(BINARY.to_string(), HttpClient::new(&server, &server));
+
+    let mut download = pin!(save_new_fw(&mut other, Stalled));
+    let mut context = Context::from_waker(Waker::noop());
+    assert!(download.as_mut().poll(&mut context).is_pending());
+
+    assert!(matches!(
+        block_on(client.read_binary_resumable(&mut flash, RELEASE)),
+        Err(UpgradeError::DLInProgress)
+    ));
+    assert_eq!(flash.table_reads, 0);
+    assert!(server.requests().is_empty());
+}
@@ -0,0 +1,206 @@
+mod common;
+
+use botifactory_ota_nostd::{
+    save_new_fw_with_options, AppOTAState, BotifactoryClient, Delay, RetryPolicy, SaveOptions,
+    UpgradeError,
+};
+use common::http::{header, FakeServer};
+use common::{
+    flash_with_state, read_partition, resumable_flash_with_state, save_lock, test_image,
+    FlakyReader,
+};
+use embassy_futures::block_on;
+use reqwless::client::HttpClient;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+const RELEASE_URL: &str = "http://ota.example.com/bot/stable/latest";
+const RELEASE_JSON: &[u8] = br#"{"release": {"version": "2.0.0", "notes": null}}"#;
+
+const POLICY: RetryPolicy = RetryPolicy {
+    max_attempts: 3,
+    initial_backoff_ms: 1000,
+    max_backoff_ms: 1000,
+    jitter_seed: 7,
+    request_timeout_ms: Some(500),
+    idle_timeout_ms: Some(700),
+};
+
+/// Fires as soon as it's polled and keeps what it was asked to wait.
+/// Timeouts poll the request first, so only a stalled one loses to it.
+#[derive(Clone, Default)]
+struct FakeDelay {
+    waits: Rc<RefCell<Vec<u32>>>,
+}
+
+impl FakeDelay {
+    fn waits(&self) -> Vec<u32> {
+        self.waits.borrow().clone()
+    }
+}
+
+impl Delay for FakeDelay {
+    async fn delay_ms(&self, ms: u32) {
+        self.waits.borrow_mut().push(ms);
+    }
+}
+
+#[test]
+fn backoff_doubles_up_to_the_limit() {
+    let policy = RetryPolicy {
+        initial_backoff_ms: 1000,
+        max_backoff_ms: 5000,
+        ..Default::default()
+    };
+
+    // Without jitter it's half the backoff, with the most jitter all of it
+    assert_eq!(policy.backoff_ms(1, 0), 500);
+    assert_eq!(policy.backoff_ms(1, 500), 1000);
+    assert_eq!(policy.backoff_ms(2, 0), 1000);
+    assert_eq!(policy.backoff_ms(3, 2000), 4000);
+    assert_eq!(policy.backoff_ms(4, 0), 2500);
+    assert_eq!(policy.backoff_ms(40, 2500), 5000);
+    for random in [1, 77, 1234, u32::MAX] {
+        assert!((1000..=2000).contains(&policy.backoff_ms(2, random)));
+    }
+    assert_eq!(RetryPolicy::NONE.backoff_ms(1, 1234), 0);
+}
+
+#[test]
+fn only_transient_errors_are_retried() {
+    assert!(UpgradeError::RequestError.is_transient());
+    assert!(UpgradeError::Timeout.is_transient());
+    assert!(!UpgradeError::HttpStatus(404).is_transient());
+    assert!(!UpgradeError::Unauthorized.is_transient());
+    assert!(!UpgradeError::ChecksumMismatch.is_transient());
+    assert!(!UpgradeError::InvalidSignature.is_transient());
+    assert!(!UpgradeError::StorageError.is_transient());
+}
+
+#[test]
+fn broken_off_download_is_transient() {
+    let _lock = save_lock();
+    let mut flash = flash_with_state(1, AppOTAState::Valid);
+    let image = test_image(10_000);
+    let reader = FlakyReader {
+        data: &image,
+        position: 0,
+        fail_at: 5000,
+    };
+
+    let result = block_on(save_new_fw_with_options(
+        &mut flash,
+        reader,
+        SaveOptions::default(),
+    ));
+
+    assert!(matches!(result, Err(UpgradeError::RequestError)));
+}
+
+#[test]
+fn stalled_requests_time_out_until_attempts_run_out() {
+    let server = FakeServer::default();
+    let delay = FakeDelay::default();
+    let mut client =
+        BotifactoryClient::new(RELEASE_URL.to_string(), HttpClient::new(&server, &server))
+            .with_retry(POLICY, delay.clone());
+    for _ in 0..3 {
+        server.stall();
+    }
+
+    assert!(matches!(
+        block_on(client.read_release()),
+        Err(UpgradeError::Timeout)
+    ));
+
+    assert_eq!(server.requests().len(), 3);
+    let waits = delay.waits();
+    assert_eq!(waits.len(), 5);
+    // Timeout, backoff, timeout, backoff, timeout
+    for timeout in [waits[0], waits[2], waits[4]] {
+        assert_eq!(timeout, 500);
+    }
+    for backoff in [waits[1], waits[3]] {
+        assert!((500..=1000).contains(&backoff));
+    }
+}
+
+#[test]
+fn retries_stop_once_a_request_succeeds() {
+    let server = FakeServer::default();
+    let delay = FakeDelay::default();
+    let mut client =
+        BotifactoryClient::new(RELEASE_URL.to_string(), HttpClient::new(&server, &server))
+            .with_retry(POLICY, delay.clone());
+    server.stall();
+    server.respond("200 OK", &[], RELEASE_JSON);
+
+    let release = block_on(client.read_release()).unwrap();
+
+    assert_eq!(release.version.to_string(), "2.0.0");
+    assert_eq!(server.requests().len(), 2);
+    assert_eq!(delay.waits().len(), 2);
+}
+
+#[test]
+fn permanent_errors_are_not_retried() {
+    let server = FakeServer::default();
+    let delay = FakeDelay::default();
+    let mut client =
+        BotifactoryClient::new(RELEASE_URL.to_string(), HttpClient::new(&server, &server))
+            .with_retry(POLICY, delay.clone());
+    server.respond("404 Not Found", &[], b"");
+
+    assert!(matches!(
+        block_on(client.read_release()),
+        Err(UpgradeError::HttpStatus(404))
+    ));
+
+    assert_eq!(server.requests().len(), 1);
+    assert!(delay.waits().is_empty());
+}
+
+#[test]
+fn stalled_release_body_times_out() {
+    let server = FakeServer::default();
+    let delay = FakeDelay::default();
+    let policy = RetryPolicy {
+        max_attempts: 1,
+        ..POLICY
+    };
+    let mut client =
+        BotifactoryClient::new(RELEASE_URL.to_string(), HttpClient::new(&server, &server))
+            .with_retry(policy, delay.clone());
+    server.respond_partially("200 OK", RELEASE_JSON.len(), &RELEASE_JSON[..10]);
+
+    assert!(block_on(client.read_release()).is_err());
+
+    assert_eq!(delay.waits(), [700]);
+}
+
+#[test]
+fn stalled_download_times_out_and_resumes() {
+    let _lock = save_lock();
+    let mut flash = resumable_flash_with_state(1, AppOTAState::Valid);
+    let image = test_image(20_000);
+    let server = FakeServer::default();
+    let delay = FakeDelay::default();
+    let mut client = BotifactoryClient::new(
+        format!("{}/binary", RELEASE_URL),
+        HttpClient::new(&server, &server),
+    )
+    .with_retry(POLICY, delay.clone());
+    server.respond_partially("200 OK", image.len(), &image[..10_000]);
+    let rest = &image[4096 * 2..];
+    let range = format!("bytes {}-{}/{}", 4096 * 2, image.len() - 1, image.len());
+    server.respond("206 Partial Content", &[("content-range", &range)], rest);
+
+    block_on(client.read_binary_resumable(&mut flash, "2.0.0")).unwrap();
+
+    assert_eq!(read_partition(&mut flash, "ota_1", image.len()), image);
+    let waits = delay.waits();
+    assert_eq!((waits.len(), waits[0]), (2, 700));
+    let requests = server.requests();
+    assert_eq!(requests.len(), 2);
+    assert_eq!(header(&requests[1], "range"), Some("bytes=8192-"));
+}
@@ -0,0 +1,107 @@
+mod common;
+
+use botifactory_ota_nostd::{
+    save_new_fw_with_options, AppOTAState, SaveOptions, UpgradeError, UpgradeInfo,
+};
+use common::{flash_with_state, save_lock, test_image};
+use ed25519_dalek::{Signer, SigningKey};
+use embassy_futures::block_on;
+use sha2::{Digest, Sha256};
+
+fn signing_key(seed: u8) -> SigningKey {
+    SigningKey::from_bytes(&[seed; 32])
+}
+
+fn sign(key: &SigningKey, image: &[u8]) -> [u8; 64] {
+    let digest: [u8; 32] = Sha256::digest(image).into();
+    key.sign(&digest).to_bytes()
+}
+
+#[test]
+fn accepts_signature_from_any_trusted_key() {
+    let _lock = save_lock();
+    let mut flash = flash_with_state(1, AppOTAState::Valid);
+    let image = test_image(5000);
+    let trusted_keys = [
+        signing_key(1).verifying_key().to_bytes(),
+        signing_key(2).verifying_key().to_bytes(),
+    ];
+    let options = SaveOptions {
+        trusted_keys: &trusted_keys,
+        signature: Some(sign(&signing_key(2), &image)),
+        ..Default::default()
+    };
+
+    block_on(save_new_fw_with_options(
+        &mut flash,
+        image.as_slice(),
+        options,
+    ))
+    .unwrap();
+
+    assert_eq!(UpgradeInfo::from_flash(&mut flash).unwrap().seq, 2);
+}
+
+#[test]
+fn rejects_untrusted_signature() {
+    let _lock = save_lock();
+    let mut flash = flash_with_state(1, AppOTAState::Valid);
+    let image = test_image(5000);
+    let trusted_keys = [signing_key(1).verifying_key().to_bytes()];
+    let options = SaveOptions {
+        trusted_keys: &trusted_keys,
+        signature: Some(sign(&signing_key(3), &image)),
+        ..Default::default()
+    };
+
+    let result = block_on(save_new_fw_with_options(
+        &mut flash,
+        image.as_slice(),
+        options,
+    ));
+
+    assert!(matches!(result, Err(UpgradeError::InvalidSignature)));
+    assert_eq!(UpgradeInfo::from_flash(&mut flash).unwrap().seq, 1);
+}
+
+#[test]
+fn rejects_signature_for_another_image() {
+    let _lock = save_lock();
+    let mut flash = flash_with_state(1, AppOTAState::Valid);
+    let trusted_keys = [signing_key(1).verifying_key().to_bytes()];
+    let signature = sign(&signing_key(1), &test_image(4000));
+    let swapped = test_image(5000);
+    let options = SaveOptions {
+        trusted_keys: &trusted_keys,
+        signature: Some(signature),
+        ..Default::default()
+    };
+
+    let result = block_on(save_new_fw_with_options(
+        &mut flash,
+        swapped.as_slice(),
+        options,
+    ));
+
+    assert!(matches!(result, Err(UpgradeError::InvalidSignature)));
+    assert_eq!(UpgradeInfo::from_flash(&mut flash).unwrap().seq, 1);
+}
+
+#[test]
+fn requires_signature_when_keys_are_configured() {
+    let _lock = save_lock();
+    let mut flash = flash_with_state(1, AppOTAState::Valid);
+    let trusted_keys = [signing_key(1).verifying_key().to_bytes()];
+    let options = SaveOptions {
+        trusted_keys: &trusted_keys,
+        ..Default::default()
+    };
+
+    let result = block_on(save_new_fw_with_options(
+        &mut flash,
+        test_image(100).as_slice(),
+        options,
+    ));
+
+    assert!(matches!(result, Err(UpgradeError::MissingSignature)));
+}
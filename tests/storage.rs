@@ -0,0 +1,301 @@
+mod common;
+
+use botifactory_ota_nostd::{
+    accept_fw, find_partition_by_name, parse_sha256, reject_fw, save_new_fw,
+    save_new_fw_with_options, AppOTAState, EraseMode, Phase, Progress, RamFlash, SaveOptions,
+    UpgradeError, UpgradeInfo,
+};
+use common::{
+    flash_after_update, flash_with_state, otadata_entry, read_partition, save_lock, test_image,
+    APP_SIZE,
+};
+use embassy_futures::block_on;
+use embedded_storage::nor_flash::NorFlash;
+use sha2::{Digest, Sha256};
+
+#[test]
+fn save_new_fw_writes_inactive_partition() {
+    let _lock = save_lock();
+    let mut flash = flash_with_state(1, AppOTAState::Valid);
+    let image = test_image(10_000);
+
+    block_on(save_new_fw(&mut flash, image.as_slice())).unwrap();
+
+    assert_eq!(read_partition(&mut flash, "ota_1", image.len()), image);
+    let info = UpgradeInfo::from_flash(&mut flash).unwrap();
+    assert_eq!(info.seq, 2);
+    assert_eq!(info.state, AppOTAState::New);
+}
+
+#[test]
+fn save_new_fw_alternates_slots() {
+    let _lock = save_lock();
+    let mut flash = flash_with_state(2, AppOTAState::Valid);
+    let image = test_image(APP_SIZE - 64);
+
+    block_on(save_new_fw(&mut flash, image.as_slice())).unwrap();
+
+    assert_eq!(read_partition(&mut flash, "ota_0", image.len()), image);
+    assert_eq!(UpgradeInfo::from_flash(&mut flash).unwrap().seq, 3);
+}
+
+#[test]
+fn save_new_fw_refuses_while_new_fw_is_pending() {
+    let _lock = save_lock();
+    let mut flash = flash_with_state(2, AppOTAState::New);
+
+    let result = block_on(save_new_fw(&mut flash, test_image(16).as_slice()));
+
+    assert!(matches!(result, Err(UpgradeError::BootingIntoNewFW)));
+    assert_eq!(UpgradeInfo::from_flash(&mut flash).unwrap().seq, 2);
+}
+
+#[test]
+fn save_new_fw_rejects_oversized_image() {
+    let _lock = save_lock();
+    let mut flash = flash_with_state(1, AppOTAState::Valid);
+
+    let result = block_on(save_new_fw(&mut flash, test_image(APP_SIZE + 1).as_slice()));
+
+    assert!(matches!(result, Err(UpgradeError::OutOfSpace)));
+    let info = UpgradeInfo::from_flash(&mut flash).unwrap();
+    assert_eq!(info.seq, 1);
+    assert_eq!(info.state, AppOTAState::Valid);
+}
+
+#[test]
+fn accept_marks_pending_fw_valid() {
+    let mut flash = flash_with_state(4, AppOTAState::PendingVerify);
+
+    accept_fw(&mut flash).unwrap();
+
+    let info = UpgradeInfo::from_flash(&mut flash).unwrap();
+    assert_eq!(info.seq, 4);
+    assert_eq!(info.state, AppOTAState::Valid);
+}
+
+#[test]
+fn accept_after_rollback_keeps_previous_fw() {
+    // The bootloader skips aborted entries and boots seq 3 again
+    let mut flash = flash_after_update(4, AppOTAState::Aborted);
+
+    accept_fw(&mut flash).unwrap();
+
+    let info = UpgradeInfo::from_flash(&mut flash).unwrap();
+    assert_eq!(info.seq, 3);
+    assert_eq!(info.state, AppOTAState::Valid);
+}
+
+#[test]
+fn reject_marks_new_fw_invalid() {
+    let mut flash = flash_after_update(4, AppOTAState::New);
+
+    reject_fw(&mut flash).unwrap();
+
+    let rejected = UpgradeInfo::try_from(otadata_entry(&mut flash, 1)).unwrap();
+    assert_eq!(rejected.seq, 4);
+    assert_eq!(rejected.state, AppOTAState::Invalid);
+    // Which rolls back to the previous firmware
+    assert_eq!(UpgradeInfo::from_flash(&mut flash).unwrap().seq, 3);
+}
+
+#[test]
+fn reject_ignores_accepted_fw() {
+    let mut flash = flash_with_state(4, AppOTAState::Valid);
+
+    reject_fw(&mut flash).unwrap();
+
+    assert_eq!(
+        UpgradeInfo::from_flash(&mut flash).unwrap().state,
+        AppOTAState::Valid
+    );
+}
+
+#[test]
+fn save_new_fw_accepts_matching_sha256() {
+    let _lock = save_lock();
+    let mut flash = flash_with_state(1, AppOTAState::Valid);
+    let image = test_image(5000);
+    let options = SaveOptions {
+        expected_sha256: Some(Sha256::digest(&image).into()),
+        ..Default::default()
+    };
+
+    block_on(save_new_fw_with_options(
+        &mut flash,
+        image.as_slice(),
+        options,
+    ))
+    .unwrap();
+
+    assert_eq!(UpgradeInfo::from_flash(&mut flash).unwrap().seq, 2);
+}
+
+#[test]
+fn save_new_fw_rejects_sha256_mismatch() {
+    let _lock = save_lock();
+    let mut flash = flash_with_state(1, AppOTAState::Valid);
+    let options = SaveOptions {
+        expected_sha256: Some(Sha256::digest(test_image(5000)).into()),
+        ..Default::default()
+    };
+
+    let result = block_on(save_new_fw_with_options(
+        &mut flash,
+        test_image(4000).as_slice(),
+        options,
+    ));
+
+    assert!(matches!(result, Err(UpgradeError::ChecksumMismatch)));
+    let info = UpgradeInfo::from_flash(&mut flash).unwrap();
+    assert_eq!(info.seq, 1);
+    assert_eq!(info.state, AppOTAState::Valid);
+}
+
+#[test]
+fn parses_published_sha256() {
+    let hex = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+    let digest = parse_sha256(hex).unwrap();
+    assert_eq!(digest[0], 0x9f);
+    assert_eq!(digest[31], 0x08);
+    assert_eq!(parse_sha256(&format!("sha256:{}", hex)), Some(digest));
+    assert_eq!(parse_sha256(&hex[1..]), None);
+    assert_eq!(parse_sha256(&hex.replace('9', "g")), None);
+    assert_eq!(parse_sha256(&format!("+{}", &hex[1..])), None);
+}
+
+#[test]
+fn save_new_fw_reports_progress() {
+    let _lock = save_lock();
+    let mut flash = flash_with_state(1, AppOTAState::Valid);
+    let image = test_image(40_000);
+    let mut reports = Vec::new();
+    let mut observer = |progress: Progress| reports.push(progress);
+    let options = SaveOptions {
+        image_len: Some(image.len()),
+        erase_mode: EraseMode::Full,
+        progress: Some(&mut observer),
+        ..Default::default()
+    };
+
+    block_on(save_new_fw_with_options(
+        &mut flash,
+        image.as_slice(),
+        options,
+    ))
+    .unwrap();
+
+    let phases: Vec<Phase> = reports.iter().map(|progress| progress.phase).collect();
+    assert_eq!(phases.first(), Some(&Phase::Erasing));
+    assert_eq!(phases.last(), Some(&Phase::Committing));
+    assert!(phases.contains(&Phase::Verifying));
+    // One report per written sector at most, not one per read
+    let downloading: Vec<&Progress> = reports
+        .iter()
+        .filter(|progress| progress.phase == Phase::Downloading)
+        .collect();
+    assert!(downloading.len() <= image.len() / 4096 + 2);
+    assert!(downloading
+        .windows(2)
+        .all(|pair| pair[0].written < pair[1].written));
+    let last = downloading.last().unwrap();
+    assert_eq!(last.written, image.len());
+    assert_eq!(last.percent(), Some(100));
+}
+
+/// ota_1 filled with leftovers of an older image.
+fn flash_with_stale_slot() -> RamFlash {
+    let mut flash = flash_with_state(1, AppOTAState::Valid);
+    let ota_1 = find_partition_by_name(&mut flash, "ota_1").unwrap();
+    flash.write(ota_1.offset, &vec![0x5A; APP_SIZE]).unwrap();
+    flash
+}
+
+#[test]
+fn lazy_erase_stops_at_end_of_image() {
+    let _lock = save_lock();
+    let mut flash = flash_with_stale_slot();
+    let image = test_image(10_000);
+
+    block_on(save_new_fw(&mut flash, image.as_slice())).unwrap();
+
+    let slot = read_partition(&mut flash, "ota_1", APP_SIZE);
+    assert_eq!(slot[..image.len()], image);
+    // Erased up to the end of the sector the image ends in, untouched after that
+    assert!(slot[image.len()..0x3000].iter().all(|b| *b == 0xFF));
+    assert!(slot[0x3000..].iter().all(|b| *b == 0x5A));
+}
+
+#[test]
+fn full_erase_clears_whole_partition() {
+    let _lock = save_lock();
+    let mut flash = flash_with_stale_slot();
+    let image = test_image(10_000);
+    let options = SaveOptions {
+        erase_mode: EraseMode::Full,
+        ..Default::default()
+    };
+
+    block_on(save_new_fw_with_options(
+        &mut flash,
+        image.as_slice(),
+        options,
+    ))
+    .unwrap();
+
+    let slot = read_partition(&mut flash, "ota_1", APP_SIZE);
+    assert_eq!(slot[..image.len()], image);
+    assert!(slot[image.len()..].iter().all(|b| *b == 0xFF));
+}
+
+#[test]
+fn oversized_image_is_refused_before_erasing() {
+    let _lock = save_lock();
+    let mut flash = flash_with_stale_slot();
+    let image = test_image(APP_SIZE + 4096);
+    let options = SaveOptions {
+        image_len: Some(image.len()),
+        erase_mode: EraseMode::Full,
+        ..Default::default()
+    };
+
+    let result = block_on(save_new_fw_with_options(
+        &mut flash,
+        image.as_slice(),
+        options,
+    ));
+
+    assert!(matches!(result, Err(UpgradeError::OutOfSpace)));
+    let slot = read_partition(&mut flash, "ota_1", APP_SIZE);
+    assert!(slot.iter().all(|b| *b == 0x5A));
+}
+
+#[test]
+fn transfer_has_to_match_declared_length() {
+    let _lock = save_lock();
+    let image = test_image(10_000);
+    for declared in [image.len() + 100, image.len() - 100] {
+        let mut flash = flash_with_state(1, AppOTAState::Valid);
+        let options = SaveOptions {
+            image_len: Some(declared),
+            ..Default::default()
+        };
+
+        let result = block_on(save_new_fw_with_options(
+            &mut flash,
+            image.as_slice(),
+            options,
+        ));
+
+        match result {
+            Err(UpgradeError::LengthMismatch { expected, received }) => {
+                assert_eq!(expected, declared);
+                // Too much data is caught before it's written
+                assert!(received <= image.len());
+                assert_eq!(received < expected, image.len() < declared);
+            }
+            other => panic!("expected a length mismatch, got {:?}", other),
+        }
+        assert_eq!(UpgradeInfo::from_flash(&mut flash).unwrap().seq, 1);
+    }
+}
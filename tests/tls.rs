@@ -0,0 +1,53 @@
+use botifactory_ota_nostd::{require_https, UpgradeError};
+
+#[test]
+fn only_https_by_default() {
+    assert!(require_https("https://ota.example.com/bot/stable/latest", false).is_ok());
+    assert!(require_https("HTTPS://ota.example.com", false).is_ok());
+    assert!(matches!(
+        require_https("http://ota.example.com/bot/stable/latest", false),
+        Err(UpgradeError::InsecureUrl)
+    ));
+    assert!(matches!(
+        require_https("ota.example.com/bot/stable/latest", false),
+        Err(UpgradeError::InsecureUrl)
+    ));
+}
+
+#[test]
+fn http_when_allowed() {
+    assert!(require_https("http://192.168.1.10:8000/bot/stable/latest", true).is_ok());
+    assert!(require_https("https://ota.example.com", true).is_ok());
+    assert!(matches!(
+        require_https("ftp://ota.example.com", true),
+        Err(UpgradeError::InsecureUrl)
+    ));
+}
+
+#[test]
+fn only_handshake_rejections_are_untrusted() {
+    use embedded_tls::alert::{AlertDescription, AlertLevel};
+    use embedded_tls::TlsError;
+
+    let rejected = [
+        TlsError::InvalidCertificate,
+        TlsError::HandshakeAborted(AlertLevel::Fatal, AlertDescription::UnknownPskIdentity),
+    ];
+    for error in rejected {
+        assert!(matches!(
+            UpgradeError::from(reqwless::Error::Tls(error)),
+            UpgradeError::UntrustedServer
+        ));
+    }
+
+    let network = [
+        TlsError::ConnectionClosed,
+        TlsError::IoError,
+        TlsError::Io(embedded_io::ErrorKind::TimedOut),
+    ];
+    for error in network {
+        let error = UpgradeError::from(reqwless::Error::Tls(error));
+        assert!(matches!(error, UpgradeError::RequestError));
+        assert!(error.is_transient());
+    }
+}
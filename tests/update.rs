@@ -0,0 +1,84 @@
+use botifactory_ota_nostd::{IgnoreReason, UpdateDecision, UpdatePolicy};
+use semver::Version;
+
+fn decide(policy: UpdatePolicy, current: &str, offered: &str) -> UpdateDecision {
+    policy.decide(
+        &Version::parse(current).unwrap(),
+        &Version::parse(offered).unwrap(),
+    )
+}
+
+fn ignored(version: &str, reason: IgnoreReason) -> UpdateDecision {
+    UpdateDecision::Ignored {
+        version: Version::parse(version).unwrap(),
+        reason,
+    }
+}
+
+#[test]
+fn same_version_is_up_to_date() {
+    let policy = UpdatePolicy::default();
+    assert_eq!(decide(policy, "1.2.3", "1.2.3"), UpdateDecision::UpToDate);
+    assert_eq!(
+        decide(policy, "1.2.3+build.1", "1.2.3+build.2"),
+        UpdateDecision::UpToDate
+    );
+}
+
+#[test]
+fn newer_version_is_available() {
+    let decision = decide(UpdatePolicy::default(), "1.2.3", "1.3.0");
+    assert_eq!(
+        decision,
+        UpdateDecision::UpdateAvailable(Version::new(1, 3, 0))
+    );
+    assert!(decision.should_update());
+}
+
+#[test]
+fn prereleases_need_opt_in() {
+    assert_eq!(
+        decide(UpdatePolicy::default(), "1.2.3", "1.3.0-rc.1"),
+        ignored("1.3.0-rc.1", IgnoreReason::Prerelease)
+    );
+
+    let policy = UpdatePolicy {
+        allow_prerelease: true,
+        ..Default::default()
+    };
+    assert!(decide(policy, "1.2.3", "1.3.0-rc.1").should_update());
+    assert!(decide(policy, "1.3.0-rc.1", "1.3.0").should_update());
+}
+
+#[test]
+fn downgrades_are_blocked_by_default() {
+    let decision = decide(UpdatePolicy::default(), "1.2.3", "1.2.2");
+    assert_eq!(decision, ignored("1.2.2", IgnoreReason::Downgrade));
+    assert!(!decision.should_update());
+
+    let policy = UpdatePolicy {
+        allow_downgrade: true,
+        ..Default::default()
+    };
+    assert_eq!(
+        decide(policy, "1.2.3", "1.2.2"),
+        UpdateDecision::DowngradeOffered(Version::new(1, 2, 2))
+    );
+}
+
+#[test]
+fn major_versions_are_gated() {
+    assert_eq!(
+        decide(UpdatePolicy::default(), "1.9.0", "2.0.0"),
+        ignored("2.0.0", IgnoreReason::MajorVersion)
+    );
+
+    let policy = UpdatePolicy {
+        allow_major: true,
+        ..Default::default()
+    };
+    assert_eq!(
+        decide(policy, "1.9.0", "2.0.0").version(),
+        Some(&Version::new(2, 0, 0))
+    );
+}
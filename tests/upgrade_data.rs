@@ -0,0 +1,97 @@
+mod common;
+
+use botifactory_ota_nostd::{AppOTAState, UpgradeError, UpgradeInfo};
+use common::{flash_after_update, flash_with_state, otadata_entry, write_upgrade_info};
+use embedded_storage::nor_flash::NorFlash;
+
+#[test]
+fn round_trips_through_flash() {
+    let mut flash = flash_with_state(3, AppOTAState::PendingVerify);
+
+    let info = UpgradeInfo::from_flash(&mut flash).unwrap();
+    assert_eq!(info.seq, 3);
+    assert_eq!(info.state, AppOTAState::PendingVerify);
+    assert_eq!(otadata_entry(&mut flash, 1), [0xFF; 32]);
+}
+
+#[test]
+fn round_trips_through_bytes() {
+    let mut info = UpgradeInfo::new(42, *b"botifactory-ota-test");
+    info.state = AppOTAState::Valid;
+
+    let bytes: [u8; 32] = info.into();
+    let parsed = UpgradeInfo::try_from(bytes).unwrap();
+    assert_eq!(parsed.seq, 42);
+    assert_eq!(parsed.label, *b"botifactory-ota-test");
+    assert_eq!(parsed.state, AppOTAState::Valid);
+    assert_eq!(parsed.seq_crc, info.seq_crc);
+}
+
+#[test]
+fn rejects_bad_crc() {
+    let mut bytes: [u8; 32] = UpgradeInfo::new(1, [0xFF; 20]).into();
+    bytes[28] ^= 0x01;
+    assert!(matches!(
+        UpgradeInfo::try_from(bytes),
+        Err(UpgradeError::InvalidCrc)
+    ));
+}
+
+#[test]
+fn falls_back_to_other_sector() {
+    let mut flash = flash_after_update(6, AppOTAState::New);
+    let otadata = botifactory_ota_nostd::find_ota_partition(&mut flash).unwrap();
+    flash
+        .erase(otadata.offset + 0x1000, otadata.offset + 0x2000)
+        .unwrap();
+
+    let info = UpgradeInfo::from_flash(&mut flash).unwrap();
+    assert_eq!(info.seq, 5);
+    assert_eq!(info.state, AppOTAState::Valid);
+}
+
+#[test]
+fn picks_highest_seq() {
+    let mut flash = flash_with_state(1, AppOTAState::Valid);
+    for seq in 2..6 {
+        write_upgrade_info(&mut flash, seq, AppOTAState::Valid);
+        assert_eq!(UpgradeInfo::from_flash(&mut flash).unwrap().seq, seq);
+    }
+}
+
+#[test]
+fn new_seq_goes_to_other_sector() {
+    let mut flash = flash_with_state(1, AppOTAState::Valid);
+    write_upgrade_info(&mut flash, 2, AppOTAState::New);
+    write_upgrade_info(&mut flash, 3, AppOTAState::New);
+
+    let entry = |flash: &mut _, sector| UpgradeInfo::try_from(otadata_entry(flash, sector));
+    assert_eq!(entry(&mut flash, 0).unwrap().seq, 3);
+    assert_eq!(entry(&mut flash, 1).unwrap().seq, 2);
+}
+
+#[test]
+fn state_change_rewrites_active_sector() {
+    let mut flash = flash_after_update(2, AppOTAState::New);
+    write_upgrade_info(&mut flash, 2, AppOTAState::PendingVerify);
+
+    let first = UpgradeInfo::try_from(otadata_entry(&mut flash, 0)).unwrap();
+    let second = UpgradeInfo::try_from(otadata_entry(&mut flash, 1)).unwrap();
+    assert_eq!((first.seq, first.state), (1, AppOTAState::Valid));
+    assert_eq!((second.seq, second.state), (2, AppOTAState::PendingVerify));
+}
+
+#[test]
+fn skips_rejected_entries() {
+    for state in [AppOTAState::Invalid, AppOTAState::Aborted] {
+        let mut flash = flash_after_update(8, state);
+        assert_eq!(UpgradeInfo::from_flash(&mut flash).unwrap().seq, 7);
+    }
+}
+
+#[test]
+fn blank_otadata_is_an_error() {
+    let mut flash: botifactory_ota_nostd::RamFlash =
+        botifactory_ota_nostd::RamFlash::with_ota_layout(common::APP_SIZE);
+    assert!(UpgradeInfo::from_flash(&mut flash).is_err());
+}
@@ -0,0 +1,50 @@
+use botifactory_ota_nostd::{BotifactoryUrlBuilder, ReleaseSelector};
+use semver::Version;
+
+fn builder() -> BotifactoryUrlBuilder {
+    BotifactoryUrlBuilder::new("https://ota.example.com/", "bot", "stable")
+}
+
+#[test]
+fn builds_release_urls() {
+    let urls = builder();
+
+    assert_eq!(urls.latest(), "https://ota.example.com/bot/stable/latest");
+    assert_eq!(
+        urls.previous(),
+        "https://ota.example.com/bot/stable/previous"
+    );
+    assert_eq!(urls.id(42), "https://ota.example.com/bot/stable/42");
+    assert_eq!(
+        urls.version(&Version::parse("1.2.3-rc.1").unwrap()),
+        "https://ota.example.com/bot/stable/version/1.2.3-rc.1"
+    );
+}
+
+#[test]
+fn escapes_build_metadata() {
+    assert_eq!(
+        builder().version(&Version::parse("1.2.3+build.7").unwrap()),
+        "https://ota.example.com/bot/stable/version/1.2.3%2Bbuild.7"
+    );
+}
+
+#[test]
+fn builds_listing_and_binary_urls() {
+    let urls = builder();
+    let version = Version::new(2, 0, 0);
+
+    assert_eq!(
+        urls.releases(),
+        "https://ota.example.com/bot/stable/releases"
+    );
+    assert_eq!(urls.channels(), "https://ota.example.com/bot/channels");
+    assert_eq!(
+        urls.binary(ReleaseSelector::Latest),
+        "https://ota.example.com/bot/stable/latest/binary"
+    );
+    assert_eq!(
+        urls.binary(ReleaseSelector::Version(&version)),
+        "https://ota.example.com/bot/stable/version/2.0.0/binary"
+    );
+}